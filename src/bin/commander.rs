@@ -10,10 +10,35 @@
 
 use esp_alloc as _;
 use esp_backtrace as _;
-use esp_hal::{aes::Aes, prelude::*, rng::Rng, sha::Sha, timer::timg::TimerGroup};
+use esp_hal::{
+    aes::Aes,
+    gpio::{Input, Io, Pull},
+    prelude::*,
+    rng::Rng,
+    sha::Sha,
+    time::Duration,
+    timer::timg::TimerGroup,
+    uart::Uart,
+};
 use esp_println::println;
+use esp_storage::FlashStorage;
 use esp_wifi::{init, EspWifiInitFor};
-use tactile_tesla::packet_manager::{PacketManager, Role};
+use tactile_tesla::{
+    counter_store::FlashCounterStore,
+    key_store::{self, FlashKeyStore, KeyStore},
+    packet_manager::{PacketManager, Role},
+    provisioning::{ProvisioningConsole, ProvisioningEvent},
+};
+
+/// Start of the `keys` partition in `partitions.csv`
+const KEY_STORE_OFFSET: u32 = 0x3f0000;
+/// Start and size of the `counters` partition in `partitions.csv`
+const COUNTER_STORE_OFFSET: u32 = 0x3f4000;
+const COUNTER_STORE_SIZE: u32 = 0x8000;
+/// How long new nodes can ask to join after the pairing button is pressed
+const PAIRING_WINDOW: Duration = Duration::secs(60);
+/// How long the old key stays valid after `key rotate`
+const ROTATION_GRACE: Duration = Duration::secs(300);
 
 #[entry]
 fn main() -> ! {
@@ -44,8 +69,72 @@ fn main() -> ! {
 
     println!("esp-now version {}", esp_now.get_version().unwrap());
 
-    let mut manager = PacketManager::new(esp_now);
+    // Provisioning console shares UART0 with the log output
+    let io = Io::new(peripherals.GPIO, peripherals.IO_MUX);
+    let mut serial = Uart::new(peripherals.UART0, io.pins.gpio3, io.pins.gpio1).unwrap();
+    let button = Input::new(io.pins.gpio0, Pull::Up);
+    let mut console = ProvisioningConsole::new();
+    let mut key_store = FlashKeyStore::new(FlashStorage::new(), KEY_STORE_OFFSET);
+
+    // Create the signing identity up front so `identity show` works right away
+    key_store::load_or_create_identity(&mut key_store, &mut rng).unwrap();
+
+    // Wait for a key to be provisioned before joining the cluster
+    if !matches!(key_store.load_cluster_key(), Ok(Some(_))) {
+        println!("No cluster key installed. Use `key set <hex>` to provision.");
+        while !matches!(
+            console.poll(&mut serial, &mut key_store),
+            Some(ProvisioningEvent::KeyInstalled(_))
+        ) {}
+    }
+
+    let counter_store =
+        FlashCounterStore::new(FlashStorage::new(), COUNTER_STORE_OFFSET, COUNTER_STORE_SIZE);
+    let mut manager =
+        PacketManager::new(esp_now, key_store, counter_store, &mut sha, &mut rng).unwrap();
+    let mut button_state = false;
+    let mut shown_code = None;
     loop {
+        // The button opens a pairing window, then confirms the code shown by the new node
+        let new_button_state = button.is_low();
+        if button_state != new_button_state && new_button_state {
+            match manager.pending_pairing() {
+                Some(_) => {
+                    if manager.confirm_pairing(&mut aes) {
+                        println!("Pairing confirmed. Sent cluster key.");
+                    }
+                }
+                None => {
+                    println!("Pairing window open.");
+                    manager.start_pairing(PAIRING_WINDOW);
+                }
+            }
+        }
+        button_state = new_button_state;
+        if let Some((peer, code)) = manager.pending_pairing() {
+            if shown_code != Some(code) {
+                println!("Node {peer:02x?} wants to pair. Code: {code:06}");
+                shown_code = Some(code);
+            }
+        }
+
+        match console.poll(&mut serial, manager.key_store_mut()) {
+            Some(ProvisioningEvent::KeyInstalled(key)) => {
+                manager.set_cluster_key(&mut sha, &key).unwrap();
+            }
+            Some(ProvisioningEvent::RotateRequested) => {
+                match manager.rotate_key(&mut sha, &mut rng, ROTATION_GRACE) {
+                    Ok(()) => println!("Rotated to key {}.", manager.current_key_id()),
+                    Err(err) => println!("Key rotation failed: {err}"),
+                }
+            }
+            Some(ProvisioningEvent::CommanderPinned(key)) => manager.set_commander_key(&key),
+            Some(ProvisioningEvent::KeyErased) => {
+                println!("Cluster key erased. Restarting.");
+                esp_hal::reset::software_reset();
+            }
+            None => {}
+        }
         manager.tick(&mut aes, &mut sha, &mut rng, Role::Commander);
     }
 }
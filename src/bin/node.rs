@@ -0,0 +1,84 @@
+//! Generic cluster node.
+//!
+//! Joins the cluster using the provisioned key, or pairs with a Commander
+//! over the air if no key has been installed yet.
+
+#![no_std]
+#![no_main]
+
+use esp_alloc as _;
+use esp_backtrace as _;
+use esp_hal::{aes::Aes, prelude::*, rng::Rng, sha::Sha, timer::timg::TimerGroup};
+use esp_println::println;
+use esp_storage::FlashStorage;
+use esp_wifi::{init, EspWifiInitFor};
+use tactile_tesla::{
+    counter_store::FlashCounterStore,
+    key_store::{FlashKeyStore, KeyStore},
+    packet_manager::{PacketManager, PairingClient, PairingEvent, Role},
+};
+
+/// Start of the `keys` partition in `partitions.csv`
+const KEY_STORE_OFFSET: u32 = 0x3f0000;
+/// Start and size of the `counters` partition in `partitions.csv`
+const COUNTER_STORE_OFFSET: u32 = 0x3f4000;
+const COUNTER_STORE_SIZE: u32 = 0x8000;
+
+#[entry]
+fn main() -> ! {
+    esp_println::logger::init_logger_from_env();
+    let peripherals = esp_hal::init({
+        let mut config = esp_hal::Config::default();
+        config.cpu_clock = CpuClock::max();
+        config
+    });
+
+    esp_alloc::heap_allocator!(72 * 1024);
+
+    let timg0 = TimerGroup::new(peripherals.TIMG0);
+
+    let mut rng = Rng::new(peripherals.RNG);
+    let init = init(
+        EspWifiInitFor::Wifi,
+        timg0.timer0,
+        rng.clone(),
+        peripherals.RADIO_CLK,
+    )
+    .unwrap();
+
+    let wifi = peripherals.WIFI;
+    let mut esp_now = esp_wifi::esp_now::EspNow::new(&init, wifi).unwrap();
+    let mut aes = Aes::new(peripherals.AES);
+    let mut sha = Sha::new(peripherals.SHA);
+    let mut key_store = FlashKeyStore::new(FlashStorage::new(), KEY_STORE_OFFSET);
+
+    // Pair with a Commander if we haven't been provisioned yet
+    if !matches!(key_store.load_cluster_key(), Ok(Some(_))) {
+        println!("No cluster key installed. Waiting for a Commander to pair with.");
+        let mut client = PairingClient::new(esp_now, &mut rng);
+        loop {
+            match client.tick(&mut aes, &mut sha, &mut rng, &mut key_store) {
+                Some(PairingEvent::ConfirmationCode(code)) => {
+                    println!("Pairing code: {code:06}");
+                }
+                Some(PairingEvent::Restarted) => {
+                    println!("Pairing timed out. Waiting for a Commander again.");
+                }
+                Some(PairingEvent::Paired(_)) => {
+                    println!("Paired with cluster.");
+                    break;
+                }
+                None => {}
+            }
+        }
+        esp_now = client.into_transport();
+    }
+
+    let counter_store =
+        FlashCounterStore::new(FlashStorage::new(), COUNTER_STORE_OFFSET, COUNTER_STORE_SIZE);
+    let mut manager =
+        PacketManager::new(esp_now, key_store, counter_store, &mut sha, &mut rng).unwrap();
+    loop {
+        manager.tick(&mut aes, &mut sha, &mut rng, Role::Node);
+    }
+}
@@ -235,6 +235,11 @@ impl<'a> PacketReader<'a> {
         return Some(str::from_utf8(self.read_bytes()?));
     }
 
+    /// Number of bytes that have not been read yet
+    pub fn remaining(&self) -> usize {
+        return self.packet.len() - self.cursor;
+    }
+
     pub fn get_remainder(self) -> &'a [u8] {
         return &self.packet[self.cursor..];
     }
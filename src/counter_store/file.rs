@@ -0,0 +1,75 @@
+use super::{decode_record, encode_record, CounterStore, MemoryCounterStore, RECORD_LEN};
+use crate::transport::MacAddress;
+use std::{ffi::OsString, fs, io::ErrorKind, path::PathBuf};
+
+/// Stores replay counters in a file, for host builds.
+///
+/// Updates are written to `<path>.tmp` and then renamed over the file, so a
+/// crash partway through leaves the previous counters in place.
+pub struct FileCounterStore {
+    path: PathBuf,
+    temp_path: PathBuf,
+    counters: MemoryCounterStore,
+}
+impl FileCounterStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut temp_path = OsString::from(path.as_os_str());
+        temp_path.push(".tmp");
+
+        let counters = match fs::read(&path) {
+            Ok(record) => match <[u8; RECORD_LEN]>::try_from(record.as_slice()) {
+                Ok(record) => decode_record(&record).map(|(_, counters)| counters),
+                Err(_) => None,
+            },
+            Err(err) if err.kind() == ErrorKind::NotFound => Some(MemoryCounterStore::new()),
+            Err(err) => {
+                log::error!("Failed to read replay counters: {err}");
+                None
+            }
+        };
+        let counters = match counters {
+            Some(counters) => counters,
+            None => {
+                log::error!("Replay counters in {} are corrupted", path.display());
+                MemoryCounterStore::new()
+            }
+        };
+        return Self {
+            path,
+            temp_path: temp_path.into(),
+            counters,
+        };
+    }
+
+    fn persist(&mut self) {
+        let result = fs::write(&self.temp_path, encode_record(&self.counters, 0))
+            .and_then(|()| fs::rename(&self.temp_path, &self.path));
+        if let Err(err) = result {
+            log::error!("Failed to persist replay counters: {err}");
+        }
+    }
+}
+impl CounterStore for FileCounterStore {
+    fn load_tx_counter(&mut self) -> Option<u64> {
+        return self.counters.load_tx_counter();
+    }
+    fn store_tx_counter(&mut self, counter: u64) {
+        self.counters.store_tx_counter(counter);
+        self.persist();
+    }
+    fn load_peer_counter(&mut self, peer: &MacAddress) -> Option<u64> {
+        return self.counters.load_peer_counter(peer);
+    }
+    fn store_peer_counter(&mut self, peer: &MacAddress, counter: u64) {
+        self.counters.store_peer_counter(peer, counter);
+        self.persist();
+    }
+    fn load_command_sequence(&mut self) -> Option<u64> {
+        return self.counters.load_command_sequence();
+    }
+    fn store_command_sequence(&mut self, sequence: u64) {
+        self.counters.store_command_sequence(sequence);
+        self.persist();
+    }
+}
@@ -0,0 +1,146 @@
+use super::{decode_record, encode_record, CounterStore, MemoryCounterStore, RECORD_LEN};
+use crate::transport::MacAddress;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Room taken by each record, padded so every slot starts on a write boundary
+const SLOT_LEN: usize = RECORD_LEN.div_ceil(32) * 32;
+
+/// Stores replay counters in a region of flash.
+///
+/// Every update appends the whole record to the next free slot, so a sector
+/// is only erased once its slots are used up and the next one is needed.
+/// Successive sectors are used in turn to spread the wear. On boot the record
+/// with the highest generation wins, so a write torn by a power loss only
+/// loses that one update.
+///
+/// On the ESP32 this is used with `esp_storage::FlashStorage` and the
+/// `counters` partition in `partitions.csv`.
+pub struct FlashCounterStore<S: NorFlash> {
+    storage: S,
+    offset: u32,
+    sectors: u32,
+    generation: u32,
+    counters: MemoryCounterStore,
+}
+impl<S: NorFlash> FlashCounterStore<S> {
+    const SLOTS_PER_SECTOR: u32 = (S::ERASE_SIZE / SLOT_LEN) as u32;
+
+    /// Loads the latest record from the `size` bytes of flash at `offset`.
+    ///
+    /// The region must span at least two sectors.
+    pub fn new(mut storage: S, offset: u32, size: u32) -> Self {
+        let sectors = size / S::ERASE_SIZE as u32;
+        assert!(sectors >= 2, "Counter storage needs at least two flash sectors");
+        assert!(
+            Self::SLOTS_PER_SECTOR > 0
+                && SLOT_LEN % S::WRITE_SIZE == 0
+                && SLOT_LEN % S::READ_SIZE == 0,
+            "Counter records don't fit the flash geometry"
+        );
+
+        let mut latest: Option<(u32, MemoryCounterStore)> = None;
+        let mut record = [0u8; SLOT_LEN];
+        for slot in 0..sectors * Self::SLOTS_PER_SECTOR {
+            if storage
+                .read(Self::slot_offset(offset, slot), &mut record)
+                .is_err()
+            {
+                log::error!("Failed to read replay counters from slot {slot}");
+                continue;
+            }
+            let (generation, counters) =
+                match decode_record(record[0..RECORD_LEN].try_into().unwrap()) {
+                    Some(decoded) => decoded,
+                    None => continue,
+                };
+            let newer = match latest {
+                Some((latest_generation, _)) => generation > latest_generation,
+                None => true,
+            };
+            if newer {
+                latest = Some((generation, counters));
+            }
+        }
+
+        let (generation, counters) = latest.unwrap_or_default();
+        let mut store = Self {
+            storage,
+            offset,
+            sectors,
+            generation,
+            counters,
+        };
+
+        // A torn write leaves the next slot dirty without a valid record, and
+        // flash can't be written twice without an erase. Move on to a fresh
+        // sector instead.
+        let next = store.slot(generation.wrapping_add(1));
+        let blank = match store
+            .storage
+            .read(Self::slot_offset(offset, next), &mut record)
+        {
+            Ok(()) => record.iter().all(|byte| *byte == 0xFF),
+            Err(_) => false,
+        };
+        if !blank && next % Self::SLOTS_PER_SECTOR != 0 {
+            store.generation += Self::SLOTS_PER_SECTOR - next % Self::SLOTS_PER_SECTOR;
+        }
+        return store;
+    }
+
+    fn slot(&self, generation: u32) -> u32 {
+        return generation % (self.sectors * Self::SLOTS_PER_SECTOR);
+    }
+
+    fn slot_offset(offset: u32, slot: u32) -> u32 {
+        let sector = slot / Self::SLOTS_PER_SECTOR;
+        return offset
+            + sector * S::ERASE_SIZE as u32
+            + (slot % Self::SLOTS_PER_SECTOR) * SLOT_LEN as u32;
+    }
+
+    /// Appends the counters to the slot after the one written last, erasing
+    /// its sector first if it's the sector's first slot
+    fn persist(&mut self) {
+        self.generation += 1;
+        let slot = self.slot(self.generation);
+        let slot_offset = Self::slot_offset(self.offset, slot);
+        if slot % Self::SLOTS_PER_SECTOR == 0
+            && self
+                .storage
+                .erase(slot_offset, slot_offset + S::ERASE_SIZE as u32)
+                .is_err()
+        {
+            log::error!("Failed to erase replay counter sector");
+            return;
+        }
+        let mut record = [0xFF; SLOT_LEN];
+        record[0..RECORD_LEN].copy_from_slice(&encode_record(&self.counters, self.generation));
+        if self.storage.write(slot_offset, &record).is_err() {
+            log::error!("Failed to persist replay counters");
+        }
+    }
+}
+impl<S: NorFlash> CounterStore for FlashCounterStore<S> {
+    fn load_tx_counter(&mut self) -> Option<u64> {
+        return self.counters.load_tx_counter();
+    }
+    fn store_tx_counter(&mut self, counter: u64) {
+        self.counters.store_tx_counter(counter);
+        self.persist();
+    }
+    fn load_peer_counter(&mut self, peer: &MacAddress) -> Option<u64> {
+        return self.counters.load_peer_counter(peer);
+    }
+    fn store_peer_counter(&mut self, peer: &MacAddress, counter: u64) {
+        self.counters.store_peer_counter(peer, counter);
+        self.persist();
+    }
+    fn load_command_sequence(&mut self) -> Option<u64> {
+        return self.counters.load_command_sequence();
+    }
+    fn store_command_sequence(&mut self, sequence: u64) {
+        self.counters.store_command_sequence(sequence);
+        self.persist();
+    }
+}
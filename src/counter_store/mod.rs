@@ -0,0 +1,148 @@
+//! Persistent storage for replay-protection counters.
+//!
+//! Every backend stores the same record: a magic number, a format version, a
+//! generation number, the counters, and a CRC-32 to detect torn or corrupted
+//! writes. See `packet_manager` for how often each counter is written.
+
+mod flash;
+#[cfg(feature = "std")]
+mod file;
+
+pub use flash::FlashCounterStore;
+#[cfg(feature = "std")]
+pub use file::FileCounterStore;
+
+use crate::{crc::crc32, transport::MacAddress};
+
+/// Peers whose high-water marks are kept. The least recently updated one is
+/// forgotten first, so this should be at least the number of peers tracked.
+pub const MAX_PERSISTED_PEERS: usize = 64;
+
+const RECORD_MAGIC: [u8; 4] = *b"TTRC";
+const RECORD_VERSION: u8 = 1;
+const PEER_ENTRY_LEN: usize = 6 + 8;
+/// Magic, version, generation, transmit counter, command sequence, peer
+/// count, peers, CRC
+pub const RECORD_LEN: usize = 4 + 1 + 4 + 8 + 8 + 1 + MAX_PERSISTED_PEERS * PEER_ENTRY_LEN + 4;
+
+/// Persistent storage for replay counters
+pub trait CounterStore {
+    /// Loads the persisted transmit counter reservation
+    fn load_tx_counter(&mut self) -> Option<u64>;
+    fn store_tx_counter(&mut self, counter: u64);
+    /// Loads the persisted high-water mark for a peer
+    fn load_peer_counter(&mut self, peer: &MacAddress) -> Option<u64>;
+    fn store_peer_counter(&mut self, peer: &MacAddress, counter: u64);
+    /// Loads the sequence number of the last signed command that was acted on
+    fn load_command_sequence(&mut self) -> Option<u64>;
+    fn store_command_sequence(&mut self, sequence: u64);
+}
+
+/// A `CounterStore` that forgets everything on reboot.
+///
+/// The persistent stores keep a copy of their contents in one of these.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryCounterStore {
+    tx_counter: Option<u64>,
+    /// Least recently updated first
+    peers: heapless::Vec<(MacAddress, u64), MAX_PERSISTED_PEERS>,
+    command_sequence: Option<u64>,
+}
+impl MemoryCounterStore {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+}
+impl CounterStore for MemoryCounterStore {
+    fn load_tx_counter(&mut self) -> Option<u64> {
+        return self.tx_counter;
+    }
+    fn store_tx_counter(&mut self, counter: u64) {
+        self.tx_counter = Some(counter);
+    }
+    fn load_peer_counter(&mut self, peer: &MacAddress) -> Option<u64> {
+        return self
+            .peers
+            .iter()
+            .find(|(address, _)| address == peer)
+            .map(|(_, counter)| *counter);
+    }
+    fn store_peer_counter(&mut self, peer: &MacAddress, counter: u64) {
+        if let Some(i) = self.peers.iter().position(|(address, _)| address == peer) {
+            self.peers.remove(i);
+        } else if self.peers.is_full() {
+            self.peers.remove(0);
+        }
+        // There is always room after removing
+        let _ = self.peers.push((*peer, counter));
+    }
+    fn load_command_sequence(&mut self) -> Option<u64> {
+        return self.command_sequence;
+    }
+    fn store_command_sequence(&mut self, sequence: u64) {
+        self.command_sequence = Some(sequence);
+    }
+}
+
+/// Counters are never zero once stored, so zero stands for "not stored"
+fn encode_counter(counter: Option<u64>) -> [u8; 8] {
+    return counter.unwrap_or(0).to_be_bytes();
+}
+
+fn decode_counter(bytes: &[u8]) -> Option<u64> {
+    return match u64::from_be_bytes(bytes.try_into().unwrap()) {
+        0 => None,
+        counter => Some(counter),
+    };
+}
+
+fn encode_record(counters: &MemoryCounterStore, generation: u32) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..4].copy_from_slice(&RECORD_MAGIC);
+    record[4] = RECORD_VERSION;
+    record[5..9].copy_from_slice(&generation.to_be_bytes());
+    record[9..17].copy_from_slice(&encode_counter(counters.tx_counter));
+    record[17..25].copy_from_slice(&encode_counter(counters.command_sequence));
+    record[25] = counters.peers.len() as u8;
+    for (i, (address, counter)) in counters.peers.iter().enumerate() {
+        let entry = &mut record[26 + i * PEER_ENTRY_LEN..26 + (i + 1) * PEER_ENTRY_LEN];
+        entry[0..6].copy_from_slice(address);
+        entry[6..].copy_from_slice(&counter.to_be_bytes());
+    }
+    let crc_start = RECORD_LEN - 4;
+    let crc = crc32(&record[0..crc_start]);
+    record[crc_start..].copy_from_slice(&crc.to_be_bytes());
+    return record;
+}
+
+/// Decodes a stored record along with its generation.
+///
+/// Returns `None` for erased storage as well as for corrupted records.
+fn decode_record(record: &[u8; RECORD_LEN]) -> Option<(u32, MemoryCounterStore)> {
+    if record[0..4] != RECORD_MAGIC || record[4] != RECORD_VERSION {
+        return None;
+    }
+    let crc_start = RECORD_LEN - 4;
+    let crc = u32::from_be_bytes(record[crc_start..].try_into().unwrap());
+    if crc != crc32(&record[0..crc_start]) {
+        return None;
+    }
+    let peer_count = record[25] as usize;
+    if peer_count > MAX_PERSISTED_PEERS {
+        return None;
+    }
+
+    let mut counters = MemoryCounterStore {
+        tx_counter: decode_counter(&record[9..17]),
+        peers: heapless::Vec::new(),
+        command_sequence: decode_counter(&record[17..25]),
+    };
+    for i in 0..peer_count {
+        let entry = &record[26 + i * PEER_ENTRY_LEN..26 + (i + 1) * PEER_ENTRY_LEN];
+        let address: MacAddress = entry[0..6].try_into().unwrap();
+        let counter = u64::from_be_bytes(entry[6..].try_into().unwrap());
+        let _ = counters.peers.push((address, counter));
+    }
+    let generation = u32::from_be_bytes(record[5..9].try_into().unwrap());
+    return Some((generation, counters));
+}
@@ -0,0 +1,14 @@
+//! Checksums for data at rest and on the wire.
+
+/// CRC-32 (IEEE 802.3), as used by zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    return !crc;
+}
@@ -0,0 +1,59 @@
+//! Software stand-in for `esp_hal::aes`.
+
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Encryption128,
+    Encryption256,
+    Decryption128,
+    Decryption256,
+}
+
+/// Key material accepted by `Aes::process`
+pub enum Key {
+    Key16([u8; 16]),
+    Key32([u8; 32]),
+}
+impl From<[u8; 16]> for Key {
+    fn from(key: [u8; 16]) -> Self {
+        return Self::Key16(key);
+    }
+}
+impl From<[u8; 32]> for Key {
+    fn from(key: [u8; 32]) -> Self {
+        return Self::Key32(key);
+    }
+}
+
+/// Software AES block cipher mirroring the ESP32 AES peripheral
+pub struct Aes<'d> {
+    _peripheral: core::marker::PhantomData<&'d ()>,
+}
+impl<'d> Aes<'d> {
+    pub fn new() -> Self {
+        return Self {
+            _peripheral: core::marker::PhantomData,
+        };
+    }
+
+    /// Encrypts or decrypts a single block in-place
+    pub fn process<K: Into<Key>>(&mut self, block: &mut [u8; 16], mode: Mode, key: K) {
+        let block = GenericArray::from_mut_slice(block);
+        match (mode, key.into()) {
+            (Mode::Encryption128, Key::Key16(key)) => {
+                aes::Aes128::new(&key.into()).encrypt_block(block);
+            }
+            (Mode::Decryption128, Key::Key16(key)) => {
+                aes::Aes128::new(&key.into()).decrypt_block(block);
+            }
+            (Mode::Encryption256, Key::Key32(key)) => {
+                aes::Aes256::new(&key.into()).encrypt_block(block);
+            }
+            (Mode::Decryption256, Key::Key32(key)) => {
+                aes::Aes256::new(&key.into()).decrypt_block(block);
+            }
+            (mode, _) => panic!("Key size does not match AES mode {mode:?}"),
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! In-memory stand-in for `esp_wifi::esp_now`.
+//!
+//! Every `EspNow` created from the same `Medium` can hear every other one,
+//! as if they were all within radio range of each other. Like esp-wifi, only
+//! the broadcast address is registered to begin with, and unicast to any
+//! other address fails until it has been added with `add_peer`.
+
+use std::{
+    collections::VecDeque,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+pub const ESP_NOW_MAX_DATA_LEN: usize = 250;
+pub const BROADCAST_ADDRESS: [u8; 6] = [0xff; 6];
+/// Most peers that can be registered at once, including the broadcast address
+pub const ESP_NOW_MAX_TOTAL_PEER_NUM: usize = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EspNowError {
+    /// The payload was larger than `ESP_NOW_MAX_DATA_LEN`
+    TooLarge,
+    /// The destination hasn't been registered with `add_peer`
+    NotFound,
+    /// The peer is already registered
+    PeerExists,
+    /// `ESP_NOW_MAX_TOTAL_PEER_NUM` peers are already registered
+    PeerListFull,
+    /// No station with that address acknowledged the frame
+    SendFailed,
+}
+
+/// Mirrors the peer registration accepted by esp-wifi
+#[derive(Debug, Clone, Copy)]
+pub struct PeerInfo {
+    pub peer_address: [u8; 6],
+    pub lmk: Option<[u8; 16]>,
+    pub channel: Option<u8>,
+    pub encrypt: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct RxControlInfo {
+    pub rssi: i32,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceiveInfo {
+    pub src_address: [u8; 6],
+    pub dst_address: [u8; 6],
+    pub rx_control: RxControlInfo,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReceivedData {
+    pub len: u8,
+    pub data: [u8; ESP_NOW_MAX_DATA_LEN],
+    pub info: ReceiveInfo,
+}
+
+struct Station {
+    address: [u8; 6],
+    inbox: VecDeque<ReceivedData>,
+}
+
+/// Shared "airspace" that connects simulated ESP-NOW stations
+#[derive(Clone, Default)]
+pub struct Medium {
+    stations: Arc<Mutex<Vec<Station>>>,
+}
+impl Medium {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+}
+
+pub struct EspNow<'d> {
+    medium: Medium,
+    address: [u8; 6],
+    peers: Vec<[u8; 6]>,
+    _device: PhantomData<&'d ()>,
+}
+impl<'d> EspNow<'d> {
+    /// Attaches a new station with the given MAC address to `medium`
+    pub fn new(medium: &Medium, address: [u8; 6]) -> Result<Self, EspNowError> {
+        medium.stations.lock().unwrap().push(Station {
+            address,
+            inbox: VecDeque::new(),
+        });
+        return Ok(Self {
+            medium: medium.clone(),
+            address,
+            peers: vec![BROADCAST_ADDRESS],
+            _device: PhantomData,
+        });
+    }
+
+    /// The MAC address this station was attached with
+    pub fn address(&self) -> [u8; 6] {
+        return self.address;
+    }
+
+    pub fn get_version(&self) -> Result<u32, EspNowError> {
+        return Ok(1);
+    }
+
+    pub fn add_peer(&mut self, peer: PeerInfo) -> Result<(), EspNowError> {
+        if self.peer_exists(&peer.peer_address) {
+            return Err(EspNowError::PeerExists);
+        }
+        if self.peers.len() == ESP_NOW_MAX_TOTAL_PEER_NUM {
+            return Err(EspNowError::PeerListFull);
+        }
+        self.peers.push(peer.peer_address);
+        return Ok(());
+    }
+
+    pub fn remove_peer(&mut self, peer_address: &[u8; 6]) -> Result<(), EspNowError> {
+        if !self.peer_exists(peer_address) {
+            return Err(EspNowError::NotFound);
+        }
+        self.peers.retain(|peer| peer != peer_address);
+        return Ok(());
+    }
+
+    pub fn peer_exists(&self, peer_address: &[u8; 6]) -> bool {
+        return self.peers.contains(peer_address);
+    }
+
+    pub fn send(&mut self, dst_addr: &[u8; 6], data: &[u8]) -> Result<SendWaiter, EspNowError> {
+        if data.len() > ESP_NOW_MAX_DATA_LEN {
+            return Err(EspNowError::TooLarge);
+        }
+        if !self.peer_exists(dst_addr) {
+            return Err(EspNowError::NotFound);
+        }
+        let mut frame = ReceivedData {
+            len: data.len() as u8,
+            data: [0u8; ESP_NOW_MAX_DATA_LEN],
+            info: ReceiveInfo {
+                src_address: self.address,
+                dst_address: *dst_addr,
+                rx_control: RxControlInfo { rssi: -40 },
+            },
+        };
+        frame.data[0..data.len()].copy_from_slice(data);
+
+        let mut delivered = false;
+        let mut stations = self.medium.stations.lock().unwrap();
+        for station in stations.iter_mut() {
+            if station.address == self.address {
+                continue;
+            }
+            if *dst_addr == BROADCAST_ADDRESS || station.address == *dst_addr {
+                station.inbox.push_back(frame.clone());
+                delivered = true;
+            }
+        }
+
+        return Ok(SendWaiter {
+            result: if delivered || *dst_addr == BROADCAST_ADDRESS {
+                Ok(())
+            } else {
+                Err(EspNowError::SendFailed)
+            },
+        });
+    }
+
+    pub fn receive(&self) -> Option<ReceivedData> {
+        let mut stations = self.medium.stations.lock().unwrap();
+        return stations
+            .iter_mut()
+            .find(|station| station.address == self.address)?
+            .inbox
+            .pop_front();
+    }
+}
+impl<'d> Drop for EspNow<'d> {
+    fn drop(&mut self) {
+        if let Ok(mut stations) = self.medium.stations.lock() {
+            stations.retain(|station| station.address != self.address);
+        }
+    }
+}
+
+/// Mirrors the send-completion handle returned by esp-wifi
+pub struct SendWaiter {
+    result: Result<(), EspNowError>,
+}
+impl SendWaiter {
+    pub fn wait(self) -> Result<(), EspNowError> {
+        return self.result;
+    }
+}
+impl Drop for SendWaiter {
+    /// esp-wifi spins here until the frame has gone out, which in this
+    /// stand-in it already has
+    fn drop(&mut self) {}
+}
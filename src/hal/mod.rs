@@ -0,0 +1,36 @@
+//! Peripheral shim.
+//!
+//! With the `esp32` feature this simply re-exports the modules from `esp-hal`
+//! and `esp-wifi`. With the `std` feature, software implementations with the
+//! same shape are used instead so the library can be built and tested on a
+//! regular host:
+//!
+//! ```sh
+//! cargo +stable test --no-default-features --features std --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The rest of the crate should import peripherals from here rather than from
+//! `esp_hal` directly.
+
+#[cfg(all(not(feature = "esp32"), not(feature = "std")))]
+compile_error!("Either the `esp32` or the `std` feature must be enabled.");
+
+#[cfg(feature = "esp32")]
+pub use esp_hal::{aes, rng, sha, time};
+#[cfg(feature = "esp32")]
+pub use esp_println::println;
+#[cfg(feature = "esp32")]
+pub use esp_wifi::esp_now;
+
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub mod aes;
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub mod esp_now;
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub mod rng;
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub mod sha;
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub mod time;
+#[cfg(all(feature = "std", not(feature = "esp32")))]
+pub use std::println;
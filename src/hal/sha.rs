@@ -0,0 +1,68 @@
+//! Software stand-in for `esp_hal::sha`.
+
+use core::{borrow::BorrowMut, convert::Infallible, marker::PhantomData};
+use sha2::Digest;
+
+/// Hash algorithm that can be run on the `Sha` peripheral
+pub trait ShaAlgorithm {
+    #[doc(hidden)]
+    type Hasher: Digest + Default;
+}
+
+pub struct Sha256;
+impl ShaAlgorithm for Sha256 {
+    type Hasher = sha2::Sha256;
+}
+
+/// Software SHA engine mirroring the ESP32 SHA peripheral
+pub struct Sha<'d> {
+    _peripheral: PhantomData<&'d ()>,
+}
+impl<'d> Sha<'d> {
+    pub fn new() -> Self {
+        return Self {
+            _peripheral: PhantomData,
+        };
+    }
+
+    pub fn start<'a, A: ShaAlgorithm>(&'a mut self) -> ShaDigest<'d, A, &'a mut Self> {
+        return ShaDigest::new(self);
+    }
+}
+
+/// An in-progress digest.
+///
+/// Like the hardware version, `update` returns the bytes it did not consume
+/// and both methods may return `WouldBlock`. The software implementation
+/// always consumes everything and never blocks.
+pub struct ShaDigest<'d, A: ShaAlgorithm, S: BorrowMut<Sha<'d>>> {
+    sha: S,
+    hasher: A::Hasher,
+    _peripheral: PhantomData<&'d ()>,
+}
+impl<'d, A: ShaAlgorithm, S: BorrowMut<Sha<'d>>> ShaDigest<'d, A, S> {
+    pub fn new(sha: S) -> Self {
+        return Self {
+            sha,
+            hasher: A::Hasher::default(),
+            _peripheral: PhantomData,
+        };
+    }
+
+    pub fn update<'b>(&mut self, incoming: &'b [u8]) -> nb::Result<&'b [u8], Infallible> {
+        self.hasher.update(incoming);
+        return Ok(&incoming[incoming.len()..]);
+    }
+
+    pub fn finish(&mut self, output: &mut [u8]) -> nb::Result<(), Infallible> {
+        let digest = core::mem::take(&mut self.hasher).finalize();
+        let len = output.len().min(digest.len());
+        output[..len].copy_from_slice(&digest[..len]);
+        return Ok(());
+    }
+
+    /// Discards the digest and gives back the peripheral
+    pub fn cancel(self) -> S {
+        return self.sha;
+    }
+}
@@ -0,0 +1,14 @@
+//! Software stand-in for `esp_hal::time`.
+
+use std::sync::OnceLock;
+
+pub type Instant = fugit::Instant<u64, 1, 1_000_000>;
+pub type Duration = fugit::MicrosDurationU64;
+
+static BOOT: OnceLock<std::time::Instant> = OnceLock::new();
+
+/// Time since the first call to `now`, mirroring the ESP32 system timer
+pub fn now() -> Instant {
+    let boot = BOOT.get_or_init(std::time::Instant::now);
+    return Instant::from_ticks(boot.elapsed().as_micros() as u64);
+}
@@ -0,0 +1,73 @@
+//! HKDF-SHA256 (RFC 5869) built on the hardware-accelerated `hmac_chunk`.
+//!
+//! Used to turn one high-entropy secret into several independent keys, so the
+//! same bytes are never used for two different purposes.
+
+use crate::{
+    hal::sha::Sha,
+    hw_hmac::{hmac_chunk, HmacContext, HmacError, HASH_SIZE},
+};
+use thiserror::Error;
+
+/// The most output a single expansion can produce
+pub const MAX_OUTPUT_LEN: usize = 255 * HASH_SIZE;
+
+#[derive(Error, Debug)]
+pub enum HkdfError {
+    #[error("HKDF output is limited to {MAX_OUTPUT_LEN} bytes")]
+    OutputTooLong,
+    #[error(transparent)]
+    Hmac(#[from] HmacError),
+}
+
+/// Condenses `ikm` into a pseudorandom key.
+///
+/// An empty `salt` is treated as `HASH_SIZE` zero bytes, as the RFC requires.
+pub fn extract(
+    sha_peripheral: &mut Sha<'_>,
+    salt: &[u8],
+    ikm: &[u8],
+) -> Result<[u8; HASH_SIZE], HmacError> {
+    if salt.is_empty() {
+        return hmac_chunk(sha_peripheral, &[0u8; HASH_SIZE], ikm);
+    }
+    return hmac_chunk(sha_peripheral, salt, ikm);
+}
+
+/// Stretches `prk` into `okm.len()` bytes of key material bound to `info`
+pub fn expand(
+    sha_peripheral: &mut Sha<'_>,
+    prk: &[u8],
+    info: &[u8],
+    okm: &mut [u8],
+) -> Result<(), HkdfError> {
+    if okm.len() > MAX_OUTPUT_LEN {
+        return Err(HkdfError::OutputTooLong);
+    }
+
+    // T(i) = HMAC(PRK, T(i - 1) | info | i)
+    let mut t = [0u8; HASH_SIZE];
+    for (i, block) in okm.chunks_mut(HASH_SIZE).enumerate() {
+        let mut context = HmacContext::new(sha_peripheral, prk)?;
+        if i > 0 {
+            context.update(&t)?;
+        }
+        context.update(info)?;
+        context.update(&[i as u8 + 1])?;
+        t = context.finalize()?;
+        block.copy_from_slice(&t[0..block.len()]);
+    }
+    return Ok(());
+}
+
+/// Runs `extract` followed by `expand`
+pub fn derive(
+    sha_peripheral: &mut Sha<'_>,
+    salt: &[u8],
+    ikm: &[u8],
+    info: &[u8],
+    okm: &mut [u8],
+) -> Result<(), HkdfError> {
+    let prk = extract(sha_peripheral, salt, ikm)?;
+    return expand(sha_peripheral, &prk, info, okm);
+}
@@ -1,115 +1,217 @@
 extern crate alloc;
 
 use alloc::vec::Vec;
-use esp_hal::{aes::Aes, rng::Rng};
+use crate::hal::aes::{Aes, Mode};
+use crate::hw_hmac;
 use thiserror::Error;
 
 pub const AES_BLOCK_SIZE: usize = 16;
-pub const IV_SIZE: usize = AES_BLOCK_SIZE;
 pub const AES_KEY_SIZE: usize = 32;
 
-/// Encrypts a packet using AES-256.
+#[derive(Error, Debug, Clone)]
+pub enum DecryptionError {
+    #[error("The packet to be decrypted was not the correct length")]
+    InvalidLength,
+    #[error("The packet's authentication tag did not match")]
+    AuthenticationFailed,
+}
+
+/// Length of the nonce used with `seal_packet`.
 ///
-/// IV is randomly generated and appended to the end of the packet.
-/// Garbled data may appear at the end of the packet after decryption.
-/// Trimming should be done through other means.
-pub fn encrypt_packet(
-    aes_peripheral: &mut Aes<'_>,
-    rng_peripheral: &mut Rng,
-    key: &[u8; AES_KEY_SIZE],
-    packet: &mut Vec<u8>,
-) {
-    // Generate IV
-    let mut iv = [0u8; IV_SIZE];
-    rng_peripheral.read(&mut iv);
+/// This is the longest CCM allows, leaving a 2-byte length field, which is
+/// plenty for a single frame.
+pub const CCM_NONCE_SIZE: usize = 13;
+/// Longest CCM authentication tag
+pub const CCM_TAG_SIZE: usize = 16;
+/// Bytes added to a packet by `seal_packet` with a full-length tag
+pub const AEAD_OVERHEAD: usize = CCM_TAG_SIZE;
+
+/// Builds the CCM counter block `A_i` (or `B_0` when `flags` includes the MAC bits).
+///
+/// The rest of the block after the nonce holds `counter`.
+fn ccm_block(flags: u8, nonce: &[u8], counter: u64) -> [u8; AES_BLOCK_SIZE] {
+    let mut block = [0u8; AES_BLOCK_SIZE];
+    block[0] = flags;
+    block[1..1 + nonce.len()].copy_from_slice(nonce);
+    let counter_bytes = counter.to_be_bytes();
+    let length_size = AES_BLOCK_SIZE - 1 - nonce.len();
+    block[1 + nonce.len()..].copy_from_slice(&counter_bytes[counter_bytes.len() - length_size..]);
+    return block;
+}
 
-    let mut cursor = 0;
+/// Size of the length field CCM uses with a nonce of `nonce_len` bytes
+const fn ccm_length_size(nonce_len: usize) -> usize {
+    return AES_BLOCK_SIZE - 1 - nonce_len;
+}
 
-    // Ensure proper block size and allocate room to add the IV later
-    let padding = AES_BLOCK_SIZE - (packet.len() % AES_BLOCK_SIZE);
-    packet.reserve_exact(padding + IV_SIZE);
-    for _ in 0..padding {
-        packet.push(0);
+/// Checks the parameters CCM places limits on
+fn ccm_params_valid(nonce: &[u8], data_len: usize, tag_len: usize) -> bool {
+    if nonce.len() < 7 || nonce.len() > 13 {
+        return false;
+    }
+    if tag_len < 4 || tag_len > 16 || tag_len % 2 != 0 {
+        return false;
     }
+    let length_size = ccm_length_size(nonce.len());
+    return length_size >= 8 || (data_len as u64) < 1 << (8 * length_size);
+}
 
-    while cursor < packet.len() {
-        // Scramble
-        if cursor == 0 {
-            for i in 0..AES_BLOCK_SIZE {
-                // XOR against IV for first packet
-                packet[i] = packet[i] ^ iv[i];
-            }
-        } else {
-            for i in 0..AES_BLOCK_SIZE {
-                // XOR against previous encrypted packet
-                packet[i+cursor] = packet[i+cursor] ^ packet[i+cursor-AES_BLOCK_SIZE];
+/// Computes the raw CCM CBC-MAC over the associated data and plaintext
+fn ccm_cbc_mac(
+    aes_peripheral: &mut Aes<'_>,
+    key: &[u8; AES_KEY_SIZE],
+    nonce: &[u8],
+    aad: &[u8],
+    plaintext: &[u8],
+    tag_len: usize,
+) -> [u8; AES_BLOCK_SIZE] {
+    let adata_flag = if aad.is_empty() { 0 } else { 0x40 };
+    let flags = adata_flag
+        | ((((tag_len - 2) / 2) as u8) << 3)
+        | (ccm_length_size(nonce.len()) as u8 - 1);
+    let mut mac = ccm_block(flags, nonce, plaintext.len() as u64);
+    aes_peripheral.process(&mut mac, Mode::Encryption256, key.clone());
+
+    // Associated data is prefixed with its length and zero-padded to the block size
+    if !aad.is_empty() {
+        let aad_len = (aad.len() as u16).to_be_bytes();
+        mac[0] ^= aad_len[0];
+        mac[1] ^= aad_len[1];
+        let mut block_cursor = 2;
+        for byte in aad {
+            if block_cursor == AES_BLOCK_SIZE {
+                aes_peripheral.process(&mut mac, Mode::Encryption256, key.clone());
+                block_cursor = 0;
             }
+            mac[block_cursor] ^= byte;
+            block_cursor += 1;
         }
+        aes_peripheral.process(&mut mac, Mode::Encryption256, key.clone());
+    }
 
-        // Encrypt
-        let packet_block: &mut [u8; AES_BLOCK_SIZE] = (&mut packet
-            [cursor..cursor + AES_BLOCK_SIZE])
-            .try_into()
-            .unwrap();
-        aes_peripheral.process(packet_block, esp_hal::aes::Mode::Encryption256, key.clone());
-
-        // Increment cursor
-        cursor += AES_BLOCK_SIZE;
+    // Plaintext is zero-padded to the block size
+    for block in plaintext.chunks(AES_BLOCK_SIZE) {
+        for (i, byte) in block.iter().enumerate() {
+            mac[i] ^= byte;
+        }
+        aes_peripheral.process(&mut mac, Mode::Encryption256, key.clone());
     }
 
-    // Append IV to the end of the packet
-    packet.extend_from_slice(&iv);
+    return mac;
 }
 
-#[derive(Error, Debug, Clone)]
-pub enum DecryptionError {
-    #[error("The packet to be decrypted was not the correct length")]
-    InvalidLength,
+/// Applies the CCM keystream to `data`, starting at counter 1
+fn ccm_ctr(aes_peripheral: &mut Aes<'_>, key: &[u8; AES_KEY_SIZE], nonce: &[u8], data: &mut [u8]) {
+    let flags = ccm_length_size(nonce.len()) as u8 - 1;
+    for (i, data_block) in data.chunks_mut(AES_BLOCK_SIZE).enumerate() {
+        let mut keystream = ccm_block(flags, nonce, i as u64 + 1);
+        aes_peripheral.process(&mut keystream, Mode::Encryption256, key.clone());
+        for (byte, key_byte) in data_block.iter_mut().zip(keystream) {
+            *byte ^= key_byte;
+        }
+    }
 }
 
-pub fn decrypt_packet<'a>(
+/// Encrypts the CCM tag with the first keystream block
+fn ccm_mask_tag(
     aes_peripheral: &mut Aes<'_>,
     key: &[u8; AES_KEY_SIZE],
-    packet: &'a mut [u8],
-) -> Result<&'a mut [u8], DecryptionError> {
-    if packet.len() % AES_BLOCK_SIZE != 0 {
-        return Err(DecryptionError::InvalidLength);
+    nonce: &[u8],
+    mac: &mut [u8; AES_BLOCK_SIZE],
+) {
+    let mut keystream = ccm_block(ccm_length_size(nonce.len()) as u8 - 1, nonce, 0);
+    aes_peripheral.process(&mut keystream, Mode::Encryption256, key.clone());
+    for i in 0..AES_BLOCK_SIZE {
+        mac[i] ^= keystream[i];
     }
+}
 
-    // Get IV offset
-    let iv_start = packet.len() - IV_SIZE;
+/// Encrypts and authenticates `data` in-place using AES-256-CCM.
+///
+/// The nonce must be between 7 and 13 bytes, and must never be used twice
+/// with the same key. Shorter nonces allow longer data. `aad` is
+/// authenticated but not encrypted, and must be shorter than 0xFF00 bytes.
+/// The length of `tag` selects the tag size, and must be an even number
+/// between 4 and 16.
+pub fn ccm_encrypt(
+    aes_peripheral: &mut Aes<'_>,
+    key: &[u8; AES_KEY_SIZE],
+    nonce: &[u8],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &mut [u8],
+) {
+    assert!(
+        ccm_params_valid(nonce, data.len(), tag.len()),
+        "Invalid CCM nonce, data or tag length"
+    );
+    let mut mac = ccm_cbc_mac(aes_peripheral, key, nonce, aad, data, tag.len());
+    ccm_mask_tag(aes_peripheral, key, nonce, &mut mac);
+    tag.copy_from_slice(&mac[0..tag.len()]);
+    ccm_ctr(aes_peripheral, key, nonce, data);
+}
 
-    // Set up vector for CBC
-    let mut prev_encrypted_block = [0u8; IV_SIZE];
-    for i in 0..IV_SIZE {
-        prev_encrypted_block[i] = packet[iv_start + i];
+/// Decrypts and verifies `data` in-place using AES-256-CCM.
+///
+/// On failure, `data` is zeroed so unauthenticated plaintext is never exposed.
+pub fn ccm_decrypt(
+    aes_peripheral: &mut Aes<'_>,
+    key: &[u8; AES_KEY_SIZE],
+    nonce: &[u8],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8],
+) -> Result<(), DecryptionError> {
+    if !ccm_params_valid(nonce, data.len(), tag.len()) {
+        return Err(DecryptionError::InvalidLength);
     }
+    ccm_ctr(aes_peripheral, key, nonce, data);
+    let mut mac = ccm_cbc_mac(aes_peripheral, key, nonce, aad, data, tag.len());
+    ccm_mask_tag(aes_peripheral, key, nonce, &mut mac);
 
-    let packet: &mut [u8] = &mut packet[0..iv_start];
-
-    let mut cursor = 0;
-
-    while cursor < packet.len() {
-        let packet_block: &mut [u8; AES_BLOCK_SIZE] = (&mut packet
-            [cursor..cursor + AES_BLOCK_SIZE])
-            .try_into()
-            .unwrap();
-        let encrypted_packet_block = packet_block.clone();
-
-        // Decrypt
-        aes_peripheral.process(packet_block, esp_hal::aes::Mode::Decryption256, key.clone());
-
-        // Unscramble
-        for i in 0..AES_BLOCK_SIZE {
-            packet_block[i] = packet_block[i] ^ prev_encrypted_block[i];
-        }
+    if !hw_hmac::tags_match(&mac[0..tag.len()], tag) {
+        data.fill(0);
+        return Err(DecryptionError::AuthenticationFailed);
+    }
+    return Ok(());
+}
 
-        // Save encrypted block for next iteration
-        prev_encrypted_block = encrypted_packet_block;
+/// Encrypts and authenticates a packet using AES-256-CCM.
+///
+/// A `tag_len` byte tag is appended. The nonce isn't sent, so the receiver
+/// has to be able to rebuild it, e.g. from the sender's address and a
+/// counter carried in `aad`. It must never repeat under the same key.
+pub fn seal_packet(
+    aes_peripheral: &mut Aes<'_>,
+    key: &[u8; AES_KEY_SIZE],
+    nonce: &[u8; CCM_NONCE_SIZE],
+    aad: &[u8],
+    tag_len: usize,
+    packet: &mut Vec<u8>,
+) {
+    let mut tag = [0u8; CCM_TAG_SIZE];
+    let tag = &mut tag[0..tag_len];
+    ccm_encrypt(aes_peripheral, key, nonce, aad, packet, tag);
+    packet.extend_from_slice(tag);
+}
 
-        // Increment cursor
-        cursor += AES_BLOCK_SIZE;
+/// Verifies and decrypts a packet created by `seal_packet`.
+///
+/// `nonce` and `tag_len` must match what the packet was sealed with.
+/// Returns the plaintext portion of the buffer.
+pub fn open_packet<'a>(
+    aes_peripheral: &mut Aes<'_>,
+    key: &[u8; AES_KEY_SIZE],
+    nonce: &[u8; CCM_NONCE_SIZE],
+    aad: &[u8],
+    tag_len: usize,
+    packet: &'a mut [u8],
+) -> Result<&'a mut [u8], DecryptionError> {
+    if packet.len() < tag_len {
+        return Err(DecryptionError::InvalidLength);
     }
-
-    return Ok(packet);
+    let data_len = packet.len() - tag_len;
+    let (data, tag) = packet.split_at_mut(data_len);
+    ccm_decrypt(aes_peripheral, key, nonce, aad, data, tag)?;
+    return Ok(data);
 }
@@ -7,108 +7,163 @@
 //! sense of it, and doesn't support hardware acceleration on the ESP32 by default.
 //! That is likely going to be an issue since I want to HMAC every packet on esp-now
 
-use esp_hal::sha::{Sha, Sha256};
+use crate::hal::sha::{Sha, Sha256, ShaDigest};
+use thiserror::Error;
 
 const BLOCK_SIZE: usize = 64;
 pub const HASH_SIZE: usize = 32;
 const IPAD: u8 = 0x36;
 const OPAD: u8 = 0x5C;
 
-/// Verifies the authenticity of a packet using the cluster key
-pub fn authenticate_packet<'a>(sha_peripheral: &mut Sha<'_>, packet: &'a [u8]) -> Option<&'a [u8]> {
-    let hmac = &packet[0..HASH_SIZE];
-    let data = &packet[HASH_SIZE..];
-
-    let hash = hmac_cluster_chunk(sha_peripheral, data);
-    for i in 0..HASH_SIZE {
-        if hmac[i] != hash[i] {
-            return None;
-        }
-    }
-    return Some(data);
+#[derive(Error, Debug)]
+pub enum HmacError {
+    #[error("The SHA peripheral failed")]
+    Sha,
 }
 
-pub fn hmac_chunk(hash_peripheral: &mut Sha<'_>, key: &[u8], chunk: &[u8]) -> [u8; HASH_SIZE] {
-    let mut hasher = hash_peripheral.start::<Sha256>();
-    let mut ipad_key = [0u8; BLOCK_SIZE];
-    for (i, byte) in key.iter().enumerate() {
-        if i > BLOCK_SIZE {
-            // Not sure this is the right approach. May want to
-            // revisit later.
-            break;
-        }
-        ipad_key[i] = *byte ^ IPAD;
-    }
-    if let Err(err) = hasher.update(&ipad_key) {
-        log::error!("Failed to update hash: {err:?}");
-    }
+type Digest<'a, 'd> = ShaDigest<'d, Sha256, &'a mut Sha<'d>>;
 
-    if let Err(err) = hasher.update(chunk) {
-        log::error!("Failed to update hash: {err:?}");
+/// Feeds the peripheral until it has consumed all of `data`
+fn feed(hasher: &mut Digest<'_, '_>, data: &[u8]) -> Result<(), HmacError> {
+    let mut remaining = data;
+    while !remaining.is_empty() {
+        remaining = nb::block!(hasher.update(remaining)).map_err(|_| HmacError::Sha)?;
     }
+    return Ok(());
+}
+
+fn finish(hasher: &mut Digest<'_, '_>) -> Result<[u8; HASH_SIZE], HmacError> {
+    let mut hash = [0u8; HASH_SIZE];
+    nb::block!(hasher.finish(&mut hash)).map_err(|_| HmacError::Sha)?;
+    return Ok(hash);
+}
 
-    let mut initial_hash = [0u8; HASH_SIZE];
-    if let Err(err) = hasher.finish(&mut initial_hash) {
-        log::error!("Failed to update hash: {err:?}");
+/// Hashes the concatenation of `parts`
+fn sha256(sha_peripheral: &mut Sha<'_>, parts: &[&[u8]]) -> Result<[u8; HASH_SIZE], HmacError> {
+    let mut hasher = sha_peripheral.start::<Sha256>();
+    for part in parts {
+        feed(&mut hasher, part)?;
     }
-    drop(hasher);
+    return finish(&mut hasher);
+}
 
-    let mut hasher = hash_peripheral.start::<Sha256>();
-    let mut opad_key = [0u8; BLOCK_SIZE];
+/// Builds the ipad and opad blocks for `key` as described in RFC 2104.
+///
+/// Keys longer than a block are hashed first. Shorter keys are padded with
+/// zeros, which leaves the rest of each block as plain IPAD/OPAD.
+fn pad_key(
+    sha_peripheral: &mut Sha<'_>,
+    key: &[u8],
+) -> Result<([u8; BLOCK_SIZE], [u8; BLOCK_SIZE]), HmacError> {
+    let hashed_key;
+    let key = if key.len() > BLOCK_SIZE {
+        hashed_key = sha256(sha_peripheral, &[key])?;
+        &hashed_key[..]
+    } else {
+        key
+    };
+
+    let mut ipad = [IPAD; BLOCK_SIZE];
+    let mut opad = [OPAD; BLOCK_SIZE];
     for (i, byte) in key.iter().enumerate() {
-        if i > BLOCK_SIZE {
-            break;
-        }
-        opad_key[i] = *byte ^ OPAD;
-    }
-    if let Err(err) = hasher.update(&opad_key) {
-        log::error!("Failed to update hash: {err:?}");
-    }
-    if let Err(err) = hasher.update(&initial_hash) {
-        log::error!("Failed to update hash: {err:?}");
+        ipad[i] = *byte ^ IPAD;
+        opad[i] = *byte ^ OPAD;
     }
+    return Ok((ipad, opad));
+}
 
-    let mut final_hmac = [0u8; HASH_SIZE];
-    if let Err(err) = hasher.finish(&mut final_hmac) {
-        log::error!("Failed to update hash: {err:?}");
+/// How many bytes of authentication tag are sent with each frame.
+///
+/// Shorter tags leave more room for payload but are easier to forge by brute
+/// force, so the whole cluster has to agree on one. Frames are sealed with
+/// AES-CCM, whose tags are at most 16 bytes, so there is no option matching
+/// the full length of an HMAC. `Bytes8` is below the 80 bits RFC 2104
+/// recommends for truncated tags, and should only be used for traffic that is
+/// also protected against replay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TagLength {
+    Bytes8,
+    Bytes12,
+    #[default]
+    Bytes16,
+}
+impl TagLength {
+    /// Length of the tag in bytes
+    pub const fn bytes(self) -> usize {
+        return match self {
+            TagLength::Bytes8 => 8,
+            TagLength::Bytes12 => 12,
+            TagLength::Bytes16 => 16,
+        };
     }
+}
 
-    return final_hmac;
+/// Compares two tags in constant time.
+///
+/// Every byte is looked at, so the time taken doesn't reveal how much of a
+/// forged tag was right. Tags of different lengths never match; lengths
+/// aren't secret, so that check may return early.
+pub fn tags_match(expected: &[u8], tag: &[u8]) -> bool {
+    if expected.len() != tag.len() {
+        return false;
+    }
+    let mut difference = 0u8;
+    for (a, b) in expected.iter().zip(tag) {
+        difference |= a ^ b;
+    }
+    // Stop the compiler from turning the fold back into an early exit
+    return core::hint::black_box(difference) == 0;
 }
 
-pub static CLUSTER_KEY_IPAD: &[u8] = include_bytes!("../keys/cluster_key_ipad.dat");
-pub static CLUSTER_KEY_OPAD: &[u8] = include_bytes!("../keys/cluster_key_opad.dat");
+/// Computes HMAC-SHA256 of `chunk` under a key of any length
+pub fn hmac_chunk(
+    hash_peripheral: &mut Sha<'_>,
+    key: &[u8],
+    chunk: &[u8],
+) -> Result<[u8; HASH_SIZE], HmacError> {
+    let mut context = HmacContext::new(hash_peripheral, key)?;
+    context.update(chunk)?;
+    return context.finalize();
+}
 
-/// Accelerated form of `hmac_chunk` using pre-computed ipad
-/// and opad values for the cluster key
-pub fn hmac_cluster_chunk(hash_peripheral: &mut Sha<'_>, chunk: &[u8]) -> [u8; HASH_SIZE] {
-    let mut hasher = hash_peripheral.start::<Sha256>();
-    if let Err(err) = hasher.update(CLUSTER_KEY_IPAD) {
-        log::error!("Failed to update hash: {err:?}");
+/// Incremental HMAC-SHA256, for data that isn't available in one piece.
+///
+/// The context holds the SHA peripheral until it is finalized, so only one
+/// can be in progress at a time.
+pub struct HmacContext<'a, 'd> {
+    hasher: Digest<'a, 'd>,
+    opad: [u8; BLOCK_SIZE],
+}
+impl<'a, 'd> HmacContext<'a, 'd> {
+    /// Starts an HMAC under a key of any length
+    pub fn new(hash_peripheral: &'a mut Sha<'d>, key: &[u8]) -> Result<Self, HmacError> {
+        let (ipad, opad) = pad_key(hash_peripheral, key)?;
+        return Self::from_pads(hash_peripheral, &ipad, opad);
     }
 
-    if let Err(err) = hasher.update(chunk) {
-        log::error!("Failed to update hash: {err:?}");
+    fn from_pads(
+        hash_peripheral: &'a mut Sha<'d>,
+        ipad: &[u8; BLOCK_SIZE],
+        opad: [u8; BLOCK_SIZE],
+    ) -> Result<Self, HmacError> {
+        let mut hasher = hash_peripheral.start::<Sha256>();
+        feed(&mut hasher, ipad)?;
+        return Ok(Self { hasher, opad });
     }
 
-    let mut initial_hash = [0u8; HASH_SIZE];
-    if let Err(err) = hasher.finish(&mut initial_hash) {
-        log::error!("Failed to update hash: {err:?}");
+    pub fn update(&mut self, data: &[u8]) -> Result<(), HmacError> {
+        return feed(&mut self.hasher, data);
     }
-    drop(hasher);
 
-    let mut hasher = hash_peripheral.start::<Sha256>();
-    if let Err(err) = hasher.update(CLUSTER_KEY_OPAD) {
-        log::error!("Failed to update hash: {err:?}");
-    }
-    if let Err(err) = hasher.update(&initial_hash) {
-        log::error!("Failed to update hash: {err:?}");
+    pub fn finalize(mut self) -> Result<[u8; HASH_SIZE], HmacError> {
+        let initial_hash = finish(&mut self.hasher)?;
+        let hash_peripheral = self.hasher.cancel();
+        return sha256(hash_peripheral, &[&self.opad, &initial_hash]);
     }
 
-    let mut final_hmac = [0u8; HASH_SIZE];
-    if let Err(err) = hasher.finish(&mut final_hmac) {
-        log::error!("Failed to update hash: {err:?}");
+    /// Finalizes and checks the result against `tag`
+    pub fn verify(self, tag: &[u8]) -> Result<bool, HmacError> {
+        let hmac = self.finalize()?;
+        return Ok(tags_match(&hmac, tag));
     }
-
-    return final_hmac;
 }
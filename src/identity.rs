@@ -0,0 +1,41 @@
+//! Per-device Ed25519 signing keys.
+//!
+//! The cluster key only proves that a frame came from *some* member of the
+//! cluster. Commands that affect the vehicle or the cluster itself are also
+//! signed by the Commander, and nodes only act on them if the signature
+//! matches the Commander key pinned in their `DeviceIdentity`.
+
+use crate::key_store::{DeviceIdentity, PUBLIC_KEY_SIZE};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+
+pub const SIGNATURE_SIZE: usize = 64;
+
+impl DeviceIdentity {
+    /// The public half of this device's signing key
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        return SigningKey::from_bytes(&self.signing_seed)
+            .verifying_key()
+            .to_bytes();
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        return SigningKey::from_bytes(&self.signing_seed)
+            .sign(message)
+            .to_bytes();
+    }
+}
+
+/// Checks an Ed25519 signature, rejecting malleable and small-order encodings
+pub fn verify(
+    public_key: &[u8; PUBLIC_KEY_SIZE],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_SIZE],
+) -> bool {
+    let public_key = match VerifyingKey::from_bytes(public_key) {
+        Ok(public_key) => public_key,
+        Err(_) => return false,
+    };
+    return public_key
+        .verify_strict(message, &Signature::from_bytes(signature))
+        .is_ok();
+}
@@ -0,0 +1,70 @@
+use super::{
+    decode_identity_record, decode_record, encode_identity_record, encode_record, ClusterKey,
+    DeviceIdentity, KeyStore, KeyStoreError, IDENTITY_RECORD_LEN, RECORD_LEN,
+};
+use std::{ffi::OsString, fs, io::ErrorKind, path::PathBuf};
+
+/// Stores the cluster key in a file, for host builds.
+///
+/// The device identity is kept next to it, in `<path>.identity`.
+pub struct FileKeyStore {
+    path: PathBuf,
+    identity_path: PathBuf,
+}
+impl FileKeyStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let mut identity_path = OsString::from(path.as_os_str());
+        identity_path.push(".identity");
+        return Self {
+            path,
+            identity_path: identity_path.into(),
+        };
+    }
+}
+
+/// Reads a whole record, treating a missing file as an empty one
+fn read_record<const N: usize>(path: &PathBuf) -> Result<Option<[u8; N]>, KeyStoreError> {
+    let record = match fs::read(path) {
+        Ok(record) => record,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    return match record.as_slice().try_into() {
+        Ok(record) => Ok(Some(record)),
+        Err(_) => Err(KeyStoreError::Corrupted),
+    };
+}
+impl KeyStore for FileKeyStore {
+    fn load_cluster_key(&mut self) -> Result<Option<ClusterKey>, KeyStoreError> {
+        return match read_record::<RECORD_LEN>(&self.path)? {
+            Some(record) => decode_record(&record),
+            None => Ok(None),
+        };
+    }
+
+    fn store_cluster_key(&mut self, key: &ClusterKey) -> Result<(), KeyStoreError> {
+        fs::write(&self.path, encode_record(key))?;
+        return Ok(());
+    }
+
+    fn erase_cluster_key(&mut self) -> Result<(), KeyStoreError> {
+        return match fs::remove_file(&self.path) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err.into()),
+        };
+    }
+
+    fn load_identity(&mut self) -> Result<Option<DeviceIdentity>, KeyStoreError> {
+        return match read_record::<IDENTITY_RECORD_LEN>(&self.identity_path)? {
+            Some(record) => decode_identity_record(&record),
+            None => Ok(None),
+        };
+    }
+
+    fn store_identity(&mut self, identity: &DeviceIdentity) -> Result<(), KeyStoreError> {
+        fs::write(&self.identity_path, encode_identity_record(identity))?;
+        return Ok(());
+    }
+}
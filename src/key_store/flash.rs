@@ -0,0 +1,150 @@
+use super::{
+    decode_identity_record, decode_record, encode_identity_record, encode_record, is_erased,
+    ClusterKey, DeviceIdentity, KeyStore, KeyStoreError, IDENTITY_RECORD_LEN, RECORD_LEN,
+};
+use crate::crc::crc32;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Smallest unit of flash that can be erased
+pub const FLASH_SECTOR_SIZE: u32 = 0x1000;
+/// Sectors taken by a `FlashKeyStore`: two slots for the cluster key, then
+/// two for the identity
+pub const KEY_STORE_SECTORS: u32 = 4;
+const CLUSTER_KEY_SLOTS: u32 = 0;
+const IDENTITY_SLOTS: u32 = 2;
+
+const fn max(a: usize, b: usize) -> usize {
+    return if a > b { a } else { b };
+}
+/// Generation, record, then a CRC over both, padded to a write boundary
+const SLOT_RECORD_LEN: usize = max(RECORD_LEN, IDENTITY_RECORD_LEN);
+const SLOT_LEN: usize = (4 + SLOT_RECORD_LEN + 4).div_ceil(32) * 32;
+const SLOT_CRC_START: usize = 4 + SLOT_RECORD_LEN;
+
+/// Stores the cluster key and device identity in flash.
+///
+/// Each record has two slots, each in its own sector. A new record goes to
+/// the slot not holding the current one, tagged with the next generation, so
+/// a power loss partway through only loses the update rather than the key or
+/// identity it was replacing. Erasing the cluster key writes an empty record
+/// the same way.
+///
+/// On the ESP32 this is used with `esp_storage::FlashStorage` and the offset
+/// of the `keys` partition in `partitions.csv`, which must span
+/// `KEY_STORE_SECTORS` sectors.
+pub struct FlashKeyStore<S: NorFlash> {
+    storage: S,
+    offset: u32,
+}
+impl<S: NorFlash> FlashKeyStore<S> {
+    pub fn new(storage: S, offset: u32) -> Self {
+        assert!(
+            S::ERASE_SIZE == FLASH_SECTOR_SIZE as usize
+                && SLOT_LEN % S::WRITE_SIZE == 0
+                && SLOT_LEN % S::READ_SIZE == 0,
+            "Key records don't fit the flash geometry"
+        );
+        return Self { storage, offset };
+    }
+
+    fn slot_offset(&self, slot: u32) -> u32 {
+        return self.offset + slot * FLASH_SECTOR_SIZE;
+    }
+
+    /// Reads both slots starting at `first_slot`.
+    ///
+    /// Returns the index and generation of the newest valid slot along with
+    /// its record, if either is valid.
+    fn load_slot(
+        &mut self,
+        first_slot: u32,
+    ) -> Result<Option<(u32, u32, [u8; SLOT_LEN])>, KeyStoreError> {
+        let mut latest: Option<(u32, u32, [u8; SLOT_LEN])> = None;
+        let mut corrupted = false;
+        for slot in first_slot..first_slot + 2 {
+            let mut bytes = [0u8; SLOT_LEN];
+            self.storage
+                .read(self.slot_offset(slot), &mut bytes)
+                .map_err(|_| KeyStoreError::Storage)?;
+            if is_erased(&bytes) {
+                continue;
+            }
+            let crc = u32::from_be_bytes(
+                bytes[SLOT_CRC_START..SLOT_CRC_START + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            if crc != crc32(&bytes[0..SLOT_CRC_START]) {
+                corrupted = true;
+                continue;
+            }
+            let generation = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+            let newer = match latest {
+                Some((_, latest_generation, _)) => generation > latest_generation,
+                None => true,
+            };
+            if newer {
+                latest = Some((slot, generation, bytes));
+            }
+        }
+        if latest.is_none() && corrupted {
+            return Err(KeyStoreError::Corrupted);
+        }
+        return Ok(latest);
+    }
+
+    /// Writes `record` to whichever slot starting at `first_slot` doesn't
+    /// hold the newest record, with the next generation
+    fn store_slot(&mut self, first_slot: u32, record: &[u8]) -> Result<(), KeyStoreError> {
+        let (slot, generation) = match self.load_slot(first_slot) {
+            Ok(Some((slot, generation, _))) => {
+                (first_slot + (slot - first_slot + 1) % 2, generation + 1)
+            }
+            Ok(None) | Err(KeyStoreError::Corrupted) => (first_slot, 1),
+            Err(err) => return Err(err),
+        };
+        let mut bytes = [0xFF; SLOT_LEN];
+        bytes[0..4].copy_from_slice(&generation.to_be_bytes());
+        bytes[4..4 + record.len()].copy_from_slice(record);
+        let crc = crc32(&bytes[0..SLOT_CRC_START]);
+        bytes[SLOT_CRC_START..SLOT_CRC_START + 4].copy_from_slice(&crc.to_be_bytes());
+
+        let slot_offset = self.slot_offset(slot);
+        self.storage
+            .erase(slot_offset, slot_offset + FLASH_SECTOR_SIZE)
+            .map_err(|_| KeyStoreError::Storage)?;
+        return self
+            .storage
+            .write(slot_offset, &bytes)
+            .map_err(|_| KeyStoreError::Storage);
+    }
+}
+impl<S: NorFlash> KeyStore for FlashKeyStore<S> {
+    fn load_cluster_key(&mut self) -> Result<Option<ClusterKey>, KeyStoreError> {
+        return match self.load_slot(CLUSTER_KEY_SLOTS)? {
+            Some((_, _, bytes)) => decode_record(bytes[4..4 + RECORD_LEN].try_into().unwrap()),
+            None => Ok(None),
+        };
+    }
+
+    fn store_cluster_key(&mut self, key: &ClusterKey) -> Result<(), KeyStoreError> {
+        return self.store_slot(CLUSTER_KEY_SLOTS, &encode_record(key));
+    }
+
+    fn erase_cluster_key(&mut self) -> Result<(), KeyStoreError> {
+        return self.store_slot(CLUSTER_KEY_SLOTS, &[0xFF; RECORD_LEN]);
+    }
+
+    fn load_identity(&mut self) -> Result<Option<DeviceIdentity>, KeyStoreError> {
+        return match self.load_slot(IDENTITY_SLOTS)? {
+            Some((_, _, bytes)) => {
+                decode_identity_record(bytes[4..4 + IDENTITY_RECORD_LEN].try_into().unwrap())
+            }
+            None => Ok(None),
+        };
+    }
+
+    fn store_identity(&mut self, identity: &DeviceIdentity) -> Result<(), KeyStoreError> {
+        return self.store_slot(IDENTITY_SLOTS, &encode_identity_record(identity));
+    }
+}
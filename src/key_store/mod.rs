@@ -0,0 +1,220 @@
+//! Persistent storage for the cluster key and the device identity.
+//!
+//! Keys are installed at runtime (see `provisioning`) rather than compiled
+//! into the firmware, so every node can run the same image. The records stored
+//! on each backend are the same: a magic number, a format version, the
+//! payload, and a CRC-32 to detect torn or corrupted writes. `FlashKeyStore`
+//! keeps two generations of each so a torn write leaves the previous one.
+//!
+//! The identity lives in its own record so erasing the cluster key doesn't
+//! change who the device is or which Commander it trusts.
+
+mod flash;
+#[cfg(feature = "std")]
+mod file;
+
+pub use flash::{FlashKeyStore, FLASH_SECTOR_SIZE, KEY_STORE_SECTORS};
+#[cfg(feature = "std")]
+pub use file::FileKeyStore;
+
+use crate::{
+    crc::crc32,
+    hal::{rng::Rng, sha::Sha},
+    hkdf::{self, HkdfError},
+    hw_aes::AES_KEY_SIZE,
+    hw_hmac::HASH_SIZE,
+};
+use thiserror::Error;
+
+pub const CLUSTER_KEY_SIZE: usize = 64;
+
+/// Identifies which cluster key a frame was sealed with
+pub type KeyId = u8;
+
+/// The secret shared by every node in the cluster
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterKey {
+    pub id: KeyId,
+    pub secret: [u8; CLUSTER_KEY_SIZE],
+}
+impl ClusterKey {
+    /// Derives the working keys for this cluster key.
+    ///
+    /// The key ID is mixed in, so re-provisioning the same secret under a new
+    /// ID still yields fresh keys.
+    pub fn session_keys(&self, sha_peripheral: &mut Sha<'_>) -> Result<SessionKeys, HkdfError> {
+        let prk = hkdf::extract(sha_peripheral, SESSION_SALT, &self.secret)?;
+        let mut info = [0u8; 8 + 1];
+        info[8] = self.id;
+
+        let mut keys = SessionKeys {
+            encryption: [0u8; AES_KEY_SIZE],
+            authentication: [0u8; HASH_SIZE],
+        };
+        info[0..8].copy_from_slice(b"ttc-encr");
+        hkdf::expand(sha_peripheral, &prk, &info, &mut keys.encryption)?;
+        info[0..8].copy_from_slice(b"ttc-auth");
+        hkdf::expand(sha_peripheral, &prk, &info, &mut keys.authentication)?;
+        return Ok(keys);
+    }
+}
+
+const SESSION_SALT: &[u8] = b"tactile-tesla session keys v1";
+
+/// Independent keys derived from a `ClusterKey`
+#[derive(Clone)]
+pub struct SessionKeys {
+    /// AES-256 key for sealing cluster frames
+    pub encryption: [u8; AES_KEY_SIZE],
+    /// HMAC key for anything authenticated outside of the AEAD
+    pub authentication: [u8; HASH_SIZE],
+}
+
+pub const SIGNING_SEED_SIZE: usize = 32;
+pub const PUBLIC_KEY_SIZE: usize = 32;
+
+/// This device's Ed25519 identity and the Commander it takes orders from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceIdentity {
+    pub signing_seed: [u8; SIGNING_SEED_SIZE],
+    /// Public key of the Commander, pinned during provisioning or pairing
+    pub commander_key: Option<[u8; PUBLIC_KEY_SIZE]>,
+}
+
+const RECORD_MAGIC: [u8; 4] = *b"TTKS";
+const RECORD_VERSION: u8 = 2;
+/// Magic, version, key ID, key, CRC
+pub const RECORD_LEN: usize = 4 + 1 + 1 + CLUSTER_KEY_SIZE + 4;
+
+const IDENTITY_RECORD_MAGIC: [u8; 4] = *b"TTID";
+const IDENTITY_RECORD_VERSION: u8 = 1;
+/// Magic, version, signing seed, commander key flag, commander key, CRC
+pub const IDENTITY_RECORD_LEN: usize = 4 + 1 + SIGNING_SEED_SIZE + 1 + PUBLIC_KEY_SIZE + 4;
+
+#[derive(Error, Debug)]
+pub enum KeyStoreError {
+    #[error("No cluster key has been provisioned")]
+    NotProvisioned,
+    #[error("The stored key record is corrupted")]
+    Corrupted,
+    #[error("The underlying storage failed")]
+    Storage,
+    #[cfg(feature = "std")]
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+pub trait KeyStore {
+    /// Loads the cluster key, if one has been provisioned
+    fn load_cluster_key(&mut self) -> Result<Option<ClusterKey>, KeyStoreError>;
+    /// Installs or replaces the cluster key
+    fn store_cluster_key(&mut self, key: &ClusterKey) -> Result<(), KeyStoreError>;
+    /// Removes the cluster key, returning the node to the unprovisioned state
+    fn erase_cluster_key(&mut self) -> Result<(), KeyStoreError>;
+    /// Loads the device identity, if one has been created
+    fn load_identity(&mut self) -> Result<Option<DeviceIdentity>, KeyStoreError>;
+    /// Installs or replaces the device identity
+    fn store_identity(&mut self, identity: &DeviceIdentity) -> Result<(), KeyStoreError>;
+}
+
+/// Loads the device identity, creating one with a fresh signing key on first boot
+pub fn load_or_create_identity(
+    key_store: &mut impl KeyStore,
+    rng_peripheral: &mut Rng,
+) -> Result<DeviceIdentity, KeyStoreError> {
+    if let Some(identity) = key_store.load_identity()? {
+        return Ok(identity);
+    }
+    let mut identity = DeviceIdentity {
+        signing_seed: [0u8; SIGNING_SEED_SIZE],
+        commander_key: None,
+    };
+    rng_peripheral.read(&mut identity.signing_seed);
+    key_store.store_identity(&identity)?;
+    return Ok(identity);
+}
+
+/// Returns true if `record` looks like erased storage (all `0xFF` or all `0x00`)
+fn is_erased(record: &[u8]) -> bool {
+    return record.iter().all(|byte| *byte == 0xFF) || record.iter().all(|byte| *byte == 0x00);
+}
+
+/// Checks the magic, version and CRC of a record
+fn check_record(record: &[u8], magic: [u8; 4], version: u8) -> Result<(), KeyStoreError> {
+    if record[0..4] != magic || record[4] != version {
+        return Err(KeyStoreError::Corrupted);
+    }
+    let crc_start = record.len() - 4;
+    let crc = u32::from_be_bytes(record[crc_start..].try_into().unwrap());
+    if crc != crc32(&record[0..crc_start]) {
+        return Err(KeyStoreError::Corrupted);
+    }
+    return Ok(());
+}
+
+/// Appends the CRC over everything before it
+fn seal_record(record: &mut [u8]) {
+    let crc_start = record.len() - 4;
+    let crc = crc32(&record[0..crc_start]);
+    record[crc_start..].copy_from_slice(&crc.to_be_bytes());
+}
+
+fn encode_record(key: &ClusterKey) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0..4].copy_from_slice(&RECORD_MAGIC);
+    record[4] = RECORD_VERSION;
+    record[5] = key.id;
+    record[6..6 + CLUSTER_KEY_SIZE].copy_from_slice(&key.secret);
+    seal_record(&mut record);
+    return record;
+}
+
+/// Decodes a stored record.
+///
+/// Erased storage (all `0xFF` or all `0x00`) is treated as unprovisioned.
+fn decode_record(record: &[u8; RECORD_LEN]) -> Result<Option<ClusterKey>, KeyStoreError> {
+    if is_erased(record) {
+        return Ok(None);
+    }
+    check_record(record, RECORD_MAGIC, RECORD_VERSION)?;
+    return Ok(Some(ClusterKey {
+        id: record[5],
+        secret: record[6..6 + CLUSTER_KEY_SIZE].try_into().unwrap(),
+    }));
+}
+
+fn encode_identity_record(identity: &DeviceIdentity) -> [u8; IDENTITY_RECORD_LEN] {
+    let mut record = [0u8; IDENTITY_RECORD_LEN];
+    record[0..4].copy_from_slice(&IDENTITY_RECORD_MAGIC);
+    record[4] = IDENTITY_RECORD_VERSION;
+    record[5..5 + SIGNING_SEED_SIZE].copy_from_slice(&identity.signing_seed);
+    if let Some(commander_key) = identity.commander_key {
+        record[5 + SIGNING_SEED_SIZE] = 1;
+        record[6 + SIGNING_SEED_SIZE..6 + SIGNING_SEED_SIZE + PUBLIC_KEY_SIZE]
+            .copy_from_slice(&commander_key);
+    }
+    seal_record(&mut record);
+    return record;
+}
+
+fn decode_identity_record(
+    record: &[u8; IDENTITY_RECORD_LEN],
+) -> Result<Option<DeviceIdentity>, KeyStoreError> {
+    if is_erased(record) {
+        return Ok(None);
+    }
+    check_record(record, IDENTITY_RECORD_MAGIC, IDENTITY_RECORD_VERSION)?;
+    let commander_key = match record[5 + SIGNING_SEED_SIZE] {
+        0 => None,
+        1 => Some(
+            record[6 + SIGNING_SEED_SIZE..6 + SIGNING_SEED_SIZE + PUBLIC_KEY_SIZE]
+                .try_into()
+                .unwrap(),
+        ),
+        _ => return Err(KeyStoreError::Corrupted),
+    };
+    return Ok(Some(DeviceIdentity {
+        signing_seed: record[5..5 + SIGNING_SEED_SIZE].try_into().unwrap(),
+        commander_key,
+    }));
+}
@@ -5,11 +5,19 @@
 //% FEATURES: esp-wifi esp-wifi/wifi-default esp-wifi/wifi esp-wifi/utils esp-wifi/esp-now
 //% CHIPS: esp32 esp32s2 esp32s3 esp32c2 esp32c3 esp32c6
 
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 pub mod binary_packets;
+pub mod counter_store;
+pub mod crc;
+pub mod hal;
+pub mod hkdf;
 pub mod hw_aes;
 pub mod hw_hmac;
+pub mod identity;
+pub mod key_store;
 pub mod packet_manager;
 pub mod packetizer;
 pub mod packet_types;
+pub mod provisioning;
+pub mod transport;
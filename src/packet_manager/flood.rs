@@ -0,0 +1,330 @@
+//! Flood protection for received frames.
+//!
+//! Opening a frame costs a pass through the AES peripheral, so a device
+//! broadcasting junk could otherwise keep `tick` busy indefinitely. Each
+//! sender gets a token bucket that limits how many of its frames we try to
+//! open, and senders whose frames keep failing authentication are ignored
+//! for a while. Source addresses are trivially spoofed, so `PacketManager`
+//! also caps the number of frames it opens per tick.
+//!
+//! Because of that spoofing, a peer that has already authenticated is never
+//! blocked outright, or anyone could cut it off by sending junk under its
+//! address. It is only given a lower priority, so its frames are opened
+//! while the tick has budget to spare.
+//!
+//! Only so many senders are tracked. Spoofed addresses could otherwise push
+//! a blocked sender out of the table and wipe its record, so senders still
+//! being penalized are never forgotten to make room. If all of them are, new
+//! senders are rate limited until a penalty runs out, which can hold up a
+//! genuinely new peer for up to `BLOCK_DURATION`.
+
+use crate::{
+    hal::time::{Duration, Instant},
+    transport::MacAddress,
+};
+
+/// Most frames opened in a single `tick`. The rest wait for the next one.
+pub const MAX_FRAMES_PER_TICK: u32 = 16;
+/// Frames a sender can have opened back to back
+const SENDER_BURST: u64 = 32;
+/// Frames per second a sender can have opened once its burst is spent
+const SENDER_RATE: u64 = 64;
+/// Consecutive authentication failures before a sender is blocked
+const BLOCK_THRESHOLD: u8 = 8;
+const BLOCK_DURATION: Duration = Duration::secs(30);
+/// Senders tracked at once. The least recently seen one that isn't being
+/// penalized is forgotten first.
+const MAX_TRACKED_SENDERS: usize = 64;
+
+/// Whether a frame is worth opening
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Allowed,
+    /// A known peer's address has been sending frames that fail
+    /// authentication, so its frames come after everyone else's
+    LowPriority,
+    /// The sender has used up its budget for now
+    RateLimited,
+    /// The sender failed authentication too often and is being ignored
+    Blocked,
+}
+
+struct SenderBudget {
+    address: MacAddress,
+    /// Tokens are kept in thousandths so slow refills aren't rounded away
+    milli_tokens: u64,
+    last_refill: Instant,
+    last_seen: Instant,
+    failures: u8,
+    blocked_until: Option<Instant>,
+    low_priority_until: Option<Instant>,
+}
+impl SenderBudget {
+    fn new(address: MacAddress, now: Instant) -> Self {
+        return Self {
+            address,
+            milli_tokens: SENDER_BURST * 1000,
+            last_refill: now,
+            last_seen: now,
+            failures: 0,
+            blocked_until: None,
+            low_priority_until: None,
+        };
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = match now.checked_duration_since(self.last_refill) {
+            Some(elapsed) => elapsed.to_micros(),
+            None => 0,
+        };
+        // Microseconds times frames per second gives thousandths of a frame
+        let refill = elapsed.saturating_mul(SENDER_RATE) / 1000;
+        self.milli_tokens = (self.milli_tokens + refill).min(SENDER_BURST * 1000);
+        self.last_refill = now;
+    }
+
+    /// True while the sender is blocked or deprioritized
+    fn is_penalized(&self, now: Instant) -> bool {
+        return [self.blocked_until, self.low_priority_until]
+            .iter()
+            .flatten()
+            .any(|until| now < *until);
+    }
+}
+
+pub struct FloodGuard {
+    senders: heapless::Vec<SenderBudget, MAX_TRACKED_SENDERS>,
+}
+impl FloodGuard {
+    pub fn new() -> Self {
+        return Self {
+            senders: heapless::Vec::new(),
+        };
+    }
+
+    /// Decides whether to spend time on a frame from `sender`, charging its
+    /// budget if so
+    pub fn admit(&mut self, sender: &MacAddress, now: Instant) -> Admission {
+        let budget = match self.budget_mut(sender, now) {
+            Some(budget) => budget,
+            None => return Admission::RateLimited,
+        };
+        budget.last_seen = now;
+        if let Some(blocked_until) = budget.blocked_until {
+            if now < blocked_until {
+                return Admission::Blocked;
+            }
+            budget.blocked_until = None;
+            budget.failures = 0;
+        }
+
+        budget.refill(now);
+        if budget.milli_tokens < 1000 {
+            return Admission::RateLimited;
+        }
+        budget.milli_tokens -= 1000;
+        if let Some(low_priority_until) = budget.low_priority_until {
+            if now < low_priority_until {
+                return Admission::LowPriority;
+            }
+            budget.low_priority_until = None;
+            budget.failures = 0;
+        }
+        return Admission::Allowed;
+    }
+
+    /// Records a frame from `sender` that failed authentication.
+    ///
+    /// `known_peer` says whether `sender` has authenticated before. Returns
+    /// the penalty if this frame got the sender one.
+    pub fn record_failure(
+        &mut self,
+        sender: &MacAddress,
+        known_peer: bool,
+        now: Instant,
+    ) -> Option<Admission> {
+        let budget = self.budget_mut(sender, now)?;
+        budget.failures = budget.failures.saturating_add(1);
+        let penalized = budget.blocked_until.is_some() || budget.low_priority_until.is_some();
+        if budget.failures < BLOCK_THRESHOLD || penalized {
+            return None;
+        }
+        if known_peer {
+            budget.low_priority_until = Some(now + BLOCK_DURATION);
+            return Some(Admission::LowPriority);
+        }
+        budget.blocked_until = Some(now + BLOCK_DURATION);
+        return Some(Admission::Blocked);
+    }
+
+    /// Records an authentic frame from `sender`, forgiving earlier failures
+    pub fn record_success(&mut self, sender: &MacAddress) {
+        if let Some(budget) = self.senders.iter_mut().find(|i| i.address == *sender) {
+            budget.failures = 0;
+        }
+    }
+
+    /// Finds or starts tracking `sender`.
+    ///
+    /// Returns `None` if the table is full of senders that are being
+    /// penalized, so there's no room for a new one.
+    fn budget_mut(&mut self, sender: &MacAddress, now: Instant) -> Option<&mut SenderBudget> {
+        if let Some(i) = self.senders.iter().position(|i| i.address == *sender) {
+            return Some(&mut self.senders[i]);
+        }
+        if self.senders.is_full() {
+            let oldest = self
+                .senders
+                .iter()
+                .enumerate()
+                .filter(|(_, budget)| !budget.is_penalized(now))
+                .min_by_key(|(_, budget)| budget.last_seen)
+                .map(|(i, _)| i)?;
+            self.senders.swap_remove(oldest);
+        }
+        // There is always room after evicting
+        let _ = self.senders.push(SenderBudget::new(*sender, now));
+        return self.senders.last_mut();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SENDER: MacAddress = [0x02, 0, 0, 0, 0, 1];
+
+    fn at(millis: u64) -> Instant {
+        return Instant::from_ticks(millis * 1000);
+    }
+
+    fn fail(guard: &mut FloodGuard, known_peer: bool, now: Instant) -> Option<Admission> {
+        let mut penalty = None;
+        for _ in 0..BLOCK_THRESHOLD {
+            penalty = guard.record_failure(&SENDER, known_peer, now);
+        }
+        return penalty;
+    }
+
+    #[test]
+    fn tokens_refill_at_the_sender_rate() {
+        let mut guard = FloodGuard::new();
+        for _ in 0..SENDER_BURST {
+            assert_eq!(guard.admit(&SENDER, at(0)), Admission::Allowed);
+        }
+        assert_eq!(guard.admit(&SENDER, at(0)), Admission::RateLimited);
+
+        // One frame's worth of tokens takes 1/SENDER_RATE seconds
+        let frame_interval = 1000 / SENDER_RATE;
+        assert_eq!(
+            guard.admit(&SENDER, at(frame_interval - 1)),
+            Admission::RateLimited
+        );
+        assert_eq!(guard.admit(&SENDER, at(frame_interval + 1)), Admission::Allowed);
+
+        // The bucket never holds more than a burst
+        for _ in 0..SENDER_BURST {
+            assert_eq!(guard.admit(&SENDER, at(60_000)), Admission::Allowed);
+        }
+        assert_eq!(guard.admit(&SENDER, at(60_000)), Admission::RateLimited);
+    }
+
+    #[test]
+    fn unknown_senders_are_blocked_at_the_threshold() {
+        let mut guard = FloodGuard::new();
+        for _ in 0..BLOCK_THRESHOLD - 1 {
+            assert_eq!(guard.record_failure(&SENDER, false, at(0)), None);
+        }
+        assert_eq!(guard.admit(&SENDER, at(0)), Admission::Allowed);
+        assert_eq!(
+            guard.record_failure(&SENDER, false, at(0)),
+            Some(Admission::Blocked)
+        );
+        assert_eq!(guard.admit(&SENDER, at(0)), Admission::Blocked);
+        // Only reported once
+        assert_eq!(guard.record_failure(&SENDER, false, at(0)), None);
+    }
+
+    #[test]
+    fn successes_forgive_failures() {
+        let mut guard = FloodGuard::new();
+        for _ in 0..BLOCK_THRESHOLD - 1 {
+            guard.record_failure(&SENDER, false, at(0));
+        }
+        guard.record_success(&SENDER);
+        assert_eq!(guard.record_failure(&SENDER, false, at(0)), None);
+    }
+
+    #[test]
+    fn blocks_expire() {
+        let mut guard = FloodGuard::new();
+        assert_eq!(fail(&mut guard, false, at(0)), Some(Admission::Blocked));
+        let expiry = BLOCK_DURATION.to_millis();
+        assert_eq!(guard.admit(&SENDER, at(expiry - 1)), Admission::Blocked);
+        assert_eq!(guard.admit(&SENDER, at(expiry)), Admission::Allowed);
+        // The failure count starts over too
+        assert_eq!(guard.record_failure(&SENDER, false, at(expiry)), None);
+    }
+
+    #[test]
+    fn known_peers_are_only_deprioritized() {
+        let mut guard = FloodGuard::new();
+        assert_eq!(fail(&mut guard, true, at(0)), Some(Admission::LowPriority));
+        assert_eq!(guard.admit(&SENDER, at(0)), Admission::LowPriority);
+        // Still rate limited like everyone else
+        for _ in 1..SENDER_BURST {
+            guard.admit(&SENDER, at(0));
+        }
+        assert_eq!(guard.admit(&SENDER, at(0)), Admission::RateLimited);
+
+        let expiry = BLOCK_DURATION.to_millis();
+        assert_eq!(guard.admit(&SENDER, at(expiry - 1)), Admission::LowPriority);
+        assert_eq!(guard.admit(&SENDER, at(expiry)), Admission::Allowed);
+    }
+
+    #[test]
+    fn least_recently_seen_sender_is_evicted() {
+        let mut guard = FloodGuard::new();
+        assert_eq!(fail(&mut guard, false, at(0)), Some(Admission::Blocked));
+        for i in 1..MAX_TRACKED_SENDERS {
+            guard.admit(&[0x02, 0, 0, 0, 1, i as u8], at(i as u64));
+        }
+        // Seeing the blocked sender again keeps it tracked
+        assert_eq!(guard.admit(&SENDER, at(100)), Admission::Blocked);
+        guard.admit(&[0x02, 0, 0, 0, 2, 0], at(101));
+        assert_eq!(guard.admit(&SENDER, at(102)), Admission::Blocked);
+        assert_eq!(guard.senders.len(), MAX_TRACKED_SENDERS);
+
+        // Once its block is over and it is the oldest, a new sender pushes it out
+        let expiry = BLOCK_DURATION.to_millis();
+        for i in 0..MAX_TRACKED_SENDERS {
+            guard.admit(&[0x02, 0, 0, 0, 3, i as u8], at(expiry + i as u64));
+        }
+        assert!(guard.senders.iter().all(|budget| budget.address != SENDER));
+    }
+
+    #[test]
+    fn penalized_senders_are_not_evicted() {
+        let mut guard = FloodGuard::new();
+        assert_eq!(fail(&mut guard, false, at(0)), Some(Admission::Blocked));
+
+        // A flood of spoofed addresses pushes out everyone but the blocked sender
+        for i in 0..2 * MAX_TRACKED_SENDERS {
+            guard.admit(&[0x02, 0, 0, 0, 1, i as u8], at(1 + i as u64));
+        }
+        assert_eq!(guard.admit(&SENDER, at(1000)), Admission::Blocked);
+
+        // Once every tracked sender is penalized, new ones have to wait
+        for i in 1..MAX_TRACKED_SENDERS {
+            let address = [0x02, 0, 0, 0, 2, i as u8];
+            for _ in 0..BLOCK_THRESHOLD {
+                guard.record_failure(&address, false, at(2000));
+            }
+        }
+        let newcomer = [0x02, 0, 0, 0, 3, 0];
+        assert_eq!(guard.admit(&newcomer, at(2000)), Admission::RateLimited);
+        assert_eq!(guard.admit(&SENDER, at(2000)), Admission::Blocked);
+        let expiry = BLOCK_DURATION.to_millis();
+        assert_eq!(guard.admit(&newcomer, at(expiry)), Admission::Allowed);
+    }
+}
@@ -1,25 +1,62 @@
 extern crate alloc;
 
+mod flood;
+mod outbox;
+mod pairing;
+mod replay;
+
+pub use flood::MAX_FRAMES_PER_TICK;
+pub use outbox::{ClassLimits, TrafficClass, MAX_CHUNKS_SENT_PER_TICK};
+pub use pairing::{PairingClient, PairingEvent};
+pub use replay::{
+    ReplayWindow, TxCounter, REPLAY_WINDOW_SIZE, RX_PERSIST_INTERVAL, TX_COUNTER_RESERVATION,
+};
+
 use crate::{
     binary_packets::{PacketReader, PacketWriter},
-    hw_aes::{self, AES_KEY_SIZE},
-    hw_hmac::{self},
-    packet_types::{CommPacket, Heartbeat, Transmittable},
-    packetizer::{TolerantPacketAssembler, TolerantPacketDisassembler},
+    counter_store::CounterStore,
+    hkdf::HkdfError,
+    hw_aes::{self, DecryptionError, CCM_NONCE_SIZE, CCM_TAG_SIZE},
+    hw_hmac::TagLength,
+    key_store::{
+        self, ClusterKey, DeviceIdentity, KeyId, KeyStore, KeyStoreError, SessionKeys,
+        CLUSTER_KEY_SIZE, PUBLIC_KEY_SIZE,
+    },
+    packet_types::{Command, CommPacket, Heartbeat, RotateKey, SignedCommand, Transmittable},
+    packetizer::{
+        AssemblerLimits, DiscardCounters, TolerantPacketAssembler, TolerantPacketDisassembler,
+    },
+    transport::{frame_kind, MacAddress, Transport, MAX_FRAME_LEN},
 };
-use alloc::{string::String, vec::Vec};
-use esp_hal::{
+use crate::hal::{
     aes::Aes,
     rng::Rng,
     sha::Sha,
     time::{self, Duration, Instant},
 };
-use esp_println::println;
-use esp_wifi::esp_now::{EspNow, BROADCAST_ADDRESS, ESP_NOW_MAX_DATA_LEN};
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use thiserror::Error;
+use flood::{Admission, FloodGuard};
+use outbox::Outbox;
+use pairing::{PairingContext, PairingWindow};
 
-static CLUSTER_KEY: &'static [u8] = include_bytes!("../../keys/cluster_key.dat");
-const INNER_PACKET_MAX_LEN: usize = ESP_NOW_MAX_DATA_LEN - hw_hmac::HASH_SIZE;
+/// Cleartext frame header holding the frame kind, the ID of the key the frame
+/// was sealed with, and the sender's frame counter
+const FRAME_HEADER_LEN: usize = 1 + 1 + 8;
+/// First byte of every frame carrying cluster traffic
+const FRAME_KIND_CLUSTER: u8 = frame_kind::CLUSTER;
+/// Largest payload that fits in a frame, reached with the shortest tag. Its
+/// first byte is one of the sealed kinds from `frame_kind`.
+const INNER_PACKET_MAX_LEN: usize = MAX_FRAME_LEN - FRAME_HEADER_LEN - TagLength::Bytes8.bytes();
+const _: () = assert!(TagLength::Bytes16.bytes() <= CCM_TAG_SIZE, "Tags longer than AES-CCM's");
 const MAX_NODES: usize = 50;
+/// Name announced in our heartbeats, set with `CAR_NAME` at build time
+const CAR_NAME: Option<&str> = option_env!("CAR_NAME");
+/// Received datagrams waiting for `next_datagram`. The oldest is dropped first.
+const MAX_QUEUED_DATAGRAMS: usize = 64;
+/// Bytes of incomplete messages held across all peers. The oldest message
+/// cluster-wide is discarded to make room.
+const TOTAL_BUFFER_BUDGET: usize = 16 * 1024;
 
 pub enum Role {
     /// This is the main controller with access to the vehicle CANbus
@@ -28,143 +65,778 @@ pub enum Role {
     Node,
 }
 
+#[derive(Error, Debug)]
+pub enum PacketManagerError {
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+    #[error("Failed to derive session keys: {0}")]
+    KeyDerivation(#[from] HkdfError),
+    #[error("Message of {0} bytes is too large to send")]
+    MessageTooLarge(usize),
+    #[error("No room left in the {0:?} queue")]
+    QueueFull(TrafficClass),
+    #[error("Datagrams must start with a reliable, FEC or transfer frame kind")]
+    UnknownDatagramKind,
+}
+
+/// Counters for traffic dropped by the `PacketManager`.
+///
+/// A steady trickle of `bad_length` and `bad_mac` is normal in a noisy RF
+/// environment. A burst of `bad_mac` from well-formed frames, or of
+/// `unknown_sender`, is more likely someone probing the cluster.
+#[derive(Debug, Clone, Default)]
+pub struct PacketManagerStats {
+    /// Frames too short or too long to hold a header, tag and data
+    pub bad_length: u32,
+    /// Frames whose authentication tag didn't match
+    pub bad_mac: u32,
+    /// Authentic frames from a peer we had no room left to track
+    pub unknown_sender: u32,
+    /// Frames left unopened because their sender was over its rate limit,
+    /// blocked, or deprioritized while the tick was busy
+    pub rate_limited: u32,
+    /// Times a sender was blocked for repeatedly failing authentication
+    pub blocked_senders: u32,
+    /// Authentic frames that were rejected because they had already been seen
+    pub replay_rejected: u32,
+    /// Frames sealed under a key ID we don't hold
+    pub unknown_key: u32,
+    /// Commands dropped because their signature didn't match the pinned Commander key
+    pub unauthorized_commands: u32,
+    /// Times a peer was seen starting a new session, usually after a reboot
+    pub peer_restarts: u32,
+    /// Authentic datagrams dropped because `next_datagram` wasn't keeping up
+    pub datagrams_dropped: u32,
+    /// Incomplete messages given up on, by cause
+    pub partial_discards: DiscardCounters,
+}
+
 struct PeerPacketizer {
     last_heartbeat: Instant,
+    replay_window: ReplayWindow,
     packetizer: TolerantPacketAssembler,
 }
 impl PeerPacketizer {
-    pub fn new() -> Self {
+    pub fn new(replay_window: ReplayWindow, limits: AssemblerLimits) -> Self {
         return Self {
             last_heartbeat: time::now(),
-            packetizer: TolerantPacketAssembler::new(),
+            replay_window,
+            packetizer: TolerantPacketAssembler::with_limits(limits),
         };
     }
+
+    /// Hands an authenticated chunk to the reassembler.
+    ///
+    /// Returns true if the chunk shows the peer restarted. Its partial
+    /// messages from before the restart are dropped, but its replay window is
+    /// kept, since frame counters survive reboots.
+    fn push_chunk(&mut self, chunk: &[u8], now: Instant) -> bool {
+        self.last_heartbeat = now;
+        return self.packetizer.push_data(chunk, now);
+    }
+}
+
+fn frame_header(key_id: KeyId, counter: u64) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header[0] = FRAME_KIND_CLUSTER;
+    header[1] = key_id;
+    header[2..].copy_from_slice(&counter.to_be_bytes());
+    return header;
+}
+
+/// Room left for the sealed payload once the header and tag are in the frame
+const fn inner_packet_len(tag_length: TagLength) -> usize {
+    return MAX_FRAME_LEN - FRAME_HEADER_LEN - tag_length.bytes();
+}
+
+/// Room left for a stream chunk once its sealed kind is in the payload
+const fn stream_chunk_len(tag_length: TagLength) -> usize {
+    return inner_packet_len(tag_length) - 1;
+}
+
+/// A cluster key along with the working keys derived from it
+#[derive(Clone)]
+struct LoadedKey {
+    key: ClusterKey,
+    session: SessionKeys,
+}
+impl LoadedKey {
+    fn new(sha_peripheral: &mut Sha<'_>, key: ClusterKey) -> Result<Self, HkdfError> {
+        return Ok(Self {
+            session: key.session_keys(sha_peripheral)?,
+            key,
+        });
+    }
+}
+
+/// Builds the associated data that binds a frame to its sender and header
+fn frame_aad(sender_mac: &MacAddress, header: &[u8]) -> [u8; 6 + FRAME_HEADER_LEN] {
+    let mut aad = [0u8; 6 + FRAME_HEADER_LEN];
+    aad[0..6].copy_from_slice(sender_mac);
+    aad[6..].copy_from_slice(&header[0..FRAME_HEADER_LEN]);
+    return aad;
+}
+
+/// Builds the CCM nonce for a frame from its sender and frame counter.
+///
+/// A sender's frame counter never repeats, even across keys and reboots, so
+/// neither does the nonce. Only the low 56 bits of the counter fit, which no
+/// sender will ever run past.
+fn frame_nonce(sender_mac: &MacAddress, counter: u64) -> [u8; CCM_NONCE_SIZE] {
+    let mut nonce = [0u8; CCM_NONCE_SIZE];
+    nonce[0..6].copy_from_slice(sender_mac);
+    nonce[6..].copy_from_slice(&counter.to_be_bytes()[1..]);
+    return nonce;
+}
+
+/// A key that is still accepted after a rotation, until `expires`
+struct RetiredKey {
+    key: LoadedKey,
+    expires: Instant,
+    /// Encoded `RotateKey` announcement, repeated under the retired key so
+    /// nodes that missed it can still catch up during the grace period
+    announcement: Option<Vec<u8>>,
 }
 
-pub struct PacketManager<'a> {
-    esp_now: EspNow<'a>,
+pub struct PacketManager<T: Transport, K: KeyStore, C: CounterStore> {
+    transport: T,
+    key_store: K,
+    identity: DeviceIdentity,
+    /// Sequence number of the last signed command we acted on
+    command_sequence: u64,
+    current_key: LoadedKey,
+    previous_key: Option<RetiredKey>,
+    counter_store: C,
+    tx_counter: TxCounter,
+    tag_length: TagLength,
     next_heartbeat: Instant,
     packet_disassembler: TolerantPacketDisassembler<INNER_PACKET_MAX_LEN>,
-    packetizers: heapless::Vec<([u8; 6], PeerPacketizer), MAX_NODES>,
+    packetizers: heapless::Vec<(MacAddress, PeerPacketizer), MAX_NODES>,
+    assembler_limits: AssemblerLimits,
+    outbox: Outbox,
+    /// Authenticated reliable, FEC and transfer frames, with their senders
+    datagrams: VecDeque<(MacAddress, Vec<u8>)>,
+    /// Sealed frame the transport was too busy to take, sent before anything else
+    unsent: Option<Vec<u8>>,
+    flood_guard: FloodGuard,
+    pairing: Option<PairingWindow>,
+    stats: PacketManagerStats,
 }
-impl<'a> PacketManager<'a> {
-    pub fn new(esp_now: EspNow<'a>) -> Self {
-        return PacketManager {
-            esp_now,
+impl<T: Transport, K: KeyStore, C: CounterStore> PacketManager<T, K, C> {
+    /// Creates a manager using the cluster key installed in `key_store`.
+    ///
+    /// A device identity is created on first use.
+    pub fn new(
+        transport: T,
+        mut key_store: K,
+        mut counter_store: C,
+        sha_peripheral: &mut Sha<'_>,
+        rng_peripheral: &mut Rng,
+    ) -> Result<Self, PacketManagerError> {
+        let current_key = key_store
+            .load_cluster_key()?
+            .ok_or(KeyStoreError::NotProvisioned)?;
+        let current_key = LoadedKey::new(sha_peripheral, current_key)?;
+        let identity = key_store::load_or_create_identity(&mut key_store, rng_peripheral)?;
+        return Ok(PacketManager {
+            transport,
+            key_store,
+            identity,
+            command_sequence: counter_store.load_command_sequence().unwrap_or(0),
+            current_key,
+            previous_key: None,
+            tx_counter: TxCounter::restore(&mut counter_store),
+            counter_store,
+            tag_length: TagLength::default(),
             next_heartbeat: time::now(),
-            packet_disassembler: TolerantPacketDisassembler::new(),
+            packet_disassembler: {
+                // A fresh epoch tells peers we restarted, so they don't wait
+                // for our message sequence to catch up with the old one
+                let mut packet_disassembler =
+                    TolerantPacketDisassembler::new(rng_peripheral.random());
+                packet_disassembler.set_chunk_size(stream_chunk_len(TagLength::default()));
+                packet_disassembler
+            },
             packetizers: heapless::Vec::new(),
-        };
+            assembler_limits: AssemblerLimits::default(),
+            outbox: Outbox::new(time::now()),
+            datagrams: VecDeque::new(),
+            unsent: None,
+            flood_guard: FloodGuard::new(),
+            pairing: None,
+            stats: PacketManagerStats::default(),
+        });
     }
 
-    /// Sends a broadcast via esp-now.
+    /// The key store the cluster key is loaded from and rotated into
+    pub fn key_store_mut(&mut self) -> &mut K {
+        return &mut self.key_store;
+    }
+
+    /// Switches to a newly provisioned cluster key.
     ///
-    /// NOTE: Packet may have garbage data appended to the end due to
-    /// encryption semantics. If this is not okay, a length indicator
-    /// should be added to the packet
+    /// Unlike a rotation, the old key stops being accepted immediately.
+    pub fn set_cluster_key(
+        &mut self,
+        sha_peripheral: &mut Sha<'_>,
+        cluster_key: &ClusterKey,
+    ) -> Result<(), HkdfError> {
+        self.current_key = LoadedKey::new(sha_peripheral, cluster_key.clone())?;
+        self.previous_key = None;
+        return Ok(());
+    }
+
+    /// Sets the length of the tag sealed into every cluster frame.
+    ///
+    /// Every device in the cluster must use the same length, since frames
+    /// with any other tag length fail to open. Shorter tags leave more room
+    /// for payload in each frame.
+    pub fn set_tag_length(&mut self, tag_length: TagLength) {
+        self.tag_length = tag_length;
+        self.packet_disassembler
+            .set_chunk_size(stream_chunk_len(tag_length));
+    }
+
+    /// Sets how much each peer can have buffered in incomplete messages, and
+    /// for how long.
     ///
-    /// Packets are:
-    /// 1. Encrypted
-    /// 2. Chunked
-    /// 3. HMAC'd
-    /// 4. Sent
-    fn broadcast_packet(
+    /// Applies to known peers as well as new ones. Memory held across all
+    /// peers is also capped, at `TOTAL_BUFFER_BUDGET` bytes. Messages longer
+    /// than `max_message_size` are refused when queued, too.
+    pub fn set_assembler_limits(&mut self, limits: AssemblerLimits) {
+        self.assembler_limits = limits;
+        for (_, sender_ctx) in self.packetizers.iter_mut() {
+            sender_ctx.packetizer.set_limits(limits);
+        }
+    }
+
+    /// This device's signing public key, for pinning on nodes
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        return self.identity.public_key();
+    }
+
+    /// Trusts commands signed by `commander_key` from now on
+    pub fn set_commander_key(&mut self, commander_key: &[u8; PUBLIC_KEY_SIZE]) {
+        self.identity.commander_key = Some(*commander_key);
+    }
+
+    /// ID of the key outgoing frames are sealed with
+    pub fn current_key_id(&self) -> KeyId {
+        return self.current_key.key.id;
+    }
+
+    /// Replaces the cluster key with a freshly generated one.
+    ///
+    /// The new key is announced to the cluster under the current key, which
+    /// keeps being accepted for `grace` so nodes have time to switch over.
+    /// Nodes that miss every announcement during the grace period have to be
+    /// paired again.
+    pub fn rotate_key(
         &mut self,
-        aes_peripheral: &mut Aes<'_>,
         sha_peripheral: &mut Sha<'_>,
         rng_peripheral: &mut Rng,
-        mut packet: Vec<u8>,
-    ) {
-        // Encrypt packet in-place
-        hw_aes::encrypt_packet(
-            aes_peripheral,
-            rng_peripheral,
-            &CLUSTER_KEY[0..AES_KEY_SIZE].try_into().unwrap(),
-            &mut packet,
-        );
-
-        // Split packet into chunks for transport
-        let mut chunk_iter = self.packet_disassembler.split_packet(&packet);
-        let mut chunk = [0u8; ESP_NOW_MAX_DATA_LEN];
-        while let Some(bytes_written) =
-            chunk_iter.get_chunk((&mut chunk[hw_hmac::HASH_SIZE..]).try_into().unwrap())
-        {
-            // Add HMAC to each packet to prove authenticity
-            hw_hmac::hmac_cluster_chunk(
-                sha_peripheral,
-                &chunk[hw_hmac::HASH_SIZE..hw_hmac::HASH_SIZE + bytes_written],
-            )
-            .into_iter()
-            .enumerate()
-            .for_each(|(i, byte)| chunk[i] = byte);
+        grace: Duration,
+    ) -> Result<(), PacketManagerError> {
+        let mut new_key = ClusterKey {
+            id: self.current_key.key.id.wrapping_add(1),
+            secret: [0u8; CLUSTER_KEY_SIZE],
+        };
+        rng_peripheral.read(&mut new_key.secret);
+        let loaded_key = LoadedKey::new(sha_peripheral, new_key.clone())?;
+        self.key_store.store_cluster_key(&new_key)?;
+
+        let packet_bytes = self.signed_command_packet(&Command::RotateKey(RotateKey {
+            new_key: new_key.clone(),
+            grace_secs: grace.to_secs() as u32,
+        }));
+
+        let old_key = core::mem::replace(&mut self.current_key, loaded_key);
+        // The announcement is repeated with every heartbeat, so a full queue only delays it
+        if let Err(err) = self.queue_packet(TrafficClass::Control, old_key.key.id, &packet_bytes) {
+            log::warn!("Failed to queue key announcement: {err}");
+        }
+        self.previous_key = Some(RetiredKey {
+            key: old_key,
+            expires: time::now() + grace,
+            announcement: Some(packet_bytes),
+        });
+        return Ok(());
+    }
+
+    /// Signs `command` with this device's identity and queues it for broadcast
+    /// as `TrafficClass::Control`.
+    ///
+    /// Only nodes that pinned this device as their Commander will act on it.
+    pub fn send_command(&mut self, command: &Command) -> Result<(), PacketManagerError> {
+        let packet_bytes = self.signed_command_packet(command);
+        return self.queue_packet(TrafficClass::Control, self.current_key.key.id, &packet_bytes);
+    }
+
+    /// Queues `packet` for broadcast. It goes out over the following ticks,
+    /// ahead of anything queued in a less urgent class.
+    pub fn broadcast(
+        &mut self,
+        class: TrafficClass,
+        packet: &CommPacket,
+    ) -> Result<(), PacketManagerError> {
+        let mut packet_bytes = PacketWriter::new();
+        packet.encode(&mut packet_bytes).unwrap();
+        return self.queue_packet(class, self.current_key.key.id, &packet_bytes.finish());
+    }
+
+    /// Queues a reliable, FEC or transfer frame to be sealed and broadcast.
+    ///
+    /// Those protocols have no authentication of their own, so their frames
+    /// only ever travel this way. They're checked against the same replay
+    /// window as everything else and come out of `next_datagram` on every
+    /// peer. `datagram` must start with its kind and be no longer than
+    /// `max_datagram_len`.
+    pub fn send_datagram(
+        &mut self,
+        class: TrafficClass,
+        datagram: &[u8],
+    ) -> Result<(), PacketManagerError> {
+        match datagram.first() {
+            Some(kind) if frame_kind::is_datagram(*kind) => {}
+            _ => return Err(PacketManagerError::UnknownDatagramKind),
+        }
+        if datagram.len() > self.max_datagram_len() {
+            return Err(PacketManagerError::MessageTooLarge(datagram.len()));
+        }
+        let chunks = VecDeque::from([Vec::from(datagram)]);
+        if !self.outbox.push(class, self.current_key.key.id, chunks) {
+            return Err(PacketManagerError::QueueFull(class));
+        }
+        return Ok(());
+    }
+
+    /// Longest datagram that fits in a frame with the current tag length
+    pub fn max_datagram_len(&self) -> usize {
+        return inner_packet_len(self.tag_length);
+    }
+
+    /// Takes the next authenticated datagram received, along with its sender
+    pub fn next_datagram(&mut self) -> Option<(MacAddress, Vec<u8>)> {
+        return self.datagrams.pop_front();
+    }
+
+    /// Sets how fast `class` can send and how much it can have queued
+    pub fn set_class_limits(&mut self, class: TrafficClass, limits: ClassLimits) {
+        self.outbox.set_limits(class, limits);
+    }
+
+    /// Bytes waiting to be sent in `class`
+    pub fn queued_bytes(&self, class: TrafficClass) -> usize {
+        return self.outbox.queued_bytes(class);
+    }
+
+    fn signed_command_packet(&mut self, command: &Command) -> Vec<u8> {
+        // Frame counters never repeat, even across reboots, so they double as
+        // command sequence numbers
+        let sequence = self.tx_counter.next(&mut self.counter_store);
+        let packet = CommPacket::Command(SignedCommand::sign(&self.identity, sequence, command).unwrap());
+        let mut packet_bytes = PacketWriter::new();
+        packet.encode(&mut packet_bytes).unwrap();
+        return packet_bytes.finish();
+    }
+
+    /// Verifies a signed command and acts on it
+    fn handle_command(&mut self, sha_peripheral: &mut Sha<'_>, signed_command: SignedCommand) {
+        let commander_key = match self.identity.commander_key {
+            Some(ref commander_key) => commander_key,
+            None => {
+                self.stats.unauthorized_commands += 1;
+                return;
+            }
+        };
+        let command = match signed_command.verify(commander_key) {
+            Some(command) => command,
+            None => {
+                self.stats.unauthorized_commands += 1;
+                return;
+            }
+        };
+        // Repeated announcements land here too, so this isn't counted
+        if signed_command.sequence <= self.command_sequence {
+            return;
+        }
+        self.command_sequence = signed_command.sequence;
+        self.counter_store
+            .store_command_sequence(signed_command.sequence);
 
-            // Send packet on broadcast channel
-            let to_send = &chunk[0..hw_hmac::HASH_SIZE + bytes_written];
-            self.esp_now
-                .send(&BROADCAST_ADDRESS, to_send)
-                .unwrap()
-                .wait()
-                .ok();
+        match command {
+            Command::RotateKey(rotate_key) => self.apply_rotation(sha_peripheral, rotate_key),
+        }
+    }
+
+    /// Applies a rotation announced by the Commander
+    fn apply_rotation(&mut self, sha_peripheral: &mut Sha<'_>, rotate_key: RotateKey) {
+        if rotate_key.new_key.id == self.current_key.key.id {
+            // Already switched; this is a repeated announcement
+            return;
+        }
+        let loaded_key = match LoadedKey::new(sha_peripheral, rotate_key.new_key.clone()) {
+            Ok(loaded_key) => loaded_key,
+            Err(err) => {
+                log::error!("Failed to derive rotated session keys: {err}");
+                return;
+            }
+        };
+        if let Err(err) = self.key_store.store_cluster_key(&rotate_key.new_key) {
+            log::error!("Failed to persist rotated key: {err}");
+            return;
+        }
+        let old_key = core::mem::replace(&mut self.current_key, loaded_key);
+        self.previous_key = Some(RetiredKey {
+            key: old_key,
+            expires: time::now() + Duration::secs(rotate_key.grace_secs as u64),
+            announcement: None,
+        });
+    }
+
+    /// Finds the key a received frame claims to be sealed with
+    fn key_for_id(&self, key_id: KeyId) -> Option<&LoadedKey> {
+        if key_id == self.current_key.key.id {
+            return Some(&self.current_key);
+        }
+        return match self.previous_key {
+            Some(ref retired) if retired.key.key.id == key_id => Some(&retired.key),
+            _ => None,
+        };
+    }
+
+    pub fn stats(&self) -> &PacketManagerStats {
+        return &self.stats;
+    }
+
+    /// Lets unprovisioned nodes request the cluster key for `duration`.
+    ///
+    /// Only takes effect when ticking as `Role::Commander`.
+    pub fn start_pairing(&mut self, duration: Duration) {
+        self.pairing = Some(PairingWindow::new(duration));
+    }
+
+    pub fn cancel_pairing(&mut self) {
+        self.pairing = None;
+    }
+
+    /// The node currently asking to pair and the confirmation code it should show
+    pub fn pending_pairing(&self) -> Option<(MacAddress, u32)> {
+        return self.pairing.as_ref()?.pending();
+    }
+
+    /// Sends the cluster key to the pending node.
+    ///
+    /// Call this only once the user has checked that the node shows the same
+    /// code as `pending_pairing`. Returns false if no node is waiting.
+    pub fn confirm_pairing(&mut self, aes_peripheral: &mut Aes<'_>) -> bool {
+        return match self.pairing {
+            Some(ref mut pairing) => pairing.confirm(
+                &mut self.transport,
+                aes_peripheral,
+                &self.current_key.key,
+                &self.identity.public_key(),
+            ),
+            None => false,
+        };
+    }
+
+    /// Discards the oldest incomplete messages across all peers until the
+    /// total they hold fits within `TOTAL_BUFFER_BUDGET`
+    fn enforce_buffer_budget(&mut self) {
+        loop {
+            let buffered: usize = self
+                .packetizers
+                .iter()
+                .map(|(_, sender_ctx)| sender_ctx.packetizer.buffered_bytes())
+                .sum();
+            if buffered <= TOTAL_BUFFER_BUDGET {
+                return;
+            }
+            let oldest = self
+                .packetizers
+                .iter_mut()
+                .filter_map(|(_, sender_ctx)| {
+                    let started = sender_ctx.packetizer.oldest_partial()?;
+                    Some((started, sender_ctx))
+                })
+                .min_by_key(|(started, _)| *started);
+            match oldest {
+                Some((_, sender_ctx)) => sender_ctx.packetizer.evict_oldest(),
+                None => return,
+            };
+        }
+    }
+
+    /// Expires stale incomplete messages and tallies everything discarded
+    fn sweep_partial_messages(&mut self, now: Instant) {
+        for (_, sender_ctx) in self.packetizers.iter_mut() {
+            sender_ctx.packetizer.expire(now);
+            self.stats.partial_discards += sender_ctx.packetizer.take_discarded();
+        }
+    }
+
+    /// Checks a received frame against the flood limits before any crypto is
+    /// spent on it, charging `frame_budget` if it gets through
+    fn admit(&mut self, sender_mac: &MacAddress, frame_budget: &mut u32) -> bool {
+        match self.flood_guard.admit(sender_mac, time::now()) {
+            Admission::Allowed => {
+                *frame_budget -= 1;
+                return true;
+            }
+            // Keep half the tick for everyone else
+            Admission::LowPriority if *frame_budget > MAX_FRAMES_PER_TICK / 2 => {
+                *frame_budget -= 1;
+                return true;
+            }
+            Admission::LowPriority | Admission::RateLimited | Admission::Blocked => {
+                self.stats.rate_limited += 1;
+                return false;
+            }
+        }
+    }
+
+    /// Chunks a packet onto the stream for `class` and queues the chunks, to
+    /// be sealed under `key_id` when they're sent.
+    ///
+    /// Packets longer than peers would reassemble are refused up front rather
+    /// than sent only to be discarded. Peers are assumed to share our
+    /// `AssemblerLimits`.
+    fn queue_packet(
+        &mut self,
+        class: TrafficClass,
+        key_id: KeyId,
+        packet: &[u8],
+    ) -> Result<(), PacketManagerError> {
+        if packet.len() > self.assembler_limits.max_message_size {
+            return Err(PacketManagerError::MessageTooLarge(packet.len()));
+        }
+        let mut chunk_iter = self
+            .packet_disassembler
+            .split_stream_packet(class.stream(), packet);
+        let mut chunk = [0u8; INNER_PACKET_MAX_LEN];
+        let mut chunks = VecDeque::new();
+        while let Some(bytes_written) = chunk_iter.get_chunk(&mut chunk) {
+            let mut payload = Vec::with_capacity(1 + bytes_written);
+            payload.push(frame_kind::STREAM_CHUNK);
+            payload.extend_from_slice(&chunk[0..bytes_written]);
+            chunks.push_back(payload);
+        }
+        if chunks.is_empty() {
+            return Err(PacketManagerError::MessageTooLarge(packet.len()));
+        }
+        if !self.outbox.push(class, key_id, chunks) {
+            return Err(PacketManagerError::QueueFull(class));
+        }
+        return Ok(());
+    }
+
+    /// Sends queued chunks via the transport, most urgent first.
+    ///
+    /// Chunks are:
+    /// 1. Given a frame counter
+    /// 2. Encrypted and authenticated with AES-CCM under the session key for
+    ///    the key ID they were queued with, with a tag of the configured length
+    /// 3. Sent
+    ///
+    /// At most `MAX_CHUNKS_SENT_PER_TICK` go out per call. Chunks queued under
+    /// a key that has since been retired are dropped.
+    fn send_queued(&mut self, aes_peripheral: &mut Aes<'_>, now: Instant) {
+        if let Some(frame) = self.unsent.take() {
+            if !self.send_frame(frame) {
+                return;
+            }
+        }
+        let sender_mac = self.transport.address();
+        for _ in 0..MAX_CHUNKS_SENT_PER_TICK {
+            let (key_id, chunk) = match self.outbox.pop_chunk(now) {
+                Some(queued) => queued,
+                None => return,
+            };
+            let aes_key = match self.key_for_id(key_id) {
+                Some(cluster_key) => cluster_key.session.encryption,
+                None => continue,
+            };
+            let counter = self.tx_counter.next(&mut self.counter_store);
+            let header = frame_header(key_id, counter);
+
+            // Seal each chunk individually so it can be verified on its own
+            let mut frame = chunk;
+            hw_aes::seal_packet(
+                aes_peripheral,
+                &aes_key,
+                &frame_nonce(&sender_mac, counter),
+                &frame_aad(&sender_mac, &header),
+                self.tag_length.bytes(),
+                &mut frame,
+            );
+            frame.splice(0..0, header);
+
+            if !self.send_frame(frame) {
+                return;
+            }
+        }
+    }
+
+    /// Broadcasts a sealed frame without waiting for it to go out.
+    ///
+    /// Returns false if the transport is busy, in which case the frame is
+    /// kept and sent on a later tick.
+    fn send_frame(&mut self, frame: Vec<u8>) -> bool {
+        match self.transport.broadcast(&frame) {
+            Ok(()) => return true,
+            Err(err) if T::is_busy(&err) => {
+                self.unsent = Some(frame);
+                return false;
+            }
+            Err(err) => {
+                log::error!("Failed to send chunk: {err:?}");
+                return true;
+            }
         }
     }
 
     /// Adds a chunk to the sender's context for processing and returns a packet if one
-    /// was completed. Performs HMAC verification, assembly, and decryption.
-    fn unwrap_packet<T: Transmittable>(
+    /// was completed. Performs verification, decryption, and assembly.
+    fn unwrap_packet<P: Transmittable>(
         &mut self,
         aes_peripheral: &mut Aes<'_>,
-        sha_peripheral: &mut Sha<'_>,
-        sender_mac: &[u8; 6],
+        sender_mac: &MacAddress,
         packet: &[u8],
-    ) -> Option<T> {
-        // Check that the packet can accomodate an HMAC.
+    ) -> Option<P> {
+        // Check that the packet can accomodate a header and tag.
         // If not, it's not one of ours.
-        if packet.len() < hw_hmac::HASH_SIZE + 1 {
+        if packet.len() < FRAME_HEADER_LEN + self.tag_length.bytes() + 1
+            || packet.len() > MAX_FRAME_LEN
+        {
+            self.stats.bad_length += 1;
             return None;
         }
+        let key_id = packet[1];
+        let counter = u64::from_be_bytes(packet[2..FRAME_HEADER_LEN].try_into().unwrap());
 
-        // Verify HMAC
-        let computed_hmac =
-            hw_hmac::hmac_cluster_chunk(sha_peripheral, &packet[hw_hmac::HASH_SIZE..]);
-        for i in 0..hw_hmac::HASH_SIZE {
-            if packet[i] != computed_hmac[i] {
-                // HMAC doesn't match, so it's not one of ours.
+        // Frames under a key we've never had or already retired can't be opened
+        let aes_key = match self.key_for_id(key_id) {
+            Some(cluster_key) => cluster_key.session.encryption,
+            None => {
+                self.stats.unknown_key += 1;
+                return None;
+            }
+        };
+
+        // Drop replays from known peers before spending time on crypto
+        if let Some((_, sender_ctx)) = self.packetizers.iter().find(|i| i.0 == *sender_mac) {
+            if !sender_ctx.replay_window.check(counter) {
+                self.stats.replay_rejected += 1;
                 return None;
             }
         }
 
+        // Verify and decrypt. If the tag doesn't match, it's not one of ours.
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let frame = &mut frame[0..packet.len() - FRAME_HEADER_LEN];
+        frame.copy_from_slice(&packet[FRAME_HEADER_LEN..]);
+        let chunk = match hw_aes::open_packet(
+            aes_peripheral,
+            &aes_key,
+            &frame_nonce(sender_mac, counter),
+            &frame_aad(sender_mac, packet),
+            self.tag_length.bytes(),
+            frame,
+        ) {
+            Ok(chunk) => chunk,
+            Err(DecryptionError::AuthenticationFailed) => {
+                self.stats.bad_mac += 1;
+                // Peers we have a replay window for have authenticated before,
+                // so the failures may come from someone spoofing their address
+                let known_peer = self.packetizers.iter().any(|i| i.0 == *sender_mac);
+                match self
+                    .flood_guard
+                    .record_failure(sender_mac, known_peer, time::now())
+                {
+                    Some(Admission::Blocked) => {
+                        log::warn!(
+                            "Blocking {sender_mac:02x?} after repeated authentication failures"
+                        );
+                        self.stats.blocked_senders += 1;
+                    }
+                    Some(_) => {
+                        log::warn!(
+                            "Deprioritizing {sender_mac:02x?} after repeated authentication failures"
+                        );
+                    }
+                    None => {}
+                }
+                return None;
+            }
+            Err(_) => {
+                self.stats.bad_length += 1;
+                return None;
+            }
+        };
+        self.flood_guard.record_success(sender_mac);
+
         // Get sender's context
         let sender_ctx = match self.packetizers.iter_mut().find(|i| i.0 == *sender_mac) {
             Some(sender_ctx) => &mut sender_ctx.1,
             None => {
-                if let Err(_) = self
-                    .packetizers
-                    .push((sender_mac.clone(), PeerPacketizer::new()))
-                {
+                let replay_window = ReplayWindow::restore(sender_mac, &mut self.counter_store);
+                let peer = PeerPacketizer::new(replay_window, self.assembler_limits);
+                if let Err(_) = self.packetizers.push((sender_mac.clone(), peer)) {
                     log::error!("More than {MAX_NODES} found. Dropping packets.");
+                    self.stats.unknown_sender += 1;
                     return None;
                 }
                 &mut self.packetizers.last_mut().unwrap().1
             }
         };
 
-        sender_ctx.last_heartbeat = time::now();
+        // New peers are only checked against their persisted high-water mark here
+        if !sender_ctx.replay_window.check(counter) {
+            self.stats.replay_rejected += 1;
+            return None;
+        }
         sender_ctx
-            .packetizer
-            .push_data(&packet[hw_hmac::HASH_SIZE..]);
-
-        return match sender_ctx.packetizer.next() {
-            Some(mut packet) => {
-                // Decrypt packet in-place
-                if let Ok(packet) = hw_aes::decrypt_packet(
-                    aes_peripheral,
-                    &CLUSTER_KEY[0..AES_KEY_SIZE].try_into().unwrap(),
-                    &mut packet,
-                ) {
-                    T::decode(&mut PacketReader::new(packet))
-                } else {
-                    None
+            .replay_window
+            .accept(sender_mac, counter, &mut self.counter_store);
+
+        let (kind, chunk) = match chunk.split_first() {
+            Some(split) => split,
+            None => {
+                self.stats.bad_length += 1;
+                return None;
+            }
+        };
+        if *kind != frame_kind::STREAM_CHUNK {
+            sender_ctx.last_heartbeat = time::now();
+            if frame_kind::is_datagram(*kind) {
+                if self.datagrams.len() >= MAX_QUEUED_DATAGRAMS {
+                    self.datagrams.pop_front();
+                    self.stats.datagrams_dropped += 1;
                 }
+                let mut datagram = Vec::with_capacity(1 + chunk.len());
+                datagram.push(*kind);
+                datagram.extend_from_slice(chunk);
+                self.datagrams.push_back((*sender_mac, datagram));
+            }
+            return None;
+        }
+        if sender_ctx.push_chunk(chunk, time::now()) {
+            log::info!("{sender_mac:02x?} restarted");
+            self.stats.peer_restarts += 1;
+        }
+        let packet = sender_ctx.packetizer.next();
+        self.enforce_buffer_budget();
+
+        return match packet {
+            Some(packet) => {
+                // Reject packets with trailing bytes we don't understand
+                let mut packet_reader = PacketReader::new(&packet);
+                let decoded = P::decode(&mut packet_reader)?;
+                if packet_reader.remaining() != 0 {
+                    return None;
+                }
+                Some(decoded)
             }
             None => None,
         };
@@ -179,40 +851,91 @@ impl<'a> PacketManager<'a> {
     ) {
         let tick_now = time::now();
 
+        // Close the pairing window once it runs out
+        if let Some(ref pairing) = self.pairing {
+            if pairing.is_expired(tick_now) {
+                self.pairing = None;
+            }
+        }
+
+        // Stop accepting the old key once the grace period is over
+        if let Some(ref retired) = self.previous_key {
+            if tick_now >= retired.expires {
+                self.previous_key = None;
+            }
+        }
+
         // Send heartbeat if necessary
         if tick_now >= self.next_heartbeat {
             self.next_heartbeat = tick_now + Duration::secs(2);
             let packet = CommPacket::Heartbeat(Heartbeat {
-                car_name: Some(unsafe {
-                    String::from_utf8_unchecked(Vec::from(include_bytes!(
-                        "../../keys/car_name.txt"
-                    )))
-                }),
+                car_name: CAR_NAME.map(String::from),
             });
-            let mut packet_bytes = PacketWriter::new();
-            packet.encode(&mut packet_bytes).unwrap();
-            self.broadcast_packet(
-                aes_peripheral,
-                sha_peripheral,
-                rng_peripheral,
-                packet_bytes.finish(),
-            );
+            if let Err(err) = self.broadcast(TrafficClass::Control, &packet) {
+                log::error!("Failed to queue heartbeat: {err}");
+            }
+
+            // Keep repeating a rotation for nodes still on the old key
+            if let Some(RetiredKey {
+                key: ref old_key,
+                announcement: Some(ref announcement),
+                ..
+            }) = self.previous_key
+            {
+                let (key_id, announcement) = (old_key.key.id, announcement.clone());
+                if let Err(err) = self.queue_packet(TrafficClass::Control, key_id, &announcement) {
+                    log::error!("Failed to queue key announcement: {err}");
+                }
+            }
         }
+        self.send_queued(aes_peripheral, tick_now);
+
+        // Give up on messages whose senders went quiet partway through
+        self.sweep_partial_messages(tick_now);
 
-        // // Receive buffered packets
-        while let Some(data) = self.esp_now.receive() {
-            let chunk = &data.data[0..data.len as usize];
+        // Receive buffered packets. Anything past the per-tick budget stays
+        // queued so heartbeats and application work still get a turn.
+        let mut frame_budget = MAX_FRAMES_PER_TICK;
+        while frame_budget > 0 {
+            let frame = match self.transport.receive() {
+                Some(frame) => frame,
+                None => break,
+            };
+            match frame.data.first() {
+                Some(&FRAME_KIND_CLUSTER) => {}
+                Some(&frame_kind::PAIRING) => {
+                    // Pairing frames cost a key exchange, so they count too
+                    let pairing_open = matches!(role_hint, Role::Commander) && self.pairing.is_some();
+                    if !pairing_open || !self.admit(&frame.src_address, &mut frame_budget) {
+                        continue;
+                    }
+                    if let Some(pairing) = &mut self.pairing {
+                        let context = PairingContext {
+                            transport: &mut self.transport,
+                            sha_peripheral,
+                            rng_peripheral,
+                        };
+                        pairing.handle_frame(context, &frame);
+                    }
+                    continue;
+                }
+                _ => continue,
+            }
+            if !self.admit(&frame.src_address, &mut frame_budget) {
+                continue;
+            }
 
-            let started = time::now();
             if let Some(packet) = self.unwrap_packet::<CommPacket>(
                 aes_peripheral,
-                sha_peripheral,
-                &data.info.src_address,
-                chunk,
+                &frame.src_address,
+                &frame.data,
             ) {
-                let elapsed = time::now() - started;
-                println!("Decryption took {}", elapsed);
-                println!("Got packet: {:?}", packet);
+                match packet {
+                    CommPacket::Command(signed_command) => {
+                        self.handle_command(sha_peripheral, signed_command)
+                    }
+                    CommPacket::Heartbeat(_) => {}
+                }
             }
         }
     }
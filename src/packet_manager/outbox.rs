@@ -0,0 +1,302 @@
+//! Outbound message queue.
+//!
+//! Messages are chunked as soon as they're queued, and `tick` sends the
+//! chunks a few at a time. Each chunk comes from the most urgent class that
+//! has something waiting and hasn't used up its rate limit, so a heartbeat
+//! or command only ever waits behind a single chunk of a bulk transfer.
+//! Every class is sent on its own stream, which lets receivers reassemble
+//! the interleaved messages independently.
+
+extern crate alloc;
+
+use crate::{
+    hal::time::Instant,
+    key_store::KeyId,
+    packetizer::{StreamId, MAX_STREAMS},
+};
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// Most chunks sent in a single `tick`. The rest wait for the next one.
+pub const MAX_CHUNKS_SENT_PER_TICK: u32 = 16;
+
+/// How urgent a message is, from most to least
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficClass {
+    /// Heartbeats, commands and key management
+    Control,
+    /// Messages a user is waiting on, such as button presses
+    Interactive,
+    /// Periodic readings that are superseded by the next one
+    Telemetry,
+    /// Large transfers such as logs, configuration or firmware
+    Bulk,
+}
+impl TrafficClass {
+    /// Every class, most urgent first
+    pub const ALL: [TrafficClass; 4] = [
+        TrafficClass::Control,
+        TrafficClass::Interactive,
+        TrafficClass::Telemetry,
+        TrafficClass::Bulk,
+    ];
+
+    /// The stream messages of this class are sent on
+    pub const fn stream(self) -> StreamId {
+        return self as StreamId;
+    }
+
+    pub const fn default_limits(self) -> ClassLimits {
+        return match self {
+            TrafficClass::Control => ClassLimits {
+                frames_per_sec: None,
+                burst: 1,
+                max_queued_bytes: 4096,
+            },
+            TrafficClass::Interactive => ClassLimits {
+                frames_per_sec: Some(100),
+                burst: 20,
+                max_queued_bytes: 4096,
+            },
+            TrafficClass::Telemetry => ClassLimits {
+                frames_per_sec: Some(50),
+                burst: 10,
+                max_queued_bytes: 8192,
+            },
+            TrafficClass::Bulk => ClassLimits {
+                frames_per_sec: Some(20),
+                burst: 8,
+                max_queued_bytes: 16384,
+            },
+        };
+    }
+}
+const _: () = assert!(TrafficClass::ALL.len() <= MAX_STREAMS as usize);
+
+/// How much a traffic class can send and queue
+#[derive(Debug, Clone, Copy)]
+pub struct ClassLimits {
+    /// Frames per second the class can send once its burst is spent, or
+    /// `None` for no limit
+    pub frames_per_sec: Option<u32>,
+    /// Frames the class can send back to back
+    pub burst: u32,
+    /// Bytes of chunks that can wait to be sent. Messages that don't fit are refused.
+    pub max_queued_bytes: usize,
+}
+
+struct QueuedMessage {
+    /// Key the chunks are to be sealed with
+    key_id: KeyId,
+    chunks: VecDeque<Vec<u8>>,
+}
+
+struct ClassQueue {
+    limits: ClassLimits,
+    messages: VecDeque<QueuedMessage>,
+    queued_bytes: usize,
+    /// Tokens are kept in thousandths so slow refills aren't rounded away
+    milli_tokens: u64,
+    last_refill: Instant,
+}
+impl ClassQueue {
+    fn new(limits: ClassLimits, now: Instant) -> Self {
+        return Self {
+            limits,
+            messages: VecDeque::new(),
+            queued_bytes: 0,
+            milli_tokens: Self::capacity(&limits),
+            last_refill: now,
+        };
+    }
+
+    /// Most thousandths of a frame the class can save up
+    fn capacity(limits: &ClassLimits) -> u64 {
+        return limits.burst.max(1) as u64 * 1000;
+    }
+
+    /// Charges one frame to the rate limit, if there's room for it
+    fn take_token(&mut self, now: Instant) -> bool {
+        let frames_per_sec = match self.limits.frames_per_sec {
+            Some(frames_per_sec) => frames_per_sec as u64,
+            None => return true,
+        };
+        let elapsed = match now.checked_duration_since(self.last_refill) {
+            Some(elapsed) => elapsed.to_micros(),
+            None => 0,
+        };
+        // Microseconds times frames per second gives thousandths of a frame
+        let refill = elapsed.saturating_mul(frames_per_sec) / 1000;
+        self.milli_tokens = (self.milli_tokens + refill).min(Self::capacity(&self.limits));
+        self.last_refill = now;
+        if self.milli_tokens < 1000 {
+            return false;
+        }
+        self.milli_tokens -= 1000;
+        return true;
+    }
+}
+
+pub struct Outbox {
+    /// One queue per class, most urgent first
+    queues: [ClassQueue; 4],
+}
+impl Outbox {
+    pub fn new(now: Instant) -> Self {
+        return Self {
+            queues: TrafficClass::ALL.map(|class| ClassQueue::new(class.default_limits(), now)),
+        };
+    }
+
+    pub fn set_limits(&mut self, class: TrafficClass, limits: ClassLimits) {
+        let queue = &mut self.queues[class as usize];
+        queue.limits = limits;
+        queue.milli_tokens = queue.milli_tokens.min(ClassQueue::capacity(&limits));
+    }
+
+    /// Bytes of chunks waiting to be sent in `class`
+    pub fn queued_bytes(&self, class: TrafficClass) -> usize {
+        return self.queues[class as usize].queued_bytes;
+    }
+
+    /// Queues the chunks of a message.
+    ///
+    /// Returns false, queueing nothing, if they don't fit within the class's limit.
+    pub fn push(&mut self, class: TrafficClass, key_id: KeyId, chunks: VecDeque<Vec<u8>>) -> bool {
+        let queue = &mut self.queues[class as usize];
+        let bytes: usize = chunks.iter().map(|chunk| chunk.len()).sum();
+        if queue.queued_bytes + bytes > queue.limits.max_queued_bytes {
+            return false;
+        }
+        if chunks.is_empty() {
+            return true;
+        }
+        queue.queued_bytes += bytes;
+        queue.messages.push_back(QueuedMessage { key_id, chunks });
+        return true;
+    }
+
+    /// Takes the next chunk to send along with the ID of the key to seal it with
+    pub fn pop_chunk(&mut self, now: Instant) -> Option<(KeyId, Vec<u8>)> {
+        for queue in self.queues.iter_mut() {
+            if queue.messages.is_empty() || !queue.take_token(now) {
+                continue;
+            }
+            let message = queue.messages.front_mut().unwrap();
+            let key_id = message.key_id;
+            let chunk = message.chunks.pop_front().unwrap();
+            if message.chunks.is_empty() {
+                queue.messages.pop_front();
+            }
+            queue.queued_bytes -= chunk.len();
+            return Some((key_id, chunk));
+        }
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_ID: KeyId = 1;
+
+    fn at(millis: u64) -> Instant {
+        return Instant::from_ticks(millis * 1000);
+    }
+
+    /// A message of `count` chunks, each filled with `tag`
+    fn message(tag: u8, count: usize) -> VecDeque<Vec<u8>> {
+        return (0..count).map(|_| alloc::vec![tag; 10]).collect();
+    }
+
+    fn unlimited(max_queued_bytes: usize) -> ClassLimits {
+        return ClassLimits {
+            frames_per_sec: None,
+            burst: 1,
+            max_queued_bytes,
+        };
+    }
+
+    #[test]
+    fn more_urgent_classes_go_first() {
+        let mut outbox = Outbox::new(at(0));
+        assert!(outbox.push(TrafficClass::Bulk, KEY_ID, message(4, 2)));
+        assert!(outbox.push(TrafficClass::Telemetry, KEY_ID, message(3, 1)));
+        assert!(outbox.push(TrafficClass::Interactive, KEY_ID, message(2, 1)));
+
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 2);
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 3);
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 4);
+        // A control message queued behind a bulk chunk only waits for that one chunk
+        assert!(outbox.push(TrafficClass::Control, KEY_ID, message(1, 1)));
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 1);
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 4);
+        assert_eq!(outbox.pop_chunk(at(0)), None);
+    }
+
+    #[test]
+    fn tokens_refill_at_the_class_rate() {
+        let mut outbox = Outbox::new(at(0));
+        outbox.set_limits(
+            TrafficClass::Bulk,
+            ClassLimits {
+                frames_per_sec: Some(10),
+                burst: 2,
+                max_queued_bytes: 1000,
+            },
+        );
+        assert!(outbox.push(TrafficClass::Bulk, KEY_ID, message(4, 10)));
+
+        // The burst goes out back to back, then one frame every 100 ms
+        assert!(outbox.pop_chunk(at(0)).is_some());
+        assert!(outbox.pop_chunk(at(0)).is_some());
+        assert_eq!(outbox.pop_chunk(at(0)), None);
+        assert_eq!(outbox.pop_chunk(at(50)), None);
+        assert!(outbox.pop_chunk(at(100)).is_some());
+        assert_eq!(outbox.pop_chunk(at(100)), None);
+
+        // Idle time never saves up more than the burst
+        assert!(outbox.pop_chunk(at(10_000)).is_some());
+        assert!(outbox.pop_chunk(at(10_000)).is_some());
+        assert_eq!(outbox.pop_chunk(at(10_000)), None);
+    }
+
+    #[test]
+    fn rate_limited_class_lets_others_through() {
+        let mut outbox = Outbox::new(at(0));
+        outbox.set_limits(
+            TrafficClass::Interactive,
+            ClassLimits {
+                frames_per_sec: Some(1),
+                burst: 1,
+                max_queued_bytes: 1000,
+            },
+        );
+        assert!(outbox.push(TrafficClass::Interactive, KEY_ID, message(2, 2)));
+        assert!(outbox.push(TrafficClass::Bulk, KEY_ID, message(4, 1)));
+
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 2);
+        assert_eq!(outbox.pop_chunk(at(0)).unwrap().1[0], 4);
+        assert_eq!(outbox.pop_chunk(at(0)), None);
+        assert_eq!(outbox.pop_chunk(at(1000)).unwrap().1[0], 2);
+    }
+
+    #[test]
+    fn messages_over_the_queue_limit_are_refused() {
+        let mut outbox = Outbox::new(at(0));
+        outbox.set_limits(TrafficClass::Telemetry, unlimited(25));
+
+        assert!(outbox.push(TrafficClass::Telemetry, KEY_ID, message(3, 2)));
+        assert_eq!(outbox.queued_bytes(TrafficClass::Telemetry), 20);
+        // Nothing of a message that doesn't fit is queued
+        assert!(!outbox.push(TrafficClass::Telemetry, KEY_ID, message(3, 1)));
+        assert_eq!(outbox.queued_bytes(TrafficClass::Telemetry), 20);
+        // Other classes have their own limits
+        assert!(outbox.push(TrafficClass::Bulk, KEY_ID, message(4, 1)));
+
+        // Sending frees up room
+        assert!(outbox.pop_chunk(at(0)).is_some());
+        assert_eq!(outbox.queued_bytes(TrafficClass::Telemetry), 10);
+        assert!(outbox.push(TrafficClass::Telemetry, KEY_ID, message(3, 1)));
+    }
+}
@@ -0,0 +1,625 @@
+//! Over-the-air enrollment of new nodes.
+//!
+//! 1. The Commander opens a pairing window (`PacketManager::start_pairing`).
+//! 2. An unprovisioned node (`PairingClient`) broadcasts an X25519 public key.
+//! 3. The Commander answers with its own public key and a commitment to a
+//!    random nonce.
+//! 4. The node sends its own nonce, then the Commander reveals its nonce and
+//!    the node checks it against the commitment.
+//! 5. Both sides derive a pairing key and a 6-digit confirmation code from
+//!    the shared secret, both public keys and both nonces.
+//! 6. The user checks that both devices show the same code and confirms on
+//!    the Commander (`PacketManager::confirm_pairing`), which sends the
+//!    cluster key and its own signing public key sealed under the pairing key.
+//! 7. The node writes the key to its `KeyStore`, pins the Commander's signing
+//!    key in its `DeviceIdentity`, and can join the cluster.
+//!
+//! A man-in-the-middle ends up with a different shared secret on each side,
+//! so it has to make the two codes match. As in Bluetooth's numeric
+//! comparison, the commitment stops it from choosing its nonces after seeing
+//! the real ones: whichever side it completes last, one input to that code is
+//! still unknown when it has to commit, so each attempt succeeds with only a
+//! one in a million chance, and every attempt needs the user to confirm.
+//!
+//! Either side gives up on a half-finished exchange after a while, so a stray
+//! or malicious offer can't hold up pairing for good.
+
+extern crate alloc;
+
+use crate::{
+    hal::{
+        aes::Aes,
+        rng::Rng,
+        sha::Sha,
+        time::{self, Duration, Instant},
+    },
+    hkdf, hw_aes,
+    hw_hmac::{self, HASH_SIZE},
+    key_store::{
+        ClusterKey, DeviceIdentity, KeyStore, CLUSTER_KEY_SIZE, PUBLIC_KEY_SIZE as SIGNING_KEY_SIZE,
+        SIGNING_SEED_SIZE,
+    },
+    transport::{frame_kind, MacAddress, ReceivedFrame, Transport},
+};
+use alloc::vec::Vec;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// First byte of every pairing frame
+const FRAME_KIND_PAIRING: u8 = frame_kind::PAIRING;
+
+/// Node's public key, broadcast
+const MSG_REQUEST: u8 = 0;
+/// Commander's public key and nonce commitment
+const MSG_OFFER: u8 = 1;
+/// Node's nonce
+const MSG_NONCE: u8 = 2;
+/// Commander's nonce
+const MSG_REVEAL: u8 = 3;
+/// Cluster key sealed under the pairing key
+const MSG_KEY: u8 = 4;
+
+const PUBLIC_KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 16;
+const REQUEST_LEN: usize = 2 + PUBLIC_KEY_SIZE;
+const OFFER_LEN: usize = 2 + PUBLIC_KEY_SIZE + HASH_SIZE;
+const NONCE_LEN: usize = 2 + NONCE_SIZE;
+/// Key ID, cluster key and the Commander's signing key
+const KEY_PAYLOAD_LEN: usize = 1 + CLUSTER_KEY_SIZE + SIGNING_KEY_SIZE;
+const REQUEST_INTERVAL: Duration = Duration::secs(1);
+/// How long a node waits for the Commander to reveal its nonce
+const OFFER_TIMEOUT: Duration = Duration::secs(3);
+/// How long a node shows a code while waiting for the user to confirm
+const CONFIRM_TIMEOUT: Duration = Duration::secs(60);
+/// How long the Commander keeps a quiet node before letting another one pair
+const PENDING_TIMEOUT: Duration = Duration::secs(10);
+const CONFIRMATION_CODE_MODULUS: u32 = 1_000_000;
+const PAIRING_SALT: &[u8] = b"tactile-tesla pairing v2";
+/// Every pairing derives a fresh key that only ever seals the one key
+/// payload, so its nonce doesn't need to vary
+const KEY_FRAME_NONCE: [u8; hw_aes::CCM_NONCE_SIZE] = [0u8; hw_aes::CCM_NONCE_SIZE];
+
+/// Keys derived from the X25519 exchange
+#[derive(Clone)]
+struct PairingSecrets {
+    pairing_key: [u8; hw_aes::AES_KEY_SIZE],
+    code: u32,
+}
+
+/// Everything both sides contribute, in the order it is hashed
+struct Transcript<'a> {
+    node_public: &'a [u8; PUBLIC_KEY_SIZE],
+    commander_public: &'a [u8; PUBLIC_KEY_SIZE],
+    node_nonce: &'a [u8; NONCE_SIZE],
+    commander_nonce: &'a [u8; NONCE_SIZE],
+}
+
+fn generate_keypair(rng_peripheral: &mut Rng) -> ([u8; 32], [u8; PUBLIC_KEY_SIZE]) {
+    let mut secret = [0u8; 32];
+    rng_peripheral.read(&mut secret);
+    let public = x25519(secret, X25519_BASEPOINT_BYTES);
+    return (secret, public);
+}
+
+fn generate_nonce(rng_peripheral: &mut Rng) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    rng_peripheral.read(&mut nonce);
+    return nonce;
+}
+
+/// The X25519 shared secret, or `None` for a low-order public key, which
+/// forces an all-zero secret
+fn shared_secret(secret: [u8; 32], peer_public: [u8; PUBLIC_KEY_SIZE]) -> Option<[u8; 32]> {
+    let shared = x25519(secret, peer_public);
+    if shared.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    return Some(shared);
+}
+
+/// The Commander's commitment to its nonce, bound to both public keys
+fn commitment(
+    sha_peripheral: &mut Sha<'_>,
+    commander_nonce: &[u8; NONCE_SIZE],
+    node_public: &[u8; PUBLIC_KEY_SIZE],
+    commander_public: &[u8; PUBLIC_KEY_SIZE],
+) -> Option<[u8; HASH_SIZE]> {
+    let mut message = [0u8; 8 + 2 * PUBLIC_KEY_SIZE];
+    message[0..8].copy_from_slice(b"pair-cmt");
+    message[8..8 + PUBLIC_KEY_SIZE].copy_from_slice(node_public);
+    message[8 + PUBLIC_KEY_SIZE..].copy_from_slice(commander_public);
+    return hkdf::extract(sha_peripheral, commander_nonce, &message).ok();
+}
+
+fn derive_secrets(
+    sha_peripheral: &mut Sha<'_>,
+    shared: &[u8; 32],
+    transcript: &Transcript<'_>,
+) -> Option<PairingSecrets> {
+    let mut info = [0u8; 8 + 2 * PUBLIC_KEY_SIZE + 2 * NONCE_SIZE];
+    let mut offset = 8;
+    for part in [
+        &transcript.node_public[..],
+        transcript.commander_public,
+        transcript.node_nonce,
+        transcript.commander_nonce,
+    ] {
+        info[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+
+    let prk = hkdf::extract(sha_peripheral, PAIRING_SALT, shared).ok()?;
+    let mut pairing_key = [0u8; hw_aes::AES_KEY_SIZE];
+    info[0..8].copy_from_slice(b"pair-key");
+    hkdf::expand(sha_peripheral, &prk, &info, &mut pairing_key).ok()?;
+    let mut code = [0u8; 4];
+    info[0..8].copy_from_slice(b"pair-cfm");
+    hkdf::expand(sha_peripheral, &prk, &info, &mut code).ok()?;
+
+    return Some(PairingSecrets {
+        pairing_key,
+        code: u32::from_be_bytes(code) % CONFIRMATION_CODE_MODULUS,
+    });
+}
+
+/// Associated data for the key transfer, binding it to both public keys
+fn key_frame_aad(
+    node_public: &[u8; PUBLIC_KEY_SIZE],
+    commander_public: &[u8; PUBLIC_KEY_SIZE],
+) -> [u8; 2 + 2 * PUBLIC_KEY_SIZE] {
+    let mut aad = [0u8; 2 + 2 * PUBLIC_KEY_SIZE];
+    aad[0] = FRAME_KIND_PAIRING;
+    aad[1] = MSG_KEY;
+    aad[2..2 + PUBLIC_KEY_SIZE].copy_from_slice(node_public);
+    aad[2 + PUBLIC_KEY_SIZE..].copy_from_slice(commander_public);
+    return aad;
+}
+
+/// A pairing frame made of `msg` followed by `parts`
+fn pairing_frame(msg: u8, parts: &[&[u8]]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(OFFER_LEN);
+    frame.extend_from_slice(&[FRAME_KIND_PAIRING, msg]);
+    for part in parts {
+        frame.extend_from_slice(part);
+    }
+    return frame;
+}
+
+fn send_frame<T: Transport>(transport: &mut T, peer: &MacAddress, frame: &[u8]) {
+    if let Err(err) = transport.send(peer, frame) {
+        log::error!("Failed to send pairing frame: {err:?}");
+    }
+}
+
+/// What the Commander needs to answer a pairing frame
+pub(super) struct PairingContext<'a, 'd, T: Transport> {
+    pub transport: &'a mut T,
+    pub sha_peripheral: &'a mut Sha<'d>,
+    pub rng_peripheral: &'a mut Rng,
+}
+
+/// A node that has answered our offer and is waiting for confirmation
+struct PendingPairing {
+    peer: MacAddress,
+    node_public: [u8; PUBLIC_KEY_SIZE],
+    commander_public: [u8; PUBLIC_KEY_SIZE],
+    shared: [u8; 32],
+    commander_nonce: [u8; NONCE_SIZE],
+    commitment: [u8; HASH_SIZE],
+    /// The node's nonce and the secrets derived with it, once it has sent one.
+    /// It can't be changed afterwards, or the node could pick it after seeing
+    /// our nonce.
+    revealed: Option<([u8; NONCE_SIZE], PairingSecrets)>,
+    /// The sealed cluster key, once the user has confirmed
+    key_frame: Option<Vec<u8>>,
+    last_heard: Instant,
+}
+
+/// Commander side of an open pairing window
+pub(super) struct PairingWindow {
+    closes_at: Instant,
+    pending: Option<PendingPairing>,
+}
+impl PairingWindow {
+    pub fn new(duration: Duration) -> Self {
+        return Self {
+            closes_at: time::now() + duration,
+            pending: None,
+        };
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        return now >= self.closes_at;
+    }
+
+    /// The node being paired and the code it should be displaying, once both
+    /// nonces have been exchanged
+    pub fn pending(&self) -> Option<(MacAddress, u32)> {
+        let pending = self.pending.as_ref()?;
+        let (_, ref secrets) = pending.revealed.as_ref()?;
+        return Some((pending.peer, secrets.code));
+    }
+
+    /// Handles a pairing frame, sending any replies
+    pub fn handle_frame<T: Transport>(
+        &mut self,
+        context: PairingContext<'_, '_, T>,
+        frame: &ReceivedFrame,
+    ) {
+        let now = time::now();
+        let data = &frame.data;
+        let msg = match data.get(1) {
+            Some(msg) => *msg,
+            None => return,
+        };
+        match (msg, &mut self.pending) {
+            (MSG_REQUEST, pending) if data.len() == REQUEST_LEN => {
+                let node_public: [u8; PUBLIC_KEY_SIZE] = data[2..].try_into().unwrap();
+                if let Some(pending) = pending.as_mut() {
+                    let same_node =
+                        pending.peer == frame.src_address && pending.node_public == node_public;
+                    // The node didn't hear our offer. Repeat it.
+                    if same_node && pending.revealed.is_none() {
+                        pending.last_heard = now;
+                        let offer = pairing_frame(
+                            MSG_OFFER,
+                            &[&pending.commander_public, &pending.commitment],
+                        );
+                        send_frame(context.transport, &pending.peer, &offer);
+                        return;
+                    }
+                    // Only pair with one node at a time, unless it went quiet
+                    // or started over
+                    if !same_node && now < pending.last_heard + PENDING_TIMEOUT {
+                        return;
+                    }
+                }
+                *pending = Self::offer(context, frame.src_address, node_public, now);
+            }
+            (MSG_NONCE, Some(pending))
+                if data.len() == NONCE_LEN && pending.peer == frame.src_address =>
+            {
+                let node_nonce: [u8; NONCE_SIZE] = data[2..].try_into().unwrap();
+                match pending.revealed {
+                    Some((ref revealed_nonce, _)) if *revealed_nonce != node_nonce => return,
+                    // The node didn't hear our reveal or the key. Repeat them.
+                    Some(_) => {}
+                    None => {
+                        let secrets = match derive_secrets(
+                            context.sha_peripheral,
+                            &pending.shared,
+                            &Transcript {
+                                node_public: &pending.node_public,
+                                commander_public: &pending.commander_public,
+                                node_nonce: &node_nonce,
+                                commander_nonce: &pending.commander_nonce,
+                            },
+                        ) {
+                            Some(secrets) => secrets,
+                            None => return,
+                        };
+                        pending.revealed = Some((node_nonce, secrets));
+                    }
+                }
+                pending.last_heard = now;
+                let reveal = pairing_frame(MSG_REVEAL, &[&pending.commander_nonce]);
+                send_frame(context.transport, &pending.peer, &reveal);
+                pending.send_key(context.transport);
+            }
+            _ => {}
+        }
+    }
+
+    /// Starts pairing with a node that sent `node_public`, sending our offer
+    fn offer<T: Transport>(
+        context: PairingContext<'_, '_, T>,
+        peer: MacAddress,
+        node_public: [u8; PUBLIC_KEY_SIZE],
+        now: Instant,
+    ) -> Option<PendingPairing> {
+        let (secret, commander_public) = generate_keypair(context.rng_peripheral);
+        let shared = shared_secret(secret, node_public)?;
+        let commander_nonce = generate_nonce(context.rng_peripheral);
+        let commitment = commitment(
+            context.sha_peripheral,
+            &commander_nonce,
+            &node_public,
+            &commander_public,
+        )?;
+        let offer = pairing_frame(MSG_OFFER, &[&commander_public, &commitment]);
+        send_frame(context.transport, &peer, &offer);
+        return Some(PendingPairing {
+            peer,
+            node_public,
+            commander_public,
+            shared,
+            commander_nonce,
+            commitment,
+            revealed: None,
+            key_frame: None,
+            last_heard: now,
+        });
+    }
+
+    /// Sends the cluster key to the pending node once the user has compared codes
+    pub fn confirm<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        aes_peripheral: &mut Aes<'_>,
+        cluster_key: &ClusterKey,
+        commander_key: &[u8; SIGNING_KEY_SIZE],
+    ) -> bool {
+        let pending = match self.pending {
+            Some(ref mut pending) => pending,
+            None => return false,
+        };
+        let secrets = match pending.revealed {
+            Some((_, ref secrets)) => secrets,
+            None => return false,
+        };
+        // Seal the key only once, so repeats reuse the nonce with the same payload
+        if pending.key_frame.is_none() {
+            let mut sealed = Vec::with_capacity(KEY_PAYLOAD_LEN);
+            sealed.push(cluster_key.id);
+            sealed.extend_from_slice(&cluster_key.secret);
+            sealed.extend_from_slice(commander_key);
+            hw_aes::seal_packet(
+                aes_peripheral,
+                &secrets.pairing_key,
+                &KEY_FRAME_NONCE,
+                &key_frame_aad(&pending.node_public, &pending.commander_public),
+                hw_aes::CCM_TAG_SIZE,
+                &mut sealed,
+            );
+            sealed.splice(0..0, [FRAME_KIND_PAIRING, MSG_KEY]);
+            pending.key_frame = Some(sealed);
+        }
+        pending.send_key(transport);
+        return true;
+    }
+}
+
+impl PendingPairing {
+    /// Sends the sealed cluster key, if the user has confirmed
+    fn send_key<T: Transport>(&self, transport: &mut T) {
+        if let Some(ref key_frame) = self.key_frame {
+            send_frame(transport, &self.peer, key_frame);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PairingEvent {
+    /// A Commander answered. This code should be shown to the user.
+    ConfirmationCode(u32),
+    /// The Commander went quiet before sending the key, so the code shown is
+    /// no longer valid. The node starts asking again.
+    Restarted,
+    /// The cluster key was received and stored, and the Commander's signing key pinned
+    Paired(ClusterKey),
+}
+
+/// How far the node has got with a Commander
+enum ClientState {
+    /// Broadcasting our public key until a Commander answers
+    Requesting,
+    /// Sent our nonce in answer to an offer, waiting for the Commander's
+    Offered {
+        commander_mac: MacAddress,
+        commander_public: [u8; PUBLIC_KEY_SIZE],
+        commitment: [u8; HASH_SIZE],
+    },
+    /// Showing the code, waiting for the user to confirm on the Commander
+    Confirming {
+        commander_mac: MacAddress,
+        commander_public: [u8; PUBLIC_KEY_SIZE],
+        secrets: PairingSecrets,
+    },
+}
+
+/// Node side of pairing, used while no cluster key is provisioned
+pub struct PairingClient<T: Transport> {
+    transport: T,
+    secret: [u8; 32],
+    public: [u8; PUBLIC_KEY_SIZE],
+    /// Fresh for every Commander we try to pair with
+    nonce: [u8; NONCE_SIZE],
+    /// Used for the device identity if the node doesn't have one yet
+    signing_seed: [u8; SIGNING_SEED_SIZE],
+    state: ClientState,
+    state_since: Instant,
+    next_send: Instant,
+}
+impl<T: Transport> PairingClient<T> {
+    pub fn new(transport: T, rng_peripheral: &mut Rng) -> Self {
+        let (secret, public) = generate_keypair(rng_peripheral);
+        let mut signing_seed = [0u8; SIGNING_SEED_SIZE];
+        rng_peripheral.read(&mut signing_seed);
+        let now = time::now();
+        return Self {
+            transport,
+            secret,
+            public,
+            nonce: generate_nonce(rng_peripheral),
+            signing_seed,
+            state: ClientState::Requesting,
+            state_since: now,
+            next_send: now,
+        };
+    }
+
+    /// Gives back the transport so it can be handed to a `PacketManager`
+    pub fn into_transport(self) -> T {
+        return self.transport;
+    }
+
+    /// Forgets the current Commander and starts asking again
+    fn restart(&mut self, rng_peripheral: &mut Rng, now: Instant) {
+        self.nonce = generate_nonce(rng_peripheral);
+        self.state = ClientState::Requesting;
+        self.state_since = now;
+        self.next_send = now;
+    }
+
+    fn send_nonce(&mut self, commander_mac: &MacAddress) {
+        let frame = pairing_frame(MSG_NONCE, &[&self.nonce]);
+        send_frame(&mut self.transport, commander_mac, &frame);
+    }
+
+    pub fn tick(
+        &mut self,
+        aes_peripheral: &mut Aes<'_>,
+        sha_peripheral: &mut Sha<'_>,
+        rng_peripheral: &mut Rng,
+        key_store: &mut impl KeyStore,
+    ) -> Option<PairingEvent> {
+        let tick_now = time::now();
+        let mut event = None;
+
+        // Give up on a Commander that stopped answering, or was never real
+        match self.state {
+            ClientState::Offered { .. } if tick_now >= self.state_since + OFFER_TIMEOUT => {
+                self.restart(rng_peripheral, tick_now);
+            }
+            ClientState::Confirming { .. } if tick_now >= self.state_since + CONFIRM_TIMEOUT => {
+                self.restart(rng_peripheral, tick_now);
+                event = Some(PairingEvent::Restarted);
+            }
+            _ => {}
+        }
+
+        // Keep repeating our last message until we have a key, in case
+        // replies get lost
+        if tick_now >= self.next_send {
+            self.next_send = tick_now + REQUEST_INTERVAL;
+            match self.state {
+                ClientState::Requesting => {
+                    let request = pairing_frame(MSG_REQUEST, &[&self.public]);
+                    if let Err(err) = self.transport.broadcast(&request) {
+                        log::error!("Failed to send pairing request: {err:?}");
+                    }
+                }
+                ClientState::Offered { commander_mac, .. }
+                | ClientState::Confirming { commander_mac, .. } => {
+                    self.send_nonce(&commander_mac);
+                }
+            }
+        }
+
+        while let Some(frame) = self.transport.receive() {
+            if frame.data.len() < 2 || frame.data[0] != FRAME_KIND_PAIRING {
+                continue;
+            }
+            match (frame.data[1], &self.state) {
+                (MSG_OFFER, ClientState::Requesting) if frame.data.len() == OFFER_LEN => {
+                    let commander_public: [u8; PUBLIC_KEY_SIZE] =
+                        frame.data[2..2 + PUBLIC_KEY_SIZE].try_into().unwrap();
+                    let commitment: [u8; HASH_SIZE] =
+                        frame.data[2 + PUBLIC_KEY_SIZE..].try_into().unwrap();
+                    self.state = ClientState::Offered {
+                        commander_mac: frame.src_address,
+                        commander_public,
+                        commitment,
+                    };
+                    self.state_since = tick_now;
+                    self.next_send = tick_now + REQUEST_INTERVAL;
+                    self.send_nonce(&frame.src_address);
+                }
+                (
+                    MSG_REVEAL,
+                    ClientState::Offered {
+                        commander_mac,
+                        commander_public,
+                        commitment: committed,
+                    },
+                ) if frame.src_address == *commander_mac && frame.data.len() == NONCE_LEN => {
+                    let commander_nonce: [u8; NONCE_SIZE] = frame.data[2..].try_into().unwrap();
+                    let (commander_mac, commander_public, committed) =
+                        (*commander_mac, *commander_public, *committed);
+                    let secrets = match commitment(
+                        sha_peripheral,
+                        &commander_nonce,
+                        &self.public,
+                        &commander_public,
+                    ) {
+                        Some(expected) if hw_hmac::tags_match(&expected, &committed) => {
+                            shared_secret(self.secret, commander_public).and_then(|shared| {
+                                derive_secrets(
+                                    sha_peripheral,
+                                    &shared,
+                                    &Transcript {
+                                        node_public: &self.public,
+                                        commander_public: &commander_public,
+                                        node_nonce: &self.nonce,
+                                        commander_nonce: &commander_nonce,
+                                    },
+                                )
+                            })
+                        }
+                        _ => None,
+                    };
+                    match secrets {
+                        Some(secrets) => {
+                            event = Some(PairingEvent::ConfirmationCode(secrets.code));
+                            self.state = ClientState::Confirming {
+                                commander_mac,
+                                commander_public,
+                                secrets,
+                            };
+                            self.state_since = tick_now;
+                        }
+                        // Whoever sent the offer can't back it up
+                        None => self.restart(rng_peripheral, tick_now),
+                    }
+                }
+                (
+                    MSG_KEY,
+                    ClientState::Confirming {
+                        commander_mac,
+                        commander_public,
+                        secrets,
+                    },
+                ) if frame.src_address == *commander_mac => {
+                    let mut sealed = frame.data;
+                    let payload = match hw_aes::open_packet(
+                        aes_peripheral,
+                        &secrets.pairing_key,
+                        &KEY_FRAME_NONCE,
+                        &key_frame_aad(&self.public, commander_public),
+                        hw_aes::CCM_TAG_SIZE,
+                        &mut sealed[2..],
+                    ) {
+                        Ok(payload) if payload.len() == KEY_PAYLOAD_LEN => payload,
+                        _ => continue,
+                    };
+                    let cluster_key = ClusterKey {
+                        id: payload[0],
+                        secret: payload[1..1 + CLUSTER_KEY_SIZE].try_into().unwrap(),
+                    };
+                    let commander_key: [u8; SIGNING_KEY_SIZE] =
+                        payload[1 + CLUSTER_KEY_SIZE..].try_into().unwrap();
+
+                    // Pin the Commander before joining, so we never run without one
+                    let mut identity = match key_store.load_identity() {
+                        Ok(Some(identity)) => identity,
+                        _ => DeviceIdentity {
+                            signing_seed: self.signing_seed,
+                            commander_key: None,
+                        },
+                    };
+                    identity.commander_key = Some(commander_key);
+                    if let Err(err) = key_store.store_identity(&identity) {
+                        log::error!("Failed to store device identity: {err:?}");
+                        continue;
+                    }
+                    if let Err(err) = key_store.store_cluster_key(&cluster_key) {
+                        log::error!("Failed to store cluster key: {err:?}");
+                        continue;
+                    }
+                    return Some(PairingEvent::Paired(cluster_key));
+                }
+                _ => {}
+            }
+        }
+        return event;
+    }
+}
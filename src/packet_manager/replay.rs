@@ -0,0 +1,115 @@
+//! Replay protection for authenticated frames.
+//!
+//! Every frame carries a per-sender counter that is authenticated along with
+//! the sender's address. Receivers keep a sliding window of recently seen
+//! counters for each peer and drop anything old or already seen.
+//!
+//! Counters survive reboots via a `CounterStore`. To avoid wearing out flash,
+//! the transmit side persists a reservation ahead of the counter it is using
+//! and skips to the end of that reservation on boot, while the receive side
+//! only persists each peer's high-water mark every `RX_PERSIST_INTERVAL`
+//! frames. On boot a receiver assumes each peer got up to that far past its
+//! stored mark, so it never accepts a frame it saw before the reboot, at the
+//! cost of dropping up to `RX_PERSIST_INTERVAL` of the peer's next frames.
+
+use crate::{counter_store::CounterStore, transport::MacAddress};
+
+/// Number of counters behind the highest one that are still accepted
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+/// Number of transmit counters reserved per write to the `CounterStore`
+pub const TX_COUNTER_RESERVATION: u64 = 1024;
+/// How far a peer's high-water mark can advance before it is persisted
+pub const RX_PERSIST_INTERVAL: u64 = 256;
+
+/// Hands out frame counters that never repeat, even across reboots
+pub struct TxCounter {
+    next: u64,
+    reserved: u64,
+}
+impl TxCounter {
+    pub fn restore(store: &mut impl CounterStore) -> Self {
+        // Anything below the persisted reservation may already have been used
+        let next = store.load_tx_counter().unwrap_or(0) + 1;
+        let reserved = next + TX_COUNTER_RESERVATION;
+        store.store_tx_counter(reserved);
+        return Self { next, reserved };
+    }
+
+    pub fn next(&mut self, store: &mut impl CounterStore) -> u64 {
+        let counter = self.next;
+        self.next += 1;
+        if self.next >= self.reserved {
+            self.reserved = self.next + TX_COUNTER_RESERVATION;
+            store.store_tx_counter(self.reserved);
+        }
+        return counter;
+    }
+}
+
+/// Sliding window of counters recently accepted from a single peer
+pub struct ReplayWindow {
+    highest: u64,
+    /// Bit `i` is set if `highest - i` has been accepted
+    bitmap: u64,
+    /// The mark last written to the `CounterStore`, if any
+    persisted: Option<u64>,
+}
+impl ReplayWindow {
+    pub fn restore(peer: &MacAddress, store: &mut impl CounterStore) -> Self {
+        let persisted = store.load_peer_counter(peer);
+        // The peer may have got up to an interval past the stored mark before
+        // we rebooted, so treat everything up to there as already seen
+        let highest = match persisted {
+            Some(stored) => stored + RX_PERSIST_INTERVAL,
+            None => 0,
+        };
+        return Self {
+            highest,
+            bitmap: u64::MAX,
+            persisted,
+        };
+    }
+
+    /// Checks whether `counter` would be accepted, without recording it.
+    ///
+    /// This is cheap enough to run before authenticating the frame.
+    pub fn check(&self, counter: u64) -> bool {
+        if counter > self.highest {
+            return true;
+        }
+        let age = self.highest - counter;
+        if age >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+        return self.bitmap & (1 << age) == 0;
+    }
+
+    /// Records `counter` as seen.
+    ///
+    /// Must only be called once the frame has been authenticated.
+    pub fn accept(&mut self, peer: &MacAddress, counter: u64, store: &mut impl CounterStore) {
+        if counter > self.highest {
+            let shift = counter - self.highest;
+            self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.highest = counter;
+
+            // The first mark is stored straight away, so a peer we have heard
+            // from is never treated as new after a reboot
+            let due = match self.persisted {
+                Some(persisted) => self.highest >= persisted + RX_PERSIST_INTERVAL,
+                None => true,
+            };
+            if due {
+                store.store_peer_counter(peer, self.highest);
+                self.persisted = Some(self.highest);
+            }
+        } else {
+            self.bitmap |= 1 << (self.highest - counter);
+        }
+    }
+}
@@ -1,8 +1,10 @@
 extern crate alloc;
 
-use alloc::string::String;
+use alloc::{string::String, vec::Vec};
 use crate::{
     binary_packets::{PacketReader, PacketWriteError, PacketWriter},
+    identity::{self, SIGNATURE_SIZE},
+    key_store::{ClusterKey, DeviceIdentity, CLUSTER_KEY_SIZE, PUBLIC_KEY_SIZE},
     packet_manager::Role,
 };
 
@@ -35,6 +37,8 @@ impl Transmittable for Role {
 #[derive(Debug, Clone)]
 pub enum CommPacket {
     Heartbeat(Heartbeat),
+    /// Privileged command, only acted on if signed by the Commander
+    Command(SignedCommand),
 }
 impl Transmittable for CommPacket {
     fn encode(&self, packet_writer: &mut PacketWriter) -> Result<(), PacketWriteError> {
@@ -43,11 +47,16 @@ impl Transmittable for CommPacket {
                 packet_writer.write_u8(0);
                 return heartbeat.encode(packet_writer);
             }
+            Self::Command(command) => {
+                packet_writer.write_u8(1);
+                return command.encode(packet_writer);
+            }
         }
     }
     fn decode(packet_reader: &mut PacketReader) -> Option<Self> {
         match packet_reader.read_u8()? {
             0 => Some(Self::Heartbeat(Heartbeat::decode(packet_reader)?)),
+            1 => Some(Self::Command(SignedCommand::decode(packet_reader)?)),
             _ => None,
         }
     }
@@ -81,3 +90,146 @@ impl Transmittable for Heartbeat {
         });
     }
 }
+
+/// Messages that change the state of the vehicle or the cluster.
+///
+/// These are always sent inside a `SignedCommand`.
+#[derive(Debug, Clone)]
+pub enum Command {
+    RotateKey(RotateKey),
+}
+impl Transmittable for Command {
+    fn encode(&self, packet_writer: &mut PacketWriter) -> Result<(), PacketWriteError> {
+        match self {
+            Self::RotateKey(rotate_key) => {
+                packet_writer.write_u8(0);
+                return rotate_key.encode(packet_writer);
+            }
+        }
+    }
+    fn decode(packet_reader: &mut PacketReader) -> Option<Self> {
+        match packet_reader.read_u8()? {
+            0 => Some(Self::RotateKey(RotateKey::decode(packet_reader)?)),
+            _ => None,
+        }
+    }
+}
+
+/// A `Command` signed with the Commander's Ed25519 key.
+///
+/// The signature covers the sequence number and the encoded command exactly
+/// as sent. Receivers must only accept increasing sequence numbers, so a node
+/// holding the cluster key can't replay an old command in a fresh frame.
+#[derive(Clone)]
+pub struct SignedCommand {
+    pub sequence: u64,
+    command: Vec<u8>,
+    signature: [u8; SIGNATURE_SIZE],
+}
+impl SignedCommand {
+    pub fn sign(
+        identity: &DeviceIdentity,
+        sequence: u64,
+        command: &Command,
+    ) -> Result<Self, PacketWriteError> {
+        let mut command_bytes = PacketWriter::new();
+        command.encode(&mut command_bytes)?;
+        let command = command_bytes.finish();
+        let signature = identity.sign(&signed_message(sequence, &command));
+        return Ok(Self {
+            sequence,
+            command,
+            signature,
+        });
+    }
+
+    /// Returns the command if it was signed by `commander_key`
+    pub fn verify(&self, commander_key: &[u8; PUBLIC_KEY_SIZE]) -> Option<Command> {
+        if !identity::verify(
+            commander_key,
+            &signed_message(self.sequence, &self.command),
+            &self.signature,
+        ) {
+            return None;
+        }
+        let mut packet_reader = PacketReader::new(&self.command);
+        let command = Command::decode(&mut packet_reader)?;
+        if packet_reader.remaining() != 0 {
+            return None;
+        }
+        return Some(command);
+    }
+}
+impl core::fmt::Debug for SignedCommand {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // The command may hold key material, so only show its size
+        return f
+            .debug_struct("SignedCommand")
+            .field("sequence", &self.sequence)
+            .field("command_len", &self.command.len())
+            .finish();
+    }
+}
+impl Transmittable for SignedCommand {
+    fn encode(&self, packet_writer: &mut PacketWriter) -> Result<(), PacketWriteError> {
+        packet_writer.write_u64(self.sequence);
+        packet_writer.write_bytes(&self.command)?;
+        packet_writer.write_bytes(&self.signature)?;
+        return Ok(());
+    }
+    fn decode(packet_reader: &mut PacketReader) -> Option<Self> {
+        let sequence = packet_reader.read_u64()?;
+        let command = Vec::from(packet_reader.read_bytes()?);
+        let signature = packet_reader.read_bytes()?.try_into().ok()?;
+        return Some(Self {
+            sequence,
+            command,
+            signature,
+        });
+    }
+}
+
+/// Domain-separates command signatures from anything else the key might sign
+fn signed_message(sequence: u64, command: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(8 + 8 + command.len());
+    message.extend_from_slice(b"ttcmd-v1");
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(command);
+    return message;
+}
+
+/// Distributes a new cluster key, sealed under the one it replaces.
+///
+/// Nodes keep accepting the old key for `grace_secs` after switching.
+#[derive(Clone)]
+pub struct RotateKey {
+    pub new_key: ClusterKey,
+    pub grace_secs: u32,
+}
+impl core::fmt::Debug for RotateKey {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        // Never log key material
+        return f
+            .debug_struct("RotateKey")
+            .field("key_id", &self.new_key.id)
+            .field("grace_secs", &self.grace_secs)
+            .finish();
+    }
+}
+impl Transmittable for RotateKey {
+    fn encode(&self, packet_writer: &mut PacketWriter) -> Result<(), PacketWriteError> {
+        packet_writer.write_u8(self.new_key.id);
+        packet_writer.write_bytes(&self.new_key.secret)?;
+        packet_writer.write_u32(self.grace_secs);
+        return Ok(());
+    }
+    fn decode(packet_reader: &mut PacketReader) -> Option<Self> {
+        let id = packet_reader.read_u8()?;
+        let secret: [u8; CLUSTER_KEY_SIZE] = packet_reader.read_bytes()?.try_into().ok()?;
+        let grace_secs = packet_reader.read_u32()?;
+        return Some(Self {
+            new_key: ClusterKey { id, secret },
+            grace_secs,
+        });
+    }
+}
@@ -0,0 +1,169 @@
+//! Framing for byte streams such as a UART.
+//!
+//! Each message is followed by its CRC-32 and then COBS encoded, which
+//! removes every zero byte so that a single `0x00` can mark where frames
+//! begin and end:
+//!
+//! `0x00 [COBS(message || crc32(message) u32)] 0x00`
+//!
+//! A receiver that loses bytes, or picks up garbage, discards at most the
+//! frame it was in the middle of and starts over at the next delimiter.
+//! The CRC catches corruption that doesn't break the encoding itself.
+
+extern crate alloc;
+
+use crate::crc::crc32;
+use alloc::{collections::VecDeque, vec::Vec};
+
+/// Marks the boundaries between frames
+const DELIMITER: u8 = 0;
+/// Bytes of CRC appended to each message before encoding
+const CRC_LEN: usize = 4;
+
+/// Worst-case length of a frame carrying `len` bytes, not counting delimiters
+pub const fn cobs_frame_len(len: usize) -> usize {
+    return len + CRC_LEN + (len + CRC_LEN) / 254 + 1;
+}
+
+/// Appends `packet` to `out` as a delimited frame.
+///
+/// This is the transmitting end of `CobsPacketAssembler`.
+pub fn encode_cobs_frame(packet: &[u8], out: &mut Vec<u8>) {
+    out.reserve(cobs_frame_len(packet.len()) + 2);
+    // A leading delimiter ends any garbage the receiver picked up before us
+    out.push(DELIMITER);
+
+    let crc = crc32(packet).to_be_bytes();
+    let mut code_index = out.len();
+    out.push(0);
+    let mut code = 1u8;
+    for byte in packet.iter().chain(crc.iter()) {
+        if *byte != 0 {
+            out.push(*byte);
+            code += 1;
+        }
+        if *byte == 0 || code == 0xFF {
+            out[code_index] = code;
+            code_index = out.len();
+            out.push(0);
+            code = 1;
+        }
+    }
+    out[code_index] = code;
+    out.push(DELIMITER);
+}
+
+/// Decodes a COBS frame, without delimiters, in place.
+///
+/// Returns the decoded length, or `None` if the frame isn't valid COBS.
+fn decode_in_place(frame: &mut [u8]) -> Option<usize> {
+    let (mut read, mut write) = (0, 0);
+    while read < frame.len() {
+        let code = frame[read] as usize;
+        if code == 0 || read + code > frame.len() {
+            return None;
+        }
+        read += 1;
+        for _ in 1..code {
+            frame[write] = frame[read];
+            read += 1;
+            write += 1;
+        }
+        if code < 0xFF && read < frame.len() {
+            frame[write] = 0;
+            write += 1;
+        }
+    }
+    return Some(write);
+}
+
+/// Counts of frames a `CobsPacketAssembler` threw away
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FramingErrors {
+    /// Frames whose CRC didn't match
+    pub bad_crc: u32,
+    /// Frames that weren't valid COBS, or were too short to hold a CRC
+    pub malformed: u32,
+    /// Frames longer than the assembler's maximum message size
+    pub oversized: u32,
+}
+
+/// Re-assembles messages from a byte stream framed by `encode_cobs_frame`.
+///
+/// Bytes can be pushed in pieces of any size. Unlike `PacketAssembler`,
+/// this copes with links that drop or corrupt bytes: a damaged frame is
+/// dropped and reception picks up again at the next delimiter.
+pub struct CobsPacketAssembler {
+    buffer: Vec<u8>,
+    /// Set while skipping the rest of a frame that grew too long
+    overflowed: bool,
+    max_frame_len: usize,
+    packets: VecDeque<Vec<u8>>,
+    errors: FramingErrors,
+}
+impl CobsPacketAssembler {
+    /// Creates an assembler that drops messages longer than `max_message_size`
+    pub fn new(max_message_size: usize) -> Self {
+        return Self {
+            buffer: Vec::new(),
+            overflowed: false,
+            max_frame_len: cobs_frame_len(max_message_size),
+            packets: VecDeque::new(),
+            errors: FramingErrors::default(),
+        };
+    }
+
+    /// Returns the error counters accumulated since the last call, and resets them
+    pub fn take_errors(&mut self) -> FramingErrors {
+        return core::mem::take(&mut self.errors);
+    }
+
+    pub fn push_data(&mut self, data: &[u8]) {
+        for byte in data {
+            if *byte != DELIMITER {
+                if self.overflowed {
+                    continue;
+                }
+                if self.buffer.len() == self.max_frame_len {
+                    self.buffer = Vec::new();
+                    self.overflowed = true;
+                    self.errors.oversized += 1;
+                    continue;
+                }
+                self.buffer.push(*byte);
+                continue;
+            }
+
+            self.overflowed = false;
+            // Back-to-back delimiters are just idle line
+            if !self.buffer.is_empty() {
+                self.finish_frame();
+            }
+        }
+    }
+
+    fn finish_frame(&mut self) {
+        let mut frame = core::mem::take(&mut self.buffer);
+        let len = match decode_in_place(&mut frame) {
+            Some(len) if len >= CRC_LEN => len,
+            _ => {
+                self.errors.malformed += 1;
+                return;
+            }
+        };
+        let crc = u32::from_be_bytes(frame[len - CRC_LEN..len].try_into().unwrap());
+        frame.truncate(len - CRC_LEN);
+        if crc != crc32(&frame) {
+            self.errors.bad_crc += 1;
+            return;
+        }
+        self.packets.push_back(frame);
+    }
+}
+
+impl Iterator for CobsPacketAssembler {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.packets.pop_front();
+    }
+}
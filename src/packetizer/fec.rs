@@ -0,0 +1,327 @@
+//! Forward error correction for broadcast messages.
+//!
+//! Retransmission doesn't scale to many listeners, so instead the sender adds
+//! repair chunks up front. A message is cut into equal-sized data shards and
+//! `parity_count` parity shards, where parity shard `g` is the XOR of every
+//! data shard whose index is `g` modulo `parity_count`. A message can be
+//! rebuilt as long as each group, counting its parity shard, loses at most
+//! one chunk. Interleaving the groups means that covers any burst of up to
+//! `parity_count` consecutive lost data chunks.
+//!
+//! Every chunk repeats the message header, so any subset is enough to start
+//! reassembly. Like the reliable mode's frames, chunks must only travel
+//! sealed, and start with a kind byte that tells them apart from other
+//! sealed traffic:
+//!
+//! `[FEC_DATA][epoch u32][msg_seq u32][index u8][data count u8][parity count u8][length u16][shard]`
+//!
+//! The epoch is picked at random on boot, as for `TolerantPacketDisassembler`,
+//! so listeners forget the messages they've completed when the sender restarts
+//! its sequence numbers.
+//!
+//! The redundancy is chosen per message, so the caller can spend more air
+//! time on messages that matter and none on ones that are resent anyway.
+
+extern crate alloc;
+
+use super::seq_newer;
+use crate::binary_packets::PacketReader;
+use alloc::{collections::VecDeque, vec::Vec};
+use core::cmp::Ordering;
+
+pub use crate::transport::frame_kind::FEC_DATA;
+
+/// 1 byte for the kind, 4 each for the epoch and msg_seq, 1 each for the index
+/// and shard counts, 2 for the length
+pub const FEC_PACKET_OVERHEAD: usize = 14;
+
+/// Incomplete messages being reassembled at once. The oldest is dropped first.
+const MAX_INCOMPLETE_MESSAGES: usize = 4;
+/// Completed messages remembered so their leftover chunks are ignored
+const COMPLETED_HISTORY: usize = 8;
+
+/// Splits messages into data and parity chunks.
+///
+/// This is the transmitting end of `FecPacketAssembler`.
+pub struct FecPacketDisassembler<const MAX_CHUNK_SIZE: usize> {
+    epoch: u32,
+    msg_seq: u32,
+}
+impl<const MAX_CHUNK_SIZE: usize> FecPacketDisassembler<MAX_CHUNK_SIZE> {
+    const SHARD_MAX_LEN: usize = MAX_CHUNK_SIZE - FEC_PACKET_OVERHEAD;
+
+    /// Creates a disassembler for a new session.
+    ///
+    /// `epoch` should be picked at random on every boot, so listeners don't
+    /// mistake our new messages for ones they've already completed.
+    pub fn new(epoch: u32) -> Self {
+        if MAX_CHUNK_SIZE < FEC_PACKET_OVERHEAD + 1 {
+            panic!("Cannot instantiate disassembler. Chunk size too small.");
+        }
+        return Self { epoch, msg_seq: 0 };
+    }
+
+    /// Splits `packet`, adding `parity_count` repair chunks.
+    ///
+    /// Returns `None` if the packet is longer than 65535 bytes or needs more
+    /// than 255 chunks in total.
+    pub fn split_packet<'a>(
+        &mut self,
+        packet: &'a [u8],
+        parity_count: u8,
+    ) -> Option<FecPacketIterator<'a, MAX_CHUNK_SIZE>> {
+        let data_count = packet.len().div_ceil(Self::SHARD_MAX_LEN).max(1);
+        if packet.len() > u16::MAX as usize || data_count + parity_count as usize > u8::MAX as usize
+        {
+            return None;
+        }
+        self.msg_seq = self.msg_seq.wrapping_add(1);
+        return Some(FecPacketIterator {
+            epoch: self.epoch,
+            msg_seq: self.msg_seq,
+            index: 0,
+            data_count: data_count as u8,
+            parity_count,
+            // Shrink the shards so small messages aren't padded out to a full chunk
+            shard_len: packet.len().div_ceil(data_count).max(1),
+            data: packet,
+        });
+    }
+}
+
+/// Yields the data chunks of a message, followed by its parity chunks.
+///
+/// Meant to be created via `FecPacketDisassembler::split_packet`
+pub struct FecPacketIterator<'a, const MAX_CHUNK_SIZE: usize> {
+    epoch: u32,
+    msg_seq: u32,
+    index: u8,
+    data_count: u8,
+    parity_count: u8,
+    shard_len: usize,
+    data: &'a [u8],
+}
+impl<'a, const MAX_CHUNK_SIZE: usize> FecPacketIterator<'a, MAX_CHUNK_SIZE> {
+    /// XORs data shard `index` into `shard`, treating bytes past the end of
+    /// the message as zeros
+    fn xor_data_shard(&self, index: u8, shard: &mut [u8]) {
+        let start = (index as usize * self.shard_len).min(self.data.len());
+        let end = (start + self.shard_len).min(self.data.len());
+        for (byte, data_byte) in shard.iter_mut().zip(&self.data[start..end]) {
+            *byte ^= data_byte;
+        }
+    }
+
+    /// Writes the next chunk to `chunk`.
+    ///
+    /// Returns the number of bytes written, if any.
+    pub fn get_chunk(&mut self, chunk: &mut [u8; MAX_CHUNK_SIZE]) -> Option<usize> {
+        if self.index >= self.data_count + self.parity_count {
+            return None;
+        }
+        chunk[0] = FEC_DATA;
+        chunk[1..5].copy_from_slice(&self.epoch.to_be_bytes());
+        chunk[5..9].copy_from_slice(&self.msg_seq.to_be_bytes());
+        chunk[9] = self.index;
+        chunk[10] = self.data_count;
+        chunk[11] = self.parity_count;
+        chunk[12..14].copy_from_slice(&(self.data.len() as u16).to_be_bytes());
+
+        let shard = &mut chunk[FEC_PACKET_OVERHEAD..FEC_PACKET_OVERHEAD + self.shard_len];
+        shard.fill(0);
+        if self.index < self.data_count {
+            self.xor_data_shard(self.index, shard);
+        } else {
+            let group = self.index - self.data_count;
+            for data_index in (group..self.data_count).step_by(self.parity_count as usize) {
+                self.xor_data_shard(data_index, shard);
+            }
+        }
+
+        self.index += 1;
+        return Some(FEC_PACKET_OVERHEAD + self.shard_len);
+    }
+}
+
+struct IncomingMessage {
+    msg_seq: u32,
+    data_count: u8,
+    parity_count: u8,
+    length: u16,
+    shard_len: usize,
+    shards: Vec<Option<Vec<u8>>>,
+}
+impl IncomingMessage {
+    /// Rebuilds any data shard that is the only one missing from its group.
+    ///
+    /// Returns true once every data shard is present.
+    fn recover(&mut self) -> bool {
+        let data_count = self.data_count as usize;
+        for group in 0..self.parity_count as usize {
+            let parity_index = data_count + group;
+            let members = (group..data_count).step_by(self.parity_count as usize);
+            let mut missing = members.clone().filter(|i| self.shards[*i].is_none());
+            let lost = match (missing.next(), missing.next()) {
+                (Some(lost), None) => lost,
+                _ => continue,
+            };
+            let mut shard = match &self.shards[parity_index] {
+                Some(parity) => parity.clone(),
+                None => continue,
+            };
+            for member in members.filter(|i| *i != lost) {
+                let member = self.shards[member].as_ref().unwrap();
+                for (byte, member_byte) in shard.iter_mut().zip(member) {
+                    *byte ^= member_byte;
+                }
+            }
+            self.shards[lost] = Some(shard);
+        }
+        return self.shards[0..data_count].iter().all(|shard| shard.is_some());
+    }
+}
+
+/// Re-assembles messages sent by `FecPacketDisassembler`.
+///
+/// Chunks may arrive in any order, and a message is complete as soon as
+/// enough of them have arrived to rebuild it.
+pub struct FecPacketAssembler {
+    /// Session of the sender we're currently following, once we've heard from it
+    epoch: Option<u32>,
+    /// The session before that, whose stragglers are ignored
+    previous_epoch: Option<u32>,
+    incomplete: heapless::Vec<IncomingMessage, MAX_INCOMPLETE_MESSAGES>,
+    completed: heapless::Deque<u32, COMPLETED_HISTORY>,
+    packets: VecDeque<Vec<u8>>,
+}
+impl FecPacketAssembler {
+    pub fn new() -> Self {
+        return Self {
+            epoch: None,
+            previous_epoch: None,
+            incomplete: heapless::Vec::new(),
+            completed: heapless::Deque::new(),
+            packets: VecDeque::new(),
+        };
+    }
+
+    /// Adds a chunk to the message it belongs to.
+    ///
+    /// Returns true if the chunk is the first from a new session of a sender
+    /// we'd already heard from, meaning it restarted. Anything still being
+    /// reassembled from the old session is dropped.
+    pub fn push_data(&mut self, chunk: &[u8]) -> bool {
+        let mut reader = PacketReader::new(chunk);
+        if reader.read_u8() != Some(FEC_DATA) {
+            return false;
+        }
+        let (epoch, msg_seq, index, data_count, parity_count, length) = match (
+            reader.read_u32(),
+            reader.read_u32(),
+            reader.read_u8(),
+            reader.read_u8(),
+            reader.read_u8(),
+            reader.read_u16(),
+        ) {
+            (
+                Some(epoch),
+                Some(msg_seq),
+                Some(index),
+                Some(data_count),
+                Some(parity_count),
+                Some(length),
+            ) => (epoch, msg_seq, index, data_count, parity_count, length),
+            _ => return false,
+        };
+        let shard = reader.get_remainder();
+        if data_count == 0
+            || shard.is_empty()
+            || index as usize >= data_count as usize + parity_count as usize
+            || (length as usize).div_ceil(shard.len()).max(1) != data_count as usize
+        {
+            return false;
+        }
+
+        // A new epoch means the sender restarted and its sequence numbers did too
+        let mut restarted = false;
+        if self.epoch != Some(epoch) {
+            if self.previous_epoch == Some(epoch) {
+                return false;
+            }
+            restarted = self.epoch.is_some();
+            self.previous_epoch = self.epoch;
+            self.epoch = Some(epoch);
+            self.incomplete.clear();
+            self.completed.clear();
+        }
+        if self.completed.iter().any(|completed| *completed == msg_seq) {
+            return restarted;
+        }
+
+        let i = match self.incomplete.iter().position(|i| i.msg_seq == msg_seq) {
+            Some(i) => i,
+            None => {
+                if self.incomplete.is_full() {
+                    // Sequence numbers wrap, so compare them with serial arithmetic
+                    let oldest = self
+                        .incomplete
+                        .iter()
+                        .enumerate()
+                        .min_by(|(_, a), (_, b)| {
+                            if seq_newer(a.msg_seq, b.msg_seq) {
+                                return Ordering::Greater;
+                            }
+                            return Ordering::Less;
+                        })
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    self.incomplete.swap_remove(oldest);
+                }
+                let _ = self.incomplete.push(IncomingMessage {
+                    msg_seq,
+                    data_count,
+                    parity_count,
+                    length,
+                    shard_len: shard.len(),
+                    shards: alloc::vec![None; data_count as usize + parity_count as usize],
+                });
+                self.incomplete.len() - 1
+            }
+        };
+
+        let message = &mut self.incomplete[i];
+        if message.data_count != data_count
+            || message.parity_count != parity_count
+            || message.length != length
+            || message.shard_len != shard.len()
+            || message.shards[index as usize].is_some()
+        {
+            return restarted;
+        }
+        message.shards[index as usize] = Some(Vec::from(shard));
+        if !message.recover() {
+            return restarted;
+        }
+
+        let message = self.incomplete.swap_remove(i);
+        let mut packet = Vec::with_capacity(message.length as usize);
+        for shard in message.shards.into_iter().take(message.data_count as usize) {
+            packet.extend_from_slice(&shard.unwrap());
+        }
+        packet.truncate(message.length as usize);
+        self.packets.push_back(packet);
+
+        if self.completed.is_full() {
+            self.completed.pop_front();
+        }
+        let _ = self.completed.push_back(msg_seq);
+        return restarted;
+    }
+}
+
+impl Iterator for FecPacketAssembler {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.packets.pop_front();
+    }
+}
@@ -0,0 +1,671 @@
+extern crate alloc;
+extern crate core;
+
+mod cobs;
+mod fec;
+mod reliable;
+mod transfer;
+
+pub use cobs::{cobs_frame_len, encode_cobs_frame, CobsPacketAssembler, FramingErrors};
+pub use fec::{
+    FecPacketAssembler, FecPacketDisassembler, FecPacketIterator, FEC_DATA, FEC_PACKET_OVERHEAD,
+};
+pub use reliable::{
+    DeliveryResult, ReliablePacketAssembler, ReliablePacketDisassembler, ReliableSendError,
+    FEEDBACK_LEN, MAX_MESSAGE_CHUNKS, RELIABLE_ACK, RELIABLE_DATA, RELIABLE_NACK,
+    RELIABLE_PACKET_OVERHEAD,
+};
+pub use transfer::{
+    BulkReceiver, BulkSender, CompletedObject, ObjectSink, ObjectSource, StorageError,
+    TransferError, TransferStatus, OFFER_LEN, TRANSFER_ACK, TRANSFER_DATA, TRANSFER_FEEDBACK_LEN,
+    TRANSFER_NACK, TRANSFER_OFFER, TRANSFER_PACKET_OVERHEAD, TRANSFER_RESULT,
+};
+
+use crate::{
+    binary_packets::PacketReader,
+    hal::time::{Duration, Instant},
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::{cmp::min, ops::AddAssign};
+
+// 4 bytes for the epoch, 1 for the stream, 4 for msg_seq, 2 for chunk_seq, 2 for chunk_count
+pub const TOLERANT_PACKET_OVERHEAD: usize = 13;
+/// Most chunks a `TolerantPacketAssembler` will reassemble into one message
+pub const MAX_TOLERANT_CHUNKS: u16 = 512;
+/// Number of independent streams a sender can interleave messages on
+pub const MAX_STREAMS: u8 = 4;
+/// Stream used by `TolerantPacketDisassembler::split_packet`
+pub const DEFAULT_STREAM: StreamId = 0;
+
+/// Identifies a sequence of messages that is reassembled independently of
+/// the sender's other streams. Must be below `MAX_STREAMS`.
+pub type StreamId = u8;
+
+/// Incomplete messages reassembled at once per stream. The oldest is dropped first.
+const MAX_INCOMPLETE_MESSAGES: usize = 2;
+/// How far behind the newest message a chunk can be and still be accepted.
+/// Completed messages are remembered for as long, so duplicates are dropped.
+const REORDER_WINDOW: usize = 8;
+
+/// Bounds on what an assembler will hold for a single sender
+#[derive(Debug, Clone, Copy)]
+pub struct AssemblerLimits {
+    /// Messages longer than this are discarded as soon as that's known
+    pub max_message_size: usize,
+    /// Bytes of incomplete messages held at once. The oldest message is
+    /// discarded to make room.
+    pub buffer_budget: usize,
+    /// Incomplete messages are discarded once they're this old
+    pub message_timeout: Duration,
+}
+impl Default for AssemblerLimits {
+    fn default() -> Self {
+        return Self {
+            max_message_size: 4096,
+            buffer_budget: 8192,
+            message_timeout: Duration::secs(5),
+        };
+    }
+}
+
+/// Counts of incomplete messages an assembler gave up on
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiscardCounters {
+    /// Longer than `AssemblerLimits::max_message_size`
+    pub oversized: u32,
+    /// Evicted to stay within a memory budget
+    pub over_budget: u32,
+    /// Not completed within `AssemblerLimits::message_timeout`
+    pub expired: u32,
+    /// Still incomplete once a message `REORDER_WINDOW` newer arrived
+    pub overtaken: u32,
+}
+impl AddAssign for DiscardCounters {
+    fn add_assign(&mut self, other: Self) {
+        self.oversized += other.oversized;
+        self.over_budget += other.over_budget;
+        self.expired += other.expired;
+        self.overtaken += other.overtaken;
+    }
+}
+
+/// Disassembles a large packet into smaller ones.
+///
+/// Works with unreliable transports by adding sequencing
+/// information to each packet. This signals to the client
+/// that the packet should be dropped since it was not received
+/// in full.
+///
+/// Each stream has its own message sequence, so a small urgent message can
+/// be sent on one stream while a large one on another is still being
+/// chunked out. The chunks of both can be interleaved freely.
+///
+/// This is the transmitting end of `TolerantPacketAssembler`.
+pub struct TolerantPacketDisassembler<const MAX_CHUNK_SIZE: usize> {
+    epoch: u32,
+    msg_seqs: [u32; MAX_STREAMS as usize],
+    chunk_size: usize,
+}
+impl<const MAX_CHUNK_SIZE: usize> TolerantPacketDisassembler<MAX_CHUNK_SIZE> {
+    /// Creates a disassembler for a new session.
+    ///
+    /// `epoch` should be picked at random on every boot, so receivers can
+    /// tell a restarted sender from one replaying old sequence numbers.
+    pub fn new(epoch: u32) -> Self {
+        if MAX_CHUNK_SIZE < TOLERANT_PACKET_OVERHEAD + 1 {
+            panic!("Cannot instantiate disassembler. Chunk size too small.");
+        }
+        return Self {
+            epoch,
+            msg_seqs: [0; MAX_STREAMS as usize],
+            chunk_size: MAX_CHUNK_SIZE,
+        };
+    }
+
+    /// Limits the chunks produced by later calls to `split_packet`.
+    ///
+    /// The size is clamped to what fits in `MAX_CHUNK_SIZE`.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        if chunk_size < TOLERANT_PACKET_OVERHEAD + 1 {
+            panic!("Cannot set chunk size. Chunk size too small.");
+        }
+        self.chunk_size = min(chunk_size, MAX_CHUNK_SIZE);
+    }
+
+    /// Splits `packet` into chunks on `DEFAULT_STREAM`.
+    ///
+    /// Packets needing more than `MAX_TOLERANT_CHUNKS` chunks would be
+    /// dropped by the receiver, so they produce no chunks at all.
+    pub fn split_packet<'a>(
+        &mut self,
+        packet: &'a [u8],
+    ) -> TolerantPacketIterator<'a, MAX_CHUNK_SIZE> {
+        return self.split_stream_packet(DEFAULT_STREAM, packet);
+    }
+
+    /// Splits `packet` into chunks on `stream`.
+    ///
+    /// Panics if `stream` isn't below `MAX_STREAMS`.
+    pub fn split_stream_packet<'a>(
+        &mut self,
+        stream: StreamId,
+        packet: &'a [u8],
+    ) -> TolerantPacketIterator<'a, MAX_CHUNK_SIZE> {
+        if stream >= MAX_STREAMS {
+            panic!("Cannot split packet. Stream {stream} out of range.");
+        }
+        let msg_seq = &mut self.msg_seqs[stream as usize];
+        *msg_seq = msg_seq.wrapping_add(1);
+        let advance_count = self.chunk_size - TOLERANT_PACKET_OVERHEAD;
+        let chunk_count = packet.len().div_ceil(advance_count).max(1);
+        let chunk_count = if chunk_count > MAX_TOLERANT_CHUNKS as usize {
+            log::error!("Packet of {} bytes is too large to send", packet.len());
+            0
+        } else {
+            chunk_count as u16
+        };
+        return TolerantPacketIterator::<'a, MAX_CHUNK_SIZE> {
+            advance_count,
+            epoch: self.epoch,
+            stream,
+            msg_seq: *msg_seq,
+            chunk_seq: 0,
+            chunk_count,
+            data: packet,
+        };
+    }
+}
+
+/// Yields chunks of a packet.
+///
+/// Meant to be created via `TolerantPacketDisassembler::split_packet`
+pub struct TolerantPacketIterator<'a, const MAX_CHUNK_SIZE: usize> {
+    advance_count: usize,
+    epoch: u32,
+    stream: StreamId,
+    msg_seq: u32,
+    chunk_seq: u16,
+    chunk_count: u16,
+    data: &'a [u8],
+}
+impl<'a, const MAX_CHUNK_SIZE: usize> TolerantPacketIterator<'a, MAX_CHUNK_SIZE> {
+    /// Writes the next data chunk to `chunk`.
+    ///
+    /// Returns the number of bytes written, if any.
+    pub fn get_chunk(&mut self, chunk: &mut [u8; MAX_CHUNK_SIZE]) -> Option<usize> {
+        if self.chunk_seq >= self.chunk_count {
+            return None;
+        }
+        chunk[0..4].copy_from_slice(&self.epoch.to_be_bytes());
+        chunk[4] = self.stream;
+        chunk[5..9].copy_from_slice(&self.msg_seq.to_be_bytes());
+        chunk[9..11].copy_from_slice(&self.chunk_seq.to_be_bytes());
+        chunk[11..13].copy_from_slice(&self.chunk_count.to_be_bytes());
+
+        let start_cursor = self.chunk_seq as usize * self.advance_count;
+        let end_cursor = min(start_cursor + self.advance_count, self.data.len());
+        chunk[TOLERANT_PACKET_OVERHEAD..TOLERANT_PACKET_OVERHEAD + end_cursor - start_cursor]
+            .copy_from_slice(&self.data[start_cursor..end_cursor]);
+        self.chunk_seq += 1;
+        return Some(end_cursor - start_cursor + TOLERANT_PACKET_OVERHEAD);
+    }
+}
+
+/// Compares sequence numbers using serial number arithmetic (RFC 1982), so
+/// the comparison keeps working when the counter wraps.
+///
+/// Returns true if `a` comes after `b`.
+pub fn seq_newer(a: u32, b: u32) -> bool {
+    return a != b && a.wrapping_sub(b) < 1 << 31;
+}
+
+/// Chunks received so far for one message
+struct PartialMessage {
+    msg_seq: u32,
+    chunk_count: u16,
+    started: Instant,
+    /// One bit per chunk, set once it has arrived
+    received: Vec<u64>,
+    missing: u16,
+    /// Chunks in the order they arrived, so memory use tracks what's been received
+    chunks: Vec<(u16, Vec<u8>)>,
+    bytes: usize,
+}
+impl PartialMessage {
+    fn new(msg_seq: u32, chunk_count: u16, now: Instant) -> Self {
+        return Self {
+            msg_seq,
+            chunk_count,
+            started: now,
+            received: alloc::vec![0; (chunk_count as usize).div_ceil(64)],
+            missing: chunk_count,
+            chunks: Vec::new(),
+            bytes: 0,
+        };
+    }
+
+    /// Stores a chunk, ignoring it if it's already been seen
+    fn insert(&mut self, chunk_seq: u16, data: &[u8]) {
+        let (word, bit) = (chunk_seq as usize / 64, 1u64 << (chunk_seq % 64));
+        if self.received[word] & bit != 0 {
+            return;
+        }
+        self.received[word] |= bit;
+        self.missing -= 1;
+        self.bytes += data.len();
+        self.chunks.push((chunk_seq, Vec::from(data)));
+    }
+
+    fn into_packet(mut self) -> Vec<u8> {
+        self.chunks.sort_unstable_by_key(|(chunk_seq, _)| *chunk_seq);
+        let mut packet = Vec::with_capacity(self.bytes);
+        for (_, data) in self.chunks {
+            packet.extend_from_slice(&data);
+        }
+        return packet;
+    }
+}
+
+/// Reassembly state for one of a sender's streams
+struct StreamState {
+    /// Newest message seen on this stream, once it's been used
+    newest_msg_seq: Option<u32>,
+    partial: heapless::Vec<PartialMessage, MAX_INCOMPLETE_MESSAGES>,
+    /// Messages that were completed or discarded, whose late chunks are ignored
+    finished: heapless::Deque<u32, REORDER_WINDOW>,
+}
+impl StreamState {
+    const fn new() -> Self {
+        return Self {
+            newest_msg_seq: None,
+            partial: heapless::Vec::new(),
+            finished: heapless::Deque::new(),
+        };
+    }
+
+    /// True if `msg_seq` is too far behind the newest message to accept
+    fn is_stale(&self, msg_seq: u32) -> bool {
+        return match self.newest_msg_seq {
+            Some(newest_msg_seq) => {
+                !seq_newer(msg_seq, newest_msg_seq)
+                    && newest_msg_seq.wrapping_sub(msg_seq) >= REORDER_WINDOW as u32
+            }
+            None => false,
+        };
+    }
+
+    /// Drops an incomplete message, ignoring any of its chunks that arrive later
+    fn discard(&mut self, i: usize) {
+        let message = self.partial.swap_remove(i);
+        self.finish(message.msg_seq);
+    }
+
+    fn finish(&mut self, msg_seq: u32) {
+        if self.finished.is_full() {
+            self.finished.pop_front();
+        }
+        let _ = self.finished.push_back(msg_seq);
+    }
+}
+
+/// Re-assembles chunked packets into their original form.
+///
+/// Works with unreliable transports. Chunks may arrive out of
+/// order or more than once, and a message is only emitted once
+/// every one of its chunks has arrived. Messages with missing
+/// chunks are eventually dropped, since this does not request
+/// retransmission. Use `ReliablePacketAssembler` for messages
+/// that must arrive, or `FecPacketAssembler` for broadcasts
+/// that should survive a few lost chunks.
+///
+/// Each stream is reassembled on its own, so traffic on one
+/// stream never pushes out a message in progress on another.
+/// Memory limits still apply across all of them.
+///
+/// This is the receiving end of `TolerantPacketDisassembler`.
+pub struct TolerantPacketAssembler {
+    /// Session of the sender we're currently following, once we've heard from it
+    epoch: Option<u32>,
+    /// The session before that, whose stragglers are ignored
+    previous_epoch: Option<u32>,
+    streams: [StreamState; MAX_STREAMS as usize],
+    packets: VecDeque<Vec<u8>>,
+    limits: AssemblerLimits,
+    discarded: DiscardCounters,
+}
+impl TolerantPacketAssembler {
+    pub fn new() -> Self {
+        return Self::with_limits(AssemblerLimits::default());
+    }
+
+    pub fn with_limits(limits: AssemblerLimits) -> Self {
+        return Self {
+            epoch: None,
+            previous_epoch: None,
+            streams: [const { StreamState::new() }; MAX_STREAMS as usize],
+            packets: VecDeque::new(),
+            limits,
+            discarded: DiscardCounters::default(),
+        };
+    }
+
+    /// Applies new limits, starting with the next chunk or call to `expire`
+    pub fn set_limits(&mut self, limits: AssemblerLimits) {
+        self.limits = limits;
+    }
+
+    fn partial_messages(&self) -> impl Iterator<Item = &PartialMessage> {
+        return self.streams.iter().flat_map(|stream| stream.partial.iter());
+    }
+
+    /// Bytes held by incomplete messages
+    pub fn buffered_bytes(&self) -> usize {
+        return self.partial_messages().map(|message| message.bytes).sum();
+    }
+
+    /// When the oldest incomplete message was started, if there is one
+    pub fn oldest_partial(&self) -> Option<Instant> {
+        return self.partial_messages().map(|message| message.started).min();
+    }
+
+    /// Discards the oldest incomplete message, on any stream, to free memory.
+    ///
+    /// Returns false if there was nothing to discard.
+    pub fn evict_oldest(&mut self) -> bool {
+        let oldest = self
+            .streams
+            .iter()
+            .enumerate()
+            .flat_map(|(stream, state)| {
+                state
+                    .partial
+                    .iter()
+                    .enumerate()
+                    .map(move |(i, message)| (stream, i, message.started))
+            })
+            .min_by_key(|(_, _, started)| *started);
+        let (stream, i) = match oldest {
+            Some((stream, i, _)) => (stream, i),
+            None => return false,
+        };
+        self.streams[stream].discard(i);
+        self.discarded.over_budget += 1;
+        return true;
+    }
+
+    /// Discards incomplete messages older than the configured timeout
+    pub fn expire(&mut self, now: Instant) {
+        for stream in self.streams.iter_mut() {
+            let mut i = 0;
+            while i < stream.partial.len() {
+                if now >= stream.partial[i].started + self.limits.message_timeout {
+                    stream.discard(i);
+                    self.discarded.expired += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+    }
+
+    /// Returns the discard counters accumulated since the last call, and resets them
+    pub fn take_discarded(&mut self) -> DiscardCounters {
+        return core::mem::take(&mut self.discarded);
+    }
+
+    /// Adds a chunk to the message it belongs to.
+    ///
+    /// `now` should come from the same clock passed to `expire`. Returns true
+    /// if the chunk is the first from a new session of a sender we'd already
+    /// heard from, meaning it restarted. Anything still being reassembled from
+    /// the old session is dropped.
+    pub fn push_data(&mut self, chunk: &[u8], now: Instant) -> bool {
+        let mut packet_reader = PacketReader::new(chunk);
+        let (epoch, stream, msg_seq, chunk_seq, chunk_count) = match (
+            packet_reader.read_u32(),
+            packet_reader.read_u8(),
+            packet_reader.read_u32(),
+            packet_reader.read_u16(),
+            packet_reader.read_u16(),
+        ) {
+            (Some(epoch), Some(stream), Some(msg_seq), Some(chunk_seq), Some(chunk_count)) => {
+                (epoch, stream, msg_seq, chunk_seq, chunk_count)
+            }
+            _ => return false,
+        };
+        let data = packet_reader.get_remainder();
+        if stream >= MAX_STREAMS
+            || chunk_count == 0
+            || chunk_count > MAX_TOLERANT_CHUNKS
+            || chunk_seq >= chunk_count
+        {
+            return false;
+        }
+
+        // A new epoch means the sender restarted and its sequence numbers did too
+        let mut restarted = false;
+        if self.epoch != Some(epoch) {
+            if self.previous_epoch == Some(epoch) {
+                return false;
+            }
+            restarted = self.epoch.is_some();
+            self.previous_epoch = self.epoch;
+            self.epoch = Some(epoch);
+            self.streams = [const { StreamState::new() }; MAX_STREAMS as usize];
+        }
+
+        // Skip old and repeated messages
+        let state = &mut self.streams[stream as usize];
+        if state.is_stale(msg_seq) || state.finished.iter().any(|i| *i == msg_seq) {
+            return restarted;
+        }
+        let is_newest = match state.newest_msg_seq {
+            Some(newest_msg_seq) => seq_newer(msg_seq, newest_msg_seq),
+            None => true,
+        };
+        if is_newest {
+            state.newest_msg_seq = Some(msg_seq);
+            let mut i = 0;
+            while i < state.partial.len() {
+                if msg_seq.wrapping_sub(state.partial[i].msg_seq) >= REORDER_WINDOW as u32 {
+                    state.discard(i);
+                    self.discarded.overtaken += 1;
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        let i = match state.partial.iter().position(|i| i.msg_seq == msg_seq) {
+            Some(i) => i,
+            None => {
+                // Make room by giving up on the oldest incomplete message
+                if state.partial.is_full() {
+                    let newest_msg_seq = state.newest_msg_seq.unwrap();
+                    let oldest = state
+                        .partial
+                        .iter()
+                        .enumerate()
+                        .max_by_key(|(_, message)| newest_msg_seq.wrapping_sub(message.msg_seq))
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    state.discard(oldest);
+                    self.discarded.over_budget += 1;
+                }
+                let _ = state.partial.push(PartialMessage::new(msg_seq, chunk_count, now));
+                state.partial.len() - 1
+            }
+        };
+
+        let message = &mut state.partial[i];
+        if message.chunk_count != chunk_count {
+            return restarted;
+        }
+        if message.bytes + data.len() > self.limits.max_message_size {
+            state.discard(i);
+            self.discarded.oversized += 1;
+            return restarted;
+        }
+        message.insert(chunk_seq, data);
+
+        if message.missing == 0 {
+            // If all chunks arrived, go ahead and push the message onto the queue
+            let message = state.partial.swap_remove(i);
+            state.finish(message.msg_seq);
+            self.packets.push_back(message.into_packet());
+        } else {
+            // Stay within budget, giving up on older messages before this one
+            while self.buffered_bytes() > self.limits.buffer_budget && self.evict_oldest() {}
+        }
+        return restarted;
+    }
+}
+
+impl Iterator for TolerantPacketAssembler {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.packets.pop_front();
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ExpectedSize {
+    None,
+    Partial(u8),
+    Full(u16),
+    /// Throwing away the rest of a message we won't keep
+    Skip(u16),
+}
+
+/// Re-assembles chunked packets into their original form.
+///
+/// Assumes reliable ordered transport, since a single lost or corrupted
+/// length byte throws off every message after it. Use `CobsPacketAssembler`
+/// for links that can lose or corrupt bytes, such as a UART.
+///
+/// Does not make any assumptions whatsoever about chunk boundaries.
+/// Messages that exceed the limits are skipped over rather than
+/// buffered, so the stream stays in sync.
+pub struct PacketAssembler {
+    packets: VecDeque<Vec<u8>>,
+    expected_size: ExpectedSize,
+    buffer: Vec<u8>,
+    started: Instant,
+    limits: AssemblerLimits,
+    discarded: DiscardCounters,
+}
+impl PacketAssembler {
+    pub fn new() -> Self {
+        return Self::with_limits(AssemblerLimits::default());
+    }
+
+    pub fn with_limits(limits: AssemblerLimits) -> Self {
+        return Self {
+            packets: VecDeque::new(),
+            expected_size: ExpectedSize::None,
+            buffer: Vec::new(),
+            started: Instant::from_ticks(0),
+            limits,
+            discarded: DiscardCounters::default(),
+        };
+    }
+
+    /// Bytes held by the incomplete message and by completed ones not yet taken
+    pub fn buffered_bytes(&self) -> usize {
+        return self.buffer.len() + self.packets.iter().map(|packet| packet.len()).sum::<usize>();
+    }
+
+    /// Discards a message that has been incomplete for longer than the
+    /// configured timeout.
+    ///
+    /// The stream is assumed to start over with a new length prefix, which
+    /// holds when the sender gave up on the message, e.g. after a reset.
+    pub fn expire(&mut self, now: Instant) {
+        if matches!(self.expected_size, ExpectedSize::None) {
+            return;
+        }
+        if now >= self.started + self.limits.message_timeout {
+            self.expected_size = ExpectedSize::None;
+            self.buffer = Vec::new();
+            self.discarded.expired += 1;
+        }
+    }
+
+    /// Returns the discard counters accumulated since the last call, and resets them
+    pub fn take_discarded(&mut self) -> DiscardCounters {
+        return core::mem::take(&mut self.discarded);
+    }
+
+    /// Starts a message of `expected_size` bytes, or skips it if it won't fit
+    fn begin(&mut self, expected_size: u16) {
+        if expected_size as usize > self.limits.max_message_size {
+            self.expected_size = ExpectedSize::Skip(expected_size);
+            self.discarded.oversized += 1;
+        } else if self.buffered_bytes() + expected_size as usize > self.limits.buffer_budget {
+            self.expected_size = ExpectedSize::Skip(expected_size);
+            self.discarded.over_budget += 1;
+        } else if expected_size == 0 {
+            self.packets.push_back(Vec::new());
+            self.expected_size = ExpectedSize::None;
+        } else {
+            self.expected_size = ExpectedSize::Full(expected_size);
+        }
+    }
+
+    /// `now` should come from the same clock passed to `expire`
+    pub fn push_data(&mut self, mut chunk: &[u8], now: Instant) {
+        // Using a loop here because our stack isn't very big and recursion is a memory hog
+        loop {
+            if chunk.len() == 0 {
+                return;
+            }
+            match self.expected_size {
+                ExpectedSize::None => {
+                    self.started = now;
+                    if chunk.len() >= 2 {
+                        self.begin(u16::from_be_bytes([chunk[0], chunk[1]]));
+                        chunk = &chunk[2..];
+                        continue;
+                    } else {
+                        self.expected_size = ExpectedSize::Partial(chunk[0]);
+                    }
+                }
+                ExpectedSize::Partial(byte1) => {
+                    self.begin(u16::from_be_bytes([byte1, chunk[0]]));
+                    chunk = &chunk[1..];
+                    continue;
+                }
+                ExpectedSize::Skip(remaining) => {
+                    let skipped = min(remaining as usize, chunk.len());
+                    chunk = &chunk[skipped..];
+                    self.expected_size = if skipped == remaining as usize {
+                        ExpectedSize::None
+                    } else {
+                        ExpectedSize::Skip(remaining - skipped as u16)
+                    };
+                    continue;
+                }
+                ExpectedSize::Full(expected_size) => {
+                    let needed = expected_size as usize - self.buffer.len();
+                    if chunk.len() >= needed {
+                        self.buffer.extend_from_slice(&chunk[0..needed]);
+                        self.packets.push_back(core::mem::take(&mut self.buffer));
+                        self.expected_size = ExpectedSize::None;
+                        chunk = &chunk[needed..];
+                        continue;
+                    } else {
+                        self.buffer.extend_from_slice(chunk);
+                    }
+                }
+            }
+            break;
+        }
+    }
+}
+
+impl Iterator for PacketAssembler {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.packets.pop_front();
+    }
+}
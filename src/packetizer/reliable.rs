@@ -0,0 +1,480 @@
+//! Selective-repeat ARQ for messages that must arrive or definitively fail.
+//!
+//! The sender numbers every chunk of a message and keeps it until the
+//! receiver acknowledges it. The receiver answers with bitmaps: an ACK lists
+//! every chunk it holds, and a NACK lists chunks it noticed were skipped so
+//! they can be resent without waiting for a timer. Chunks that are neither
+//! acknowledged nor requested are resent when their timer runs out, and the
+//! message fails once any chunk has been sent `MAX_ATTEMPTS` times.
+//!
+//! This is meant for a single peer. ACKs carry no receiver address, so
+//! broadcasting reliable messages to several nodes would mix up their
+//! feedback.
+//!
+//! Every frame carries the sender's epoch, picked at random on boot as for
+//! `TolerantPacketDisassembler`. Sequence numbers start over when the sender
+//! restarts, so the receiver forgets which messages it has completed when the
+//! epoch changes, and the sender ignores feedback meant for an earlier epoch.
+//!
+//! Frames from this module carry no authentication of their own, so they
+//! must only travel sealed, via `PacketManager::send_datagram`. They start
+//! with a kind byte that tells them apart from other sealed traffic:
+//!
+//! - data: `[RELIABLE_DATA][epoch u32][msg_seq u32][chunk index u8][chunk count u8][data]`
+//! - ACK/NACK: `[RELIABLE_ACK | RELIABLE_NACK][epoch u32][msg_seq u32][bitmap u64]`
+
+extern crate alloc;
+
+use crate::{
+    binary_packets::PacketReader,
+    hal::time::{Duration, Instant},
+};
+use alloc::{collections::VecDeque, vec::Vec};
+use thiserror::Error;
+
+pub use crate::transport::frame_kind::{RELIABLE_ACK, RELIABLE_DATA, RELIABLE_NACK};
+
+/// 1 byte for the kind, 4 for the epoch, 4 for msg_seq, 1 for the chunk index
+/// and 1 for the chunk count
+pub const RELIABLE_PACKET_OVERHEAD: usize = 11;
+/// Length of an ACK or NACK frame
+pub const FEEDBACK_LEN: usize = 1 + 4 + 4 + 8;
+/// Chunks in a single message, limited by the width of the bitmaps
+pub const MAX_MESSAGE_CHUNKS: usize = 64;
+
+/// Chunks that can be sent but not yet acknowledged, across all messages
+const SEND_WINDOW: u32 = 32;
+/// New chunks of a message received between ACKs. Well below `SEND_WINDOW`
+/// so a long message never stalls the sender waiting for one.
+const ACK_INTERVAL: u32 = 8;
+/// Messages waiting for delivery at once
+const MAX_PENDING_MESSAGES: usize = 8;
+const RETRANSMIT_TIMEOUT: Duration = Duration::millis(200);
+/// Transmissions of a single chunk before its message is given up on
+const MAX_ATTEMPTS: u8 = 5;
+
+/// Incomplete messages being reassembled at once
+const MAX_INCOMPLETE_MESSAGES: usize = 4;
+/// How long an incomplete message is kept without hearing a new chunk
+const INCOMPLETE_MESSAGE_TIMEOUT: Duration = Duration::secs(5);
+/// Completed messages remembered so their retransmissions are ACKed rather
+/// than delivered again. Must exceed `MAX_PENDING_MESSAGES`.
+const COMPLETED_HISTORY: usize = 16;
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ReliableSendError {
+    #[error("Message needs more than {MAX_MESSAGE_CHUNKS} chunks")]
+    TooLarge,
+    #[error("Too many messages are already waiting for delivery")]
+    Busy,
+}
+
+/// Final outcome of a reliable message, passed to the delivery callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryResult {
+    /// Every chunk was acknowledged
+    Delivered,
+    /// A chunk went unacknowledged after `MAX_ATTEMPTS` transmissions
+    TimedOut,
+}
+
+/// Bitmap with a bit set for each of the first `count` chunks
+fn all_chunks(count: u8) -> u64 {
+    if count as usize >= MAX_MESSAGE_CHUNKS {
+        return u64::MAX;
+    }
+    return (1u64 << count) - 1;
+}
+
+fn feedback_frame(kind: u8, epoch: u32, msg_seq: u32, bitmap: u64) -> [u8; FEEDBACK_LEN] {
+    let mut frame = [0u8; FEEDBACK_LEN];
+    frame[0] = kind;
+    frame[1..5].copy_from_slice(&epoch.to_be_bytes());
+    frame[5..9].copy_from_slice(&msg_seq.to_be_bytes());
+    frame[9..].copy_from_slice(&bitmap.to_be_bytes());
+    return frame;
+}
+
+struct OutgoingMessage {
+    msg_seq: u32,
+    data: Vec<u8>,
+    chunk_count: u8,
+    chunk_data_len: usize,
+    /// Chunks transmitted at least once
+    sent: u64,
+    acked: u64,
+    /// Chunks the receiver asked for again
+    requested: u64,
+    last_sent: Vec<Instant>,
+    attempts: Vec<u8>,
+}
+impl OutgoingMessage {
+    fn in_flight(&self) -> u32 {
+        return (self.sent & !self.acked).count_ones();
+    }
+
+    fn write_chunk(&self, epoch: u32, index: u8, chunk: &mut [u8]) -> usize {
+        let start = index as usize * self.chunk_data_len;
+        let end = (start + self.chunk_data_len).min(self.data.len());
+        chunk[0] = RELIABLE_DATA;
+        chunk[1..5].copy_from_slice(&epoch.to_be_bytes());
+        chunk[5..9].copy_from_slice(&self.msg_seq.to_be_bytes());
+        chunk[9] = index;
+        chunk[10] = self.chunk_count;
+        chunk[RELIABLE_PACKET_OVERHEAD..RELIABLE_PACKET_OVERHEAD + end - start]
+            .copy_from_slice(&self.data[start..end]);
+        return RELIABLE_PACKET_OVERHEAD + end - start;
+    }
+}
+
+/// Splits messages into chunks and retransmits them until acknowledged.
+///
+/// This is the transmitting end of `ReliablePacketAssembler`. Nothing is
+/// sent directly: call `poll_chunk` regularly and transmit whatever it
+/// writes, and hand every ACK or NACK from the peer to `handle_feedback`.
+pub struct ReliablePacketDisassembler<const MAX_CHUNK_SIZE: usize> {
+    epoch: u32,
+    msg_seq: u32,
+    pending: VecDeque<OutgoingMessage>,
+}
+impl<const MAX_CHUNK_SIZE: usize> ReliablePacketDisassembler<MAX_CHUNK_SIZE> {
+    const CHUNK_DATA_LEN: usize = MAX_CHUNK_SIZE - RELIABLE_PACKET_OVERHEAD;
+
+    /// Creates a disassembler for a new session.
+    ///
+    /// `epoch` should be picked at random on every boot, so the receiver
+    /// doesn't mistake our new messages for ones it has already completed.
+    pub fn new(epoch: u32) -> Self {
+        if MAX_CHUNK_SIZE < RELIABLE_PACKET_OVERHEAD + 1 {
+            panic!("Cannot instantiate disassembler. Chunk size too small.");
+        }
+        return Self {
+            epoch,
+            msg_seq: 0,
+            pending: VecDeque::new(),
+        };
+    }
+
+    /// Queues `packet` for delivery.
+    ///
+    /// Returns the sequence number the delivery callback will report it under.
+    pub fn send(&mut self, packet: &[u8], now: Instant) -> Result<u32, ReliableSendError> {
+        let chunk_count = packet.len().div_ceil(Self::CHUNK_DATA_LEN).max(1);
+        if chunk_count > MAX_MESSAGE_CHUNKS {
+            return Err(ReliableSendError::TooLarge);
+        }
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            return Err(ReliableSendError::Busy);
+        }
+
+        self.msg_seq = self.msg_seq.wrapping_add(1);
+        self.pending.push_back(OutgoingMessage {
+            msg_seq: self.msg_seq,
+            data: Vec::from(packet),
+            chunk_count: chunk_count as u8,
+            chunk_data_len: Self::CHUNK_DATA_LEN,
+            sent: 0,
+            acked: 0,
+            requested: 0,
+            last_sent: alloc::vec![now; chunk_count],
+            attempts: alloc::vec![0; chunk_count],
+        });
+        return Ok(self.msg_seq);
+    }
+
+    /// True if no messages are waiting for delivery
+    pub fn is_idle(&self) -> bool {
+        return self.pending.is_empty();
+    }
+
+    /// Writes the next chunk that should be transmitted to `chunk`.
+    ///
+    /// Requested and timed-out chunks go first, then new chunks as long as
+    /// the send window has room. Messages that run out of attempts are
+    /// dropped and reported to `on_delivery`. Returns the number of bytes
+    /// written, if any.
+    pub fn poll_chunk(
+        &mut self,
+        now: Instant,
+        chunk: &mut [u8; MAX_CHUNK_SIZE],
+        on_delivery: &mut impl FnMut(u32, DeliveryResult),
+    ) -> Option<usize> {
+        // Retransmissions don't grow the number of chunks in flight
+        let mut i = 0;
+        while i < self.pending.len() {
+            let message = &mut self.pending[i];
+            let unacked = message.sent & !message.acked;
+            let due = (0..message.chunk_count).find(|index| {
+                let bit = 1u64 << index;
+                return unacked & bit != 0
+                    && (message.requested & bit != 0
+                        || now >= message.last_sent[*index as usize] + RETRANSMIT_TIMEOUT);
+            });
+            let index = match due {
+                Some(index) => index,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            if message.attempts[index as usize] >= MAX_ATTEMPTS {
+                let msg_seq = message.msg_seq;
+                self.pending.remove(i);
+                on_delivery(msg_seq, DeliveryResult::TimedOut);
+                continue;
+            }
+            message.requested &= !(1u64 << index);
+            message.attempts[index as usize] += 1;
+            message.last_sent[index as usize] = now;
+            return Some(message.write_chunk(self.epoch, index, chunk));
+        }
+
+        let in_flight: u32 = self.pending.iter().map(|message| message.in_flight()).sum();
+        if in_flight >= SEND_WINDOW {
+            return None;
+        }
+        for message in self.pending.iter_mut() {
+            let unsent = all_chunks(message.chunk_count) & !message.sent;
+            if unsent == 0 {
+                continue;
+            }
+            let index = unsent.trailing_zeros() as u8;
+            message.sent |= 1u64 << index;
+            message.attempts[index as usize] = 1;
+            message.last_sent[index as usize] = now;
+            return Some(message.write_chunk(self.epoch, index, chunk));
+        }
+        return None;
+    }
+
+    /// Processes an ACK or NACK frame from the receiver.
+    ///
+    /// Fully acknowledged messages are reported to `on_delivery`.
+    pub fn handle_feedback(
+        &mut self,
+        frame: &[u8],
+        on_delivery: &mut impl FnMut(u32, DeliveryResult),
+    ) {
+        let mut reader = PacketReader::new(frame);
+        let (kind, epoch, msg_seq, bitmap) = match (
+            reader.read_u8(),
+            reader.read_u32(),
+            reader.read_u32(),
+            reader.read_u64(),
+        ) {
+            (Some(kind), Some(epoch), Some(msg_seq), Some(bitmap)) => {
+                (kind, epoch, msg_seq, bitmap)
+            }
+            _ => return,
+        };
+        // Feedback on messages we sent before restarting
+        if epoch != self.epoch {
+            return;
+        }
+        let i = match self.pending.iter().position(|i| i.msg_seq == msg_seq) {
+            Some(i) => i,
+            None => return,
+        };
+        let message = &mut self.pending[i];
+        let bitmap = bitmap & message.sent;
+
+        match kind {
+            RELIABLE_ACK => {
+                message.acked |= bitmap;
+                message.requested &= !bitmap;
+                if message.acked == all_chunks(message.chunk_count) {
+                    self.pending.remove(i);
+                    on_delivery(msg_seq, DeliveryResult::Delivered);
+                }
+            }
+            RELIABLE_NACK => message.requested |= bitmap & !message.acked,
+            _ => {}
+        }
+    }
+}
+
+struct IncomingMessage {
+    msg_seq: u32,
+    chunk_count: u8,
+    received: u64,
+    /// Chunks already requested again, so each gap is only NACKed once
+    nacked: u64,
+    /// New chunks received since the last ACK
+    since_ack: u32,
+    chunks: Vec<Vec<u8>>,
+    last_heard: Instant,
+}
+
+/// Re-assembles messages sent by `ReliablePacketDisassembler`.
+///
+/// Chunks may arrive in any order. Feedback for the sender is queued as it
+/// becomes due and must be sent back with `next_feedback`.
+pub struct ReliablePacketAssembler {
+    /// Session of the sender we're currently following, once we've heard from it
+    epoch: Option<u32>,
+    /// The session before that, whose stragglers are ignored
+    previous_epoch: Option<u32>,
+    incomplete: heapless::Vec<IncomingMessage, MAX_INCOMPLETE_MESSAGES>,
+    completed: heapless::Deque<u32, COMPLETED_HISTORY>,
+    feedback: VecDeque<[u8; FEEDBACK_LEN]>,
+    packets: VecDeque<Vec<u8>>,
+}
+impl ReliablePacketAssembler {
+    pub fn new() -> Self {
+        return Self {
+            epoch: None,
+            previous_epoch: None,
+            incomplete: heapless::Vec::new(),
+            completed: heapless::Deque::new(),
+            feedback: VecDeque::new(),
+            packets: VecDeque::new(),
+        };
+    }
+
+    /// Adds a chunk to the message it belongs to.
+    ///
+    /// Returns true if the chunk is the first from a new session of a sender
+    /// we'd already heard from, meaning it restarted. Anything still being
+    /// reassembled from the old session is dropped.
+    pub fn push_data(&mut self, chunk: &[u8], now: Instant) -> bool {
+        let mut reader = PacketReader::new(chunk);
+        let (epoch, msg_seq, index, chunk_count) = match (
+            reader.read_u8(),
+            reader.read_u32(),
+            reader.read_u32(),
+            reader.read_u8(),
+            reader.read_u8(),
+        ) {
+            (Some(RELIABLE_DATA), Some(epoch), Some(msg_seq), Some(index), Some(chunk_count)) => {
+                (epoch, msg_seq, index, chunk_count)
+            }
+            _ => return false,
+        };
+        let data = reader.get_remainder();
+        if chunk_count == 0 || chunk_count as usize > MAX_MESSAGE_CHUNKS || index >= chunk_count {
+            return false;
+        }
+
+        // A new epoch means the sender restarted and its sequence numbers did too
+        let mut restarted = false;
+        if self.epoch != Some(epoch) {
+            if self.previous_epoch == Some(epoch) {
+                return false;
+            }
+            restarted = self.epoch.is_some();
+            self.previous_epoch = self.epoch;
+            self.epoch = Some(epoch);
+            self.incomplete.clear();
+            self.completed.clear();
+        }
+
+        // The sender missed our ACK, so repeat it instead of delivering twice
+        if self.completed.iter().any(|completed| *completed == msg_seq) {
+            self.feedback.push_back(feedback_frame(
+                RELIABLE_ACK,
+                epoch,
+                msg_seq,
+                all_chunks(chunk_count),
+            ));
+            return restarted;
+        }
+
+        self.incomplete
+            .retain(|message| now < message.last_heard + INCOMPLETE_MESSAGE_TIMEOUT);
+        let i = match self.incomplete.iter().position(|i| i.msg_seq == msg_seq) {
+            Some(i) => i,
+            None => {
+                if self.incomplete.is_full() {
+                    let oldest = self
+                        .incomplete
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, message)| message.last_heard)
+                        .map(|(i, _)| i)
+                        .unwrap();
+                    self.incomplete.swap_remove(oldest);
+                }
+                let _ = self.incomplete.push(IncomingMessage {
+                    msg_seq,
+                    chunk_count,
+                    received: 0,
+                    nacked: 0,
+                    since_ack: 0,
+                    chunks: alloc::vec![Vec::new(); chunk_count as usize],
+                    last_heard: now,
+                });
+                self.incomplete.len() - 1
+            }
+        };
+
+        let message = &mut self.incomplete[i];
+        let bit = 1u64 << index;
+        if message.chunk_count != chunk_count {
+            return restarted;
+        }
+        // A repeat means the sender is still waiting on our ACK, which may
+        // have been lost
+        if message.received & bit != 0 {
+            message.since_ack = 0;
+            self.feedback.push_back(feedback_frame(
+                RELIABLE_ACK,
+                epoch,
+                msg_seq,
+                message.received,
+            ));
+            return restarted;
+        }
+        message.received |= bit;
+        message.since_ack += 1;
+        message.last_heard = now;
+        message.chunks[index as usize] = Vec::from(data);
+
+        if message.received == all_chunks(chunk_count) {
+            let message = self.incomplete.swap_remove(i);
+            self.packets.push_back(message.chunks.concat());
+            if self.completed.is_full() {
+                self.completed.pop_front();
+            }
+            let _ = self.completed.push_back(msg_seq);
+            self.feedback.push_back(feedback_frame(
+                RELIABLE_ACK,
+                epoch,
+                msg_seq,
+                all_chunks(chunk_count),
+            ));
+            return restarted;
+        }
+
+        // Chunks are sent in order, so anything missing below this one was
+        // probably lost
+        let missing = (bit - 1) & !message.received & !message.nacked;
+        if missing != 0 || message.since_ack >= ACK_INTERVAL {
+            message.since_ack = 0;
+            self.feedback.push_back(feedback_frame(
+                RELIABLE_ACK,
+                epoch,
+                msg_seq,
+                message.received,
+            ));
+        }
+        if missing != 0 {
+            message.nacked |= missing;
+            self.feedback
+                .push_back(feedback_frame(RELIABLE_NACK, epoch, msg_seq, missing));
+        }
+        return restarted;
+    }
+
+    /// Takes the next ACK or NACK that should be sent back to the sender
+    pub fn next_feedback(&mut self) -> Option<[u8; FEEDBACK_LEN]> {
+        return self.feedback.pop_front();
+    }
+}
+
+impl Iterator for ReliablePacketAssembler {
+    type Item = Vec<u8>;
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.packets.pop_front();
+    }
+}
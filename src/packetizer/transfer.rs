@@ -0,0 +1,599 @@
+//! Point-to-point transfer of large objects, such as logs or firmware images.
+//!
+//! Objects are streamed from an `ObjectSource` and into an `ObjectSink`
+//! rather than held in RAM, and are addressed by 32-bit byte offsets, so
+//! they can be far larger than a single message. The sender first offers
+//! the object's length and SHA-256. The receiver answers with how much of
+//! that object its sink already holds, and the sender streams the rest in
+//! order, a window at a time. Whenever acknowledgements stop, the sender
+//! repeats the offer to find out where to pick up again. The receiver hashes
+//! each chunk back out of its sink as it's stored, and reports the result
+//! once the last byte is in.
+//!
+//! Objects can be far too large to hash in one go, so both ends hash a
+//! bounded amount per call instead. The ESP32's SHA peripheral can't be
+//! loaded with a saved state, so these hashes are computed in software.
+//!
+//! Sinks recognise an object by its digest, so a transfer interrupted by a
+//! reboot on either end picks up where it left off the next time the same
+//! object is offered.
+//!
+//! Like the reliable mode, this is meant for a single peer, and its frames
+//! must only travel sealed. They start with a kind byte that tells them
+//! apart from other sealed traffic:
+//!
+//! - offer: `[TRANSFER_OFFER][transfer_id u32][length u32][sha256]`
+//! - data: `[TRANSFER_DATA][transfer_id u32][offset u32][data]`
+//! - feedback: `[TRANSFER_ACK | TRANSFER_NACK | TRANSFER_RESULT][transfer_id u32][offset or result u32]`
+//!
+//! An ACK confirms everything before its offset is stored. A NACK does too,
+//! and also asks for everything after it again because a chunk went missing.
+
+extern crate alloc;
+
+use crate::{
+    binary_packets::PacketReader,
+    hal::time::{Duration, Instant},
+    hw_hmac::HASH_SIZE,
+};
+use alloc::collections::VecDeque;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+pub use crate::transport::frame_kind::{
+    TRANSFER_ACK, TRANSFER_DATA, TRANSFER_NACK, TRANSFER_OFFER, TRANSFER_RESULT,
+};
+
+/// 1 byte for the kind, 4 for the transfer ID and 4 for the offset
+pub const TRANSFER_PACKET_OVERHEAD: usize = 9;
+/// Length of an offer frame
+pub const OFFER_LEN: usize = 1 + 4 + 4 + HASH_SIZE;
+/// Length of an ACK, NACK or result frame
+pub const TRANSFER_FEEDBACK_LEN: usize = 1 + 4 + 4;
+
+/// Data chunks that can be sent past the last acknowledged offset
+const SEND_WINDOW: u32 = 16;
+/// Chunks received in order between acknowledgements
+const ACK_INTERVAL: u32 = 4;
+const RETRANSMIT_TIMEOUT: Duration = Duration::millis(200);
+/// Timeouts in a row, without any progress, before the sender gives up
+const MAX_ATTEMPTS: u8 = 5;
+/// Bytes read back from storage at a time while hashing
+const HASH_BLOCK_LEN: usize = 256;
+/// Most bytes hashed in a single call, so a large object doesn't stall the caller
+const HASH_STEP_LEN: u32 = 4096;
+
+const RESULT_VERIFIED: u32 = 0;
+const RESULT_DIGEST_MISMATCH: u32 = 1;
+const RESULT_STORAGE_FAILED: u32 = 2;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+#[error("Object storage failed")]
+pub struct StorageError;
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("The receiver's copy didn't match the object's SHA-256")]
+    DigestMismatch,
+    #[error("The receiver failed to store the object")]
+    RemoteStorage,
+    #[error("The receiver stopped responding")]
+    TimedOut,
+}
+
+/// Where a sender reads the object from
+pub trait ObjectSource {
+    /// Length of the object in bytes
+    fn len(&self) -> u32;
+    fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+    /// Fills `buf` with the bytes starting at `offset`.
+    ///
+    /// `buf` never reaches past the end of the object.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError>;
+}
+
+/// Where a receiver stores the object
+pub trait ObjectSink {
+    /// Prepares to store the object with this digest and length.
+    ///
+    /// Returns how many bytes of it, from the start, are already stored from
+    /// an earlier attempt, or 0 to start over.
+    fn open(&mut self, digest: &[u8; HASH_SIZE], len: u32) -> Result<u32, StorageError>;
+    /// Stores `data` at `offset`. Writes always continue where the last one left off.
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StorageError>;
+    /// Fills `buf` with stored bytes starting at `offset`, for verification
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError>;
+    /// Called once every byte is stored. `verified` says whether the stored
+    /// object matched its digest; if not, it shouldn't be resumed from.
+    fn finish(&mut self, digest: &[u8; HASH_SIZE], verified: bool) -> Result<(), StorageError>;
+}
+
+/// SHA-256 of an object that is read in a few blocks at a time
+struct ObjectHasher {
+    hasher: Sha256,
+    /// Bytes hashed so far
+    hashed: u32,
+}
+impl ObjectHasher {
+    fn new() -> Self {
+        return Self {
+            hasher: Sha256::new(),
+            hashed: 0,
+        };
+    }
+
+    /// Hashes bytes produced by `read` up to `end`, but at most
+    /// `HASH_STEP_LEN` of them
+    fn step(
+        &mut self,
+        end: u32,
+        mut read: impl FnMut(u32, &mut [u8]) -> Result<(), StorageError>,
+    ) -> Result<(), StorageError> {
+        let end = end.min(self.hashed.saturating_add(HASH_STEP_LEN));
+        let mut block = [0u8; HASH_BLOCK_LEN];
+        while self.hashed < end {
+            let block = &mut block[0..(end - self.hashed).min(HASH_BLOCK_LEN as u32) as usize];
+            read(self.hashed, block)?;
+            self.hasher.update(&block[..]);
+            self.hashed += block.len() as u32;
+        }
+        return Ok(());
+    }
+
+    fn finish(self) -> [u8; HASH_SIZE] {
+        return self.hasher.finalize().into();
+    }
+}
+
+fn feedback_frame(kind: u8, transfer_id: u32, value: u32) -> [u8; TRANSFER_FEEDBACK_LEN] {
+    let mut frame = [0u8; TRANSFER_FEEDBACK_LEN];
+    frame[0] = kind;
+    frame[1..5].copy_from_slice(&transfer_id.to_be_bytes());
+    frame[5..].copy_from_slice(&value.to_be_bytes());
+    return frame;
+}
+
+/// Progress of an outgoing transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferStatus {
+    /// Still sending. `acked` bytes are known to be stored by the receiver.
+    InProgress { acked: u32, len: u32 },
+    /// The receiver stored the whole object and verified its digest
+    Complete,
+    Failed(TransferError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SenderState {
+    /// Working out the digest to offer
+    Hashing,
+    /// Waiting for the receiver to say where to start
+    Offering,
+    Sending,
+    Done(Result<(), TransferError>),
+}
+
+/// Streams an object to a `BulkReceiver`.
+///
+/// Nothing is sent directly: call `poll_chunk` regularly and transmit
+/// whatever it writes, and hand every feedback frame from the peer to
+/// `handle_feedback`. A sender that failed or was dropped can be replaced
+/// with a new one for the same object, which resumes from whatever the
+/// receiver already holds.
+pub struct BulkSender<S: ObjectSource, const MAX_CHUNK_SIZE: usize> {
+    source: S,
+    transfer_id: u32,
+    len: u32,
+    hasher: Option<ObjectHasher>,
+    digest: [u8; HASH_SIZE],
+    state: SenderState,
+    /// Bytes the receiver confirmed it has stored
+    acked: u32,
+    next_offset: u32,
+    /// When to repeat the offer if the receiver stays quiet
+    deadline: Option<Instant>,
+    attempts: u8,
+}
+impl<S: ObjectSource, const MAX_CHUNK_SIZE: usize> BulkSender<S, MAX_CHUNK_SIZE> {
+    const CHUNK_DATA_LEN: u32 = (MAX_CHUNK_SIZE - TRANSFER_PACKET_OVERHEAD) as u32;
+
+    /// Prepares to offer the object in `source`.
+    ///
+    /// The object is hashed a step at a time by `poll_chunk` before it's
+    /// offered. `transfer_id` should be picked at random so the receiver can
+    /// tell this transfer's frames from those of an earlier one.
+    pub fn new(source: S, transfer_id: u32) -> Self {
+        if MAX_CHUNK_SIZE < OFFER_LEN {
+            panic!("Cannot instantiate sender. Chunk size too small.");
+        }
+        return Self {
+            len: source.len(),
+            source,
+            transfer_id,
+            hasher: Some(ObjectHasher::new()),
+            digest: [0u8; HASH_SIZE],
+            state: SenderState::Hashing,
+            acked: 0,
+            next_offset: 0,
+            deadline: None,
+            attempts: 0,
+        };
+    }
+
+    pub fn status(&self) -> TransferStatus {
+        return match self.state {
+            SenderState::Done(Ok(())) => TransferStatus::Complete,
+            SenderState::Done(Err(err)) => TransferStatus::Failed(err),
+            _ => TransferStatus::InProgress {
+                acked: self.acked,
+                len: self.len,
+            },
+        };
+    }
+
+    /// The object's SHA-256, once it has been hashed
+    pub fn digest(&self) -> Option<&[u8; HASH_SIZE]> {
+        if self.state == SenderState::Hashing {
+            return None;
+        }
+        return Some(&self.digest);
+    }
+
+    /// Gives back the source once the sender is no longer needed
+    pub fn into_source(self) -> S {
+        return self.source;
+    }
+
+    /// Writes the next frame that should be transmitted to `chunk`.
+    ///
+    /// Until the object is hashed, each call hashes a bit more of it and
+    /// sends nothing. Then it's the offer until the receiver answers, then
+    /// data up to a window past the last acknowledged offset. If the receiver
+    /// goes quiet, the offer is repeated to find out where to pick up again.
+    /// Returns the number of bytes written, if any.
+    pub fn poll_chunk(&mut self, now: Instant, chunk: &mut [u8; MAX_CHUNK_SIZE]) -> Option<usize> {
+        if let SenderState::Done(_) = self.state {
+            return None;
+        }
+        if let Some(hasher) = &mut self.hasher {
+            let source = &mut self.source;
+            if let Err(err) = hasher.step(self.len, |offset, buf| source.read(offset, buf)) {
+                self.hasher = None;
+                self.state = SenderState::Done(Err(err.into()));
+                return None;
+            }
+            if hasher.hashed < self.len {
+                return None;
+            }
+            self.digest = self.hasher.take().unwrap().finish();
+            self.state = SenderState::Offering;
+        }
+        if let Some(deadline) = self.deadline {
+            if now >= deadline {
+                self.deadline = None;
+                self.state = SenderState::Offering;
+                if self.attempts >= MAX_ATTEMPTS {
+                    self.state = SenderState::Done(Err(TransferError::TimedOut));
+                    return None;
+                }
+            }
+        }
+
+        match self.state {
+            SenderState::Offering => {
+                if self.deadline.is_some() {
+                    return None;
+                }
+                self.attempts += 1;
+                self.deadline = Some(now + RETRANSMIT_TIMEOUT);
+                chunk[0] = TRANSFER_OFFER;
+                chunk[1..5].copy_from_slice(&self.transfer_id.to_be_bytes());
+                chunk[5..9].copy_from_slice(&self.len.to_be_bytes());
+                chunk[9..OFFER_LEN].copy_from_slice(&self.digest);
+                return Some(OFFER_LEN);
+            }
+            SenderState::Sending => {
+                let window_end = self
+                    .acked
+                    .saturating_add(SEND_WINDOW * Self::CHUNK_DATA_LEN)
+                    .min(self.len);
+                if self.next_offset >= window_end {
+                    // Nothing left to send until the receiver answers, which
+                    // it might not if the result or an ACK got lost
+                    if self.deadline.is_none() {
+                        self.deadline = Some(now + RETRANSMIT_TIMEOUT);
+                    }
+                    return None;
+                }
+                let data_len = (window_end - self.next_offset).min(Self::CHUNK_DATA_LEN) as usize;
+                let data = &mut chunk[TRANSFER_PACKET_OVERHEAD..TRANSFER_PACKET_OVERHEAD + data_len];
+                if let Err(err) = self.source.read(self.next_offset, data) {
+                    self.state = SenderState::Done(Err(err.into()));
+                    return None;
+                }
+                chunk[0] = TRANSFER_DATA;
+                chunk[1..5].copy_from_slice(&self.transfer_id.to_be_bytes());
+                chunk[5..9].copy_from_slice(&self.next_offset.to_be_bytes());
+                self.next_offset += data_len as u32;
+                if self.deadline.is_none() {
+                    self.deadline = Some(now + RETRANSMIT_TIMEOUT);
+                }
+                return Some(TRANSFER_PACKET_OVERHEAD + data_len);
+            }
+            SenderState::Hashing | SenderState::Done(_) => return None,
+        }
+    }
+
+    /// Processes an ACK, NACK or result frame from the receiver
+    pub fn handle_feedback(&mut self, frame: &[u8], now: Instant) {
+        let mut reader = PacketReader::new(frame);
+        let (kind, transfer_id, value) =
+            match (reader.read_u8(), reader.read_u32(), reader.read_u32()) {
+                (Some(kind), Some(transfer_id), Some(value)) => (kind, transfer_id, value),
+                _ => return,
+            };
+        if transfer_id != self.transfer_id {
+            return;
+        }
+        if let SenderState::Hashing | SenderState::Done(_) = self.state {
+            return;
+        }
+
+        match kind {
+            TRANSFER_ACK | TRANSFER_NACK => {
+                let offset = value.min(self.len);
+                if self.state == SenderState::Offering {
+                    // The receiver says where to resume, which may be behind
+                    // what it acknowledged before if it lost data
+                    self.state = SenderState::Sending;
+                    self.acked = offset;
+                    self.next_offset = offset;
+                    self.deadline = None;
+                    return;
+                }
+                if offset > self.acked {
+                    self.acked = offset;
+                    self.attempts = 0;
+                    self.deadline = Some(now + RETRANSMIT_TIMEOUT);
+                }
+                self.next_offset = match kind {
+                    TRANSFER_NACK => offset,
+                    _ => self.next_offset.max(offset),
+                };
+            }
+            TRANSFER_RESULT => {
+                self.state = SenderState::Done(match value {
+                    RESULT_VERIFIED => {
+                        self.acked = self.len;
+                        Ok(())
+                    }
+                    RESULT_DIGEST_MISMATCH => Err(TransferError::DigestMismatch),
+                    _ => Err(TransferError::RemoteStorage),
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+struct IncomingObject {
+    transfer_id: u32,
+    digest: [u8; HASH_SIZE],
+    len: u32,
+    /// Bytes stored so far, all of them in order
+    received: u32,
+    /// Hash of what's been read back out of the sink, which trails `received`
+    /// after resuming from an earlier attempt
+    hasher: ObjectHasher,
+    chunks_since_ack: u32,
+    /// Set once the gap at `received` has been reported
+    nacked: bool,
+}
+
+/// An object a `BulkReceiver` finished receiving
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompletedObject {
+    pub digest: [u8; HASH_SIZE],
+    pub len: u32,
+    /// Whether the stored object matched its digest
+    pub verified: bool,
+}
+
+/// Receives objects sent by `BulkSender` into an `ObjectSink`.
+///
+/// Feedback for the sender is queued as it becomes due and must be sent back
+/// with `next_feedback`. Finished objects, verified or not, are yielded by
+/// `next_completed`. `poll` must be called regularly so that the part of an
+/// object stored by an earlier attempt gets hashed.
+pub struct BulkReceiver<S: ObjectSink> {
+    sink: S,
+    current: Option<IncomingObject>,
+    /// Result of the last finished transfer, repeated if its sender missed it
+    last_result: Option<(u32, u32)>,
+    feedback: VecDeque<[u8; TRANSFER_FEEDBACK_LEN]>,
+    completed: VecDeque<CompletedObject>,
+}
+impl<S: ObjectSink> BulkReceiver<S> {
+    pub fn new(sink: S) -> Self {
+        return Self {
+            sink,
+            current: None,
+            last_result: None,
+            feedback: VecDeque::new(),
+            completed: VecDeque::new(),
+        };
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        return &mut self.sink;
+    }
+
+    /// Handles an offer or data frame from the sender
+    pub fn push_data(&mut self, frame: &[u8]) {
+        let mut reader = PacketReader::new(frame);
+        let (kind, transfer_id, value) =
+            match (reader.read_u8(), reader.read_u32(), reader.read_u32()) {
+                (Some(kind), Some(transfer_id), Some(value)) => (kind, transfer_id, value),
+                _ => return,
+            };
+        let rest = reader.get_remainder();
+        match kind {
+            TRANSFER_OFFER => {
+                let digest: [u8; HASH_SIZE] = match rest.try_into() {
+                    Ok(digest) => digest,
+                    Err(_) => return,
+                };
+                self.handle_offer(transfer_id, value, digest);
+            }
+            TRANSFER_DATA => self.handle_data(transfer_id, value, rest),
+            _ => {}
+        }
+    }
+
+    /// Hashes a step more of an object resumed from an earlier attempt.
+    ///
+    /// Chunks are hashed as they're stored, so this only has work to do
+    /// while catching up on what the sink already held.
+    pub fn poll(&mut self) {
+        self.advance_hash();
+    }
+
+    fn handle_offer(&mut self, transfer_id: u32, len: u32, digest: [u8; HASH_SIZE]) {
+        // The sender missed our result, so repeat it
+        if let Some((finished_id, result)) = self.last_result {
+            if finished_id == transfer_id {
+                self.feedback
+                    .push_back(feedback_frame(TRANSFER_RESULT, transfer_id, result));
+                return;
+            }
+        }
+
+        // A repeated offer for the transfer in progress means the sender lost
+        // track, so tell it again where we are
+        let resume = match self.current {
+            Some(ref mut current) if current.transfer_id == transfer_id => {
+                current.chunks_since_ack = 0;
+                current.nacked = false;
+                current.received
+            }
+            _ => {
+                let resume = match self.sink.open(&digest, len) {
+                    Ok(resume) => resume.min(len),
+                    Err(_) => {
+                        self.current = None;
+                        self.finish_transfer(transfer_id, RESULT_STORAGE_FAILED);
+                        return;
+                    }
+                };
+                self.current = Some(IncomingObject {
+                    transfer_id,
+                    digest,
+                    len,
+                    received: resume,
+                    hasher: ObjectHasher::new(),
+                    chunks_since_ack: 0,
+                    nacked: false,
+                });
+                resume
+            }
+        };
+        self.feedback
+            .push_back(feedback_frame(TRANSFER_ACK, transfer_id, resume));
+        self.advance_hash();
+    }
+
+    fn handle_data(&mut self, transfer_id: u32, offset: u32, data: &[u8]) {
+        let current = match self.current {
+            Some(ref mut current) if current.transfer_id == transfer_id => current,
+            _ => return,
+        };
+        if offset != current.received || data.is_empty() {
+            // Only NACK the first chunk past a gap, since the rest of the
+            // window follows it
+            if offset > current.received && !current.nacked {
+                current.nacked = true;
+                self.feedback
+                    .push_back(feedback_frame(TRANSFER_NACK, transfer_id, current.received));
+            }
+            return;
+        }
+        if data.len() as u32 > current.len - current.received {
+            return;
+        }
+        if self.sink.write(offset, data).is_err() {
+            self.current = None;
+            self.finish_transfer(transfer_id, RESULT_STORAGE_FAILED);
+            return;
+        }
+        current.received += data.len() as u32;
+        current.chunks_since_ack += 1;
+        current.nacked = false;
+
+        // The last chunk is answered with the result instead
+        if current.received < current.len && current.chunks_since_ack >= ACK_INTERVAL {
+            current.chunks_since_ack = 0;
+            self.feedback
+                .push_back(feedback_frame(TRANSFER_ACK, transfer_id, current.received));
+        }
+        self.advance_hash();
+    }
+
+    /// Hashes up to a step more of what's stored, reading it back from the
+    /// sink. Once the whole object is stored and hashed, reports the result
+    /// and hands it to the sink.
+    fn advance_hash(&mut self) {
+        let current = match self.current {
+            Some(ref mut current) => current,
+            None => return,
+        };
+        let sink = &mut self.sink;
+        let read = |offset, buf: &mut [u8]| sink.read(offset, buf);
+        if current.hasher.step(current.received, read).is_err() {
+            let transfer_id = current.transfer_id;
+            self.current = None;
+            self.finish_transfer(transfer_id, RESULT_STORAGE_FAILED);
+            return;
+        }
+        if current.hasher.hashed < current.len {
+            return;
+        }
+
+        let current = self.current.take().unwrap();
+        let verified = current.hasher.finish() == current.digest;
+        if self.sink.finish(&current.digest, verified).is_err() {
+            self.finish_transfer(current.transfer_id, RESULT_STORAGE_FAILED);
+            return;
+        }
+        self.completed.push_back(CompletedObject {
+            digest: current.digest,
+            len: current.len,
+            verified,
+        });
+        let result = if verified {
+            RESULT_VERIFIED
+        } else {
+            RESULT_DIGEST_MISMATCH
+        };
+        self.finish_transfer(current.transfer_id, result);
+    }
+
+    fn finish_transfer(&mut self, transfer_id: u32, result: u32) {
+        self.last_result = Some((transfer_id, result));
+        self.feedback
+            .push_back(feedback_frame(TRANSFER_RESULT, transfer_id, result));
+    }
+
+    /// Takes the next ACK or result that should be sent back to the sender
+    pub fn next_feedback(&mut self) -> Option<[u8; TRANSFER_FEEDBACK_LEN]> {
+        return self.feedback.pop_front();
+    }
+
+    /// Takes the next object that finished transferring
+    pub fn next_completed(&mut self) -> Option<CompletedObject> {
+        return self.completed.pop_front();
+    }
+}
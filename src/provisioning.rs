@@ -0,0 +1,242 @@
+//! Line-based serial console for installing the cluster key.
+//!
+//! Commands are terminated by `\n` (a preceding `\r` is ignored):
+//!
+//! - `key set <128 hex digits> [key id]`: installs or replaces the cluster key
+//! - `key status`: reports whether a key is installed, and its ID
+//! - `key rotate`: asks the application to rotate the cluster key
+//! - `key erase`: removes the key
+//! - `identity show`: prints this device's signing public key
+//! - `identity pin <64 hex digits>`: trusts commands signed by that Commander key
+//!
+//! Every command is answered with a line starting with `OK` or `ERR`.
+
+use crate::key_store::{ClusterKey, KeyStore, CLUSTER_KEY_SIZE, PUBLIC_KEY_SIZE};
+use core::fmt::Write as _;
+use embedded_io::{Read, ReadReady, Write};
+
+const MAX_LINE_LEN: usize = 16 + CLUSTER_KEY_SIZE * 2;
+
+/// Result of a console command that the application may need to act on
+#[derive(Debug, Clone)]
+pub enum ProvisioningEvent {
+    KeyInstalled(ClusterKey),
+    KeyErased,
+    RotateRequested,
+    CommanderPinned([u8; PUBLIC_KEY_SIZE]),
+}
+
+pub struct ProvisioningConsole {
+    line: heapless::Vec<u8, MAX_LINE_LEN>,
+    overflowed: bool,
+}
+impl ProvisioningConsole {
+    pub fn new() -> Self {
+        return Self {
+            line: heapless::Vec::new(),
+            overflowed: false,
+        };
+    }
+
+    /// Handles any bytes waiting on `serial` without blocking.
+    ///
+    /// Returns an event if the installed key changed.
+    pub fn poll<S: Read + ReadReady + Write>(
+        &mut self,
+        serial: &mut S,
+        key_store: &mut impl KeyStore,
+    ) -> Option<ProvisioningEvent> {
+        let mut event = None;
+        let mut buffer = [0u8; 32];
+        while let Ok(true) = serial.read_ready() {
+            let read = match serial.read(&mut buffer) {
+                Ok(read) => read,
+                Err(_) => break,
+            };
+            for byte in &buffer[0..read] {
+                if let Some(new_event) = self.push_byte(*byte, serial, key_store) {
+                    event = Some(new_event);
+                }
+            }
+        }
+        return event;
+    }
+
+    /// Feeds a single byte to the console, running the command if it completes a line
+    pub fn push_byte(
+        &mut self,
+        byte: u8,
+        serial: &mut impl Write,
+        key_store: &mut impl KeyStore,
+    ) -> Option<ProvisioningEvent> {
+        match byte {
+            b'\r' => return None,
+            b'\n' => {}
+            byte => {
+                if self.line.push(byte).is_err() {
+                    self.overflowed = true;
+                }
+                return None;
+            }
+        }
+
+        let event = if self.overflowed {
+            reply(serial, "ERR line too long");
+            None
+        } else {
+            self.run_command(serial, key_store)
+        };
+        self.line.clear();
+        self.overflowed = false;
+        return event;
+    }
+
+    fn run_command(
+        &mut self,
+        serial: &mut impl Write,
+        key_store: &mut impl KeyStore,
+    ) -> Option<ProvisioningEvent> {
+        let line = match core::str::from_utf8(&self.line) {
+            Ok(line) => line.trim(),
+            Err(_) => {
+                reply(serial, "ERR invalid utf-8");
+                return None;
+            }
+        };
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next(), words.next(), words.next()) {
+            (None, ..) => return None,
+            (Some("key"), Some("set"), Some(hex), id) => {
+                let id = match id.map(|id| id.parse::<u8>()) {
+                    Some(Ok(id)) => id,
+                    None => 0,
+                    Some(Err(_)) => {
+                        reply(serial, "ERR key id must be 0-255");
+                        return None;
+                    }
+                };
+                let key = match parse_key(hex, id) {
+                    Some(key) => key,
+                    None => {
+                        reply(serial, "ERR expected 128 hex digits");
+                        return None;
+                    }
+                };
+                return match key_store.store_cluster_key(&key) {
+                    Ok(()) => {
+                        reply(serial, "OK key installed");
+                        Some(ProvisioningEvent::KeyInstalled(key))
+                    }
+                    Err(_) => {
+                        reply(serial, "ERR failed to store key");
+                        None
+                    }
+                };
+            }
+            (Some("key"), Some("status"), None, None) => {
+                match key_store.load_cluster_key() {
+                    Ok(Some(key)) => {
+                        let mut message = heapless::String::<32>::new();
+                        write!(message, "OK provisioned id={}", key.id).ok();
+                        reply(serial, &message);
+                    }
+                    Ok(None) => reply(serial, "OK not provisioned"),
+                    Err(_) => reply(serial, "ERR key record unreadable"),
+                }
+                return None;
+            }
+            (Some("key"), Some("rotate"), None, None) => {
+                reply(serial, "OK rotating");
+                return Some(ProvisioningEvent::RotateRequested);
+            }
+            (Some("key"), Some("erase"), None, None) => {
+                return match key_store.erase_cluster_key() {
+                    Ok(()) => {
+                        reply(serial, "OK key erased");
+                        Some(ProvisioningEvent::KeyErased)
+                    }
+                    Err(_) => {
+                        reply(serial, "ERR failed to erase key");
+                        None
+                    }
+                };
+            }
+            (Some("identity"), Some("show"), None, None) => {
+                match key_store.load_identity() {
+                    Ok(Some(identity)) => {
+                        let mut message = heapless::String::<{ 3 + PUBLIC_KEY_SIZE * 2 }>::new();
+                        message.push_str("OK ").ok();
+                        for byte in identity.public_key() {
+                            write!(message, "{byte:02x}").ok();
+                        }
+                        reply(serial, &message);
+                    }
+                    Ok(None) => reply(serial, "ERR no identity yet"),
+                    Err(_) => reply(serial, "ERR identity record unreadable"),
+                }
+                return None;
+            }
+            (Some("identity"), Some("pin"), Some(hex), None) => {
+                let commander_key = match parse_hex::<PUBLIC_KEY_SIZE>(hex) {
+                    Some(commander_key) => commander_key,
+                    None => {
+                        reply(serial, "ERR expected 64 hex digits");
+                        return None;
+                    }
+                };
+                let mut identity = match key_store.load_identity() {
+                    Ok(Some(identity)) => identity,
+                    Ok(None) => {
+                        reply(serial, "ERR no identity yet");
+                        return None;
+                    }
+                    Err(_) => {
+                        reply(serial, "ERR identity record unreadable");
+                        return None;
+                    }
+                };
+                identity.commander_key = Some(commander_key);
+                return match key_store.store_identity(&identity) {
+                    Ok(()) => {
+                        reply(serial, "OK commander pinned");
+                        Some(ProvisioningEvent::CommanderPinned(commander_key))
+                    }
+                    Err(_) => {
+                        reply(serial, "ERR failed to store identity");
+                        None
+                    }
+                };
+            }
+            _ => {
+                reply(serial, "ERR unknown command");
+                return None;
+            }
+        }
+    }
+}
+
+fn reply(serial: &mut impl Write, message: &str) {
+    serial.write_all(message.as_bytes()).ok();
+    serial.write_all(b"\r\n").ok();
+    serial.flush().ok();
+}
+
+fn parse_key(hex: &str, id: u8) -> Option<ClusterKey> {
+    return Some(ClusterKey {
+        id,
+        secret: parse_hex::<CLUSTER_KEY_SIZE>(hex)?,
+    });
+}
+
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (i, pair) in hex.as_bytes().chunks(2).enumerate() {
+        let high = (pair[0] as char).to_digit(16)?;
+        let low = (pair[1] as char).to_digit(16)?;
+        bytes[i] = (high * 16 + low) as u8;
+    }
+    return Some(bytes);
+}
@@ -0,0 +1,63 @@
+use super::{MacAddress, ReceivedFrame, Transport};
+use crate::hal::esp_now::{EspNow, EspNowError, PeerInfo};
+
+impl<'a> Transport for EspNow<'a> {
+    type Error = EspNowError;
+
+    fn address(&self) -> MacAddress {
+        // ESP-NOW sends from the station interface, which uses the base MAC
+        #[cfg(feature = "esp32")]
+        return esp_hal::efuse::Efuse::get_mac_address();
+        #[cfg(not(feature = "esp32"))]
+        return EspNow::address(self);
+    }
+
+    fn send(&mut self, dst_address: &MacAddress, data: &[u8]) -> Result<(), Self::Error> {
+        // Only the broadcast address is registered up front, and unicast to an
+        // unregistered address fails. Peers added here are removed again so
+        // the peer list, which is limited to 20 entries, never fills up.
+        if self.peer_exists(dst_address) {
+            // Don't wait for the frame to go out. Dropping the waiter would
+            // spin until it had, and a full transmit queue is reported by
+            // whichever send finds it full.
+            core::mem::forget(EspNow::send(self, dst_address, data)?);
+            return Ok(());
+        }
+        // The temporary peer can't be removed while its frame is queued
+        self.add_peer(PeerInfo {
+            peer_address: *dst_address,
+            lmk: None,
+            channel: None,
+            encrypt: false,
+        })?;
+        let result = EspNow::send(self, dst_address, data).and_then(|waiter| waiter.wait());
+        self.remove_peer(dst_address)?;
+        return result;
+    }
+
+    fn receive(&mut self) -> Option<ReceivedFrame> {
+        let received = EspNow::receive(self)?;
+        let mut data = heapless::Vec::new();
+        data.extend_from_slice(&received.data[0..received.len as usize])
+            .ok()?;
+        return Some(ReceivedFrame {
+            src_address: received.info.src_address,
+            rssi: Some(received.info.rx_control.rssi as i8),
+            data,
+        });
+    }
+
+    fn is_busy(error: &Self::Error) -> bool {
+        #[cfg(feature = "esp32")]
+        return matches!(
+            error,
+            EspNowError::Error(esp_wifi::esp_now::Error::OutOfMemory)
+        );
+        // The stand-in delivers every frame as soon as it's sent
+        #[cfg(not(feature = "esp32"))]
+        {
+            let _ = error;
+            return false;
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Kind bytes that tell apart the traffic sharing a link.
+//!
+//! Every frame starts with a cleartext kind. Only pairing happens outside a
+//! cluster frame; everything else is sealed into one by `PacketManager`, and
+//! the first byte of the sealed payload says which protocol it belongs to.
+//! Both sets are allocated here so that no two protocols claim the same value.
+
+/// Sealed cluster traffic from `PacketManager`
+pub const CLUSTER: u8 = 0;
+/// Pairing handshake between a commander and a new node
+pub const PAIRING: u8 = 1;
+
+/// Sealed: chunk of a `CommPacket` stream
+pub const STREAM_CHUNK: u8 = 0;
+
+/// Sealed: chunk of a message sent by `ReliablePacketDisassembler`
+pub const RELIABLE_DATA: u8 = 1;
+pub const RELIABLE_ACK: u8 = 2;
+pub const RELIABLE_NACK: u8 = 3;
+
+/// Sealed: data or parity chunk sent by `FecPacketDisassembler`
+pub const FEC_DATA: u8 = 4;
+
+/// Sealed: object announcement from `BulkSender`
+pub const TRANSFER_OFFER: u8 = 5;
+pub const TRANSFER_DATA: u8 = 6;
+pub const TRANSFER_ACK: u8 = 7;
+pub const TRANSFER_NACK: u8 = 8;
+pub const TRANSFER_RESULT: u8 = 9;
+
+const LINK: [u8; 2] = [CLUSTER, PAIRING];
+/// Sealed kinds that `PacketManager` hands to the application as datagrams
+const DATAGRAMS: [u8; 9] = [
+    RELIABLE_DATA,
+    RELIABLE_ACK,
+    RELIABLE_NACK,
+    FEC_DATA,
+    TRANSFER_OFFER,
+    TRANSFER_DATA,
+    TRANSFER_ACK,
+    TRANSFER_NACK,
+    TRANSFER_RESULT,
+];
+
+/// Whether `kind` is a sealed kind carried as a datagram rather than a stream chunk
+pub fn is_datagram(kind: u8) -> bool {
+    return DATAGRAMS.contains(&kind);
+}
+
+const fn all_distinct(kinds: &[u8]) -> bool {
+    let mut i = 0;
+    while i < kinds.len() {
+        let mut j = i + 1;
+        while j < kinds.len() {
+            if kinds[i] == kinds[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    return true;
+}
+const _: () = assert!(all_distinct(&LINK), "Two frame kinds share a value");
+const _: () = {
+    let mut sealed = [STREAM_CHUNK; DATAGRAMS.len() + 1];
+    let mut i = 0;
+    while i < DATAGRAMS.len() {
+        sealed[i + 1] = DATAGRAMS[i];
+        i += 1;
+    }
+    assert!(all_distinct(&sealed), "Two sealed kinds share a value");
+};
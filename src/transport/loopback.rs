@@ -0,0 +1,92 @@
+extern crate alloc;
+
+use super::{MacAddress, ReceivedFrame, Transport, BROADCAST_ADDRESS, MAX_FRAME_LEN};
+use alloc::{collections::VecDeque, rc::Rc, vec::Vec};
+use core::cell::RefCell;
+use thiserror::Error;
+
+#[derive(Error, Debug, Clone)]
+pub enum LoopbackError {
+    #[error("The frame exceeds the maximum transport frame length")]
+    TooLarge,
+}
+
+struct Endpoint {
+    address: MacAddress,
+    inbox: VecDeque<ReceivedFrame>,
+}
+
+/// Connects in-process `LoopbackTransport`s to each other.
+///
+/// Every frame is delivered instantly and without loss, which makes this
+/// suitable for tests and for simulating several nodes in one program.
+#[derive(Clone, Default)]
+pub struct LoopbackHub {
+    endpoints: Rc<RefCell<Vec<Endpoint>>>,
+}
+impl LoopbackHub {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Creates a new transport attached to this hub
+    pub fn connect(&self, address: MacAddress) -> LoopbackTransport {
+        self.endpoints.borrow_mut().push(Endpoint {
+            address,
+            inbox: VecDeque::new(),
+        });
+        return LoopbackTransport {
+            hub: self.clone(),
+            address,
+        };
+    }
+}
+
+pub struct LoopbackTransport {
+    hub: LoopbackHub,
+    address: MacAddress,
+}
+impl Transport for LoopbackTransport {
+    type Error = LoopbackError;
+
+    fn address(&self) -> MacAddress {
+        return self.address;
+    }
+
+    fn send(&mut self, dst_address: &MacAddress, data: &[u8]) -> Result<(), Self::Error> {
+        if data.len() > MAX_FRAME_LEN {
+            return Err(LoopbackError::TooLarge);
+        }
+        let mut endpoints = self.hub.endpoints.borrow_mut();
+        for endpoint in endpoints.iter_mut() {
+            if endpoint.address == self.address {
+                continue;
+            }
+            if *dst_address == BROADCAST_ADDRESS || endpoint.address == *dst_address {
+                endpoint.inbox.push_back(ReceivedFrame {
+                    src_address: self.address,
+                    rssi: None,
+                    data: heapless::Vec::from_slice(data).unwrap(),
+                });
+            }
+        }
+        return Ok(());
+    }
+
+    fn receive(&mut self) -> Option<ReceivedFrame> {
+        let mut endpoints = self.hub.endpoints.borrow_mut();
+        return endpoints
+            .iter_mut()
+            .find(|endpoint| endpoint.address == self.address)?
+            .inbox
+            .pop_front();
+    }
+}
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.hub
+            .endpoints
+            .borrow_mut()
+            .retain(|endpoint| endpoint.address != self.address);
+    }
+}
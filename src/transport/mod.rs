@@ -0,0 +1,62 @@
+//! Link-layer abstraction for `PacketManager`.
+//!
+//! Anything that can move small datagrams between nodes can carry cluster
+//! traffic: ESP-NOW on the device, UDP multicast on a Linux host, or an
+//! in-process loopback for tests and simulation.
+
+pub mod esp_now;
+pub mod frame_kind;
+pub mod loopback;
+#[cfg(feature = "std")]
+pub mod udp;
+
+pub use loopback::{LoopbackHub, LoopbackTransport};
+#[cfg(feature = "std")]
+pub use udp::UdpTransport;
+
+pub type MacAddress = [u8; 6];
+
+pub const BROADCAST_ADDRESS: MacAddress = [0xff; 6];
+
+/// Largest frame a transport is required to carry.
+///
+/// This matches the ESP-NOW payload limit so that chunk sizes are the same
+/// regardless of which link a message travels over.
+pub const MAX_FRAME_LEN: usize = 250;
+
+/// A frame received from a transport
+#[derive(Debug, Clone)]
+pub struct ReceivedFrame {
+    pub src_address: MacAddress,
+    /// Signal strength in dBm, if the link reports it
+    pub rssi: Option<i8>,
+    pub data: heapless::Vec<u8, MAX_FRAME_LEN>,
+}
+
+pub trait Transport {
+    type Error: core::fmt::Debug;
+
+    /// The address other nodes see as the source of our frames
+    fn address(&self) -> MacAddress;
+
+    /// Sends a frame to a single peer.
+    ///
+    /// This shouldn't wait for the frame to go out. A link that can't take
+    /// the frame yet returns an error for which `is_busy` is true, and the
+    /// caller tries again later. Frames longer than `MAX_FRAME_LEN` must be
+    /// rejected.
+    fn send(&mut self, dst_address: &MacAddress, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Sends a frame to every peer in range
+    fn broadcast(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        return self.send(&BROADCAST_ADDRESS, data);
+    }
+
+    /// Returns the next buffered frame without blocking, if any
+    fn receive(&mut self) -> Option<ReceivedFrame>;
+
+    /// Whether `error` only means the link is still busy with earlier frames
+    fn is_busy(_error: &Self::Error) -> bool {
+        return false;
+    }
+}
@@ -0,0 +1,96 @@
+//! UDP multicast transport for host tooling and bridges.
+//!
+//! Each datagram is prefixed with the 6-byte source and destination
+//! addresses so that nodes keep their identity regardless of IP routing.
+
+use super::{MacAddress, ReceivedFrame, Transport, BROADCAST_ADDRESS, MAX_FRAME_LEN};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+};
+
+const ADDRESS_HEADER_LEN: usize = 12;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+    address: MacAddress,
+}
+impl UdpTransport {
+    /// Joins the multicast `group` on `port`, identifying as `address`
+    pub fn new(group: Ipv4Addr, port: u16, address: MacAddress) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))?;
+        socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        return Ok(Self {
+            socket,
+            group: SocketAddrV4::new(group, port),
+            address,
+        });
+    }
+}
+impl Transport for UdpTransport {
+    type Error = io::Error;
+
+    fn address(&self) -> MacAddress {
+        return self.address;
+    }
+
+    fn send(&mut self, dst_address: &MacAddress, data: &[u8]) -> Result<(), Self::Error> {
+        if data.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Frame exceeds the maximum transport frame length",
+            ));
+        }
+        let mut datagram = [0u8; ADDRESS_HEADER_LEN + MAX_FRAME_LEN];
+        datagram[0..6].copy_from_slice(&self.address);
+        datagram[6..12].copy_from_slice(dst_address);
+        datagram[ADDRESS_HEADER_LEN..ADDRESS_HEADER_LEN + data.len()].copy_from_slice(data);
+        self.socket
+            .send_to(&datagram[0..ADDRESS_HEADER_LEN + data.len()], self.group)?;
+        return Ok(());
+    }
+
+    fn receive(&mut self) -> Option<ReceivedFrame> {
+        let mut datagram = [0u8; ADDRESS_HEADER_LEN + MAX_FRAME_LEN];
+        loop {
+            let len = match self.socket.recv_from(&mut datagram) {
+                Ok((len, _)) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(err) => {
+                    log::error!("Failed to receive datagram: {err:?}");
+                    return None;
+                }
+            };
+            if len < ADDRESS_HEADER_LEN {
+                continue;
+            }
+            let src_address: MacAddress = datagram[0..6].try_into().unwrap();
+            let dst_address: MacAddress = datagram[6..12].try_into().unwrap();
+
+            // Multicast loops our own frames back to us
+            if src_address == self.address {
+                continue;
+            }
+            if dst_address != BROADCAST_ADDRESS && dst_address != self.address {
+                continue;
+            }
+            let data = match heapless::Vec::from_slice(&datagram[ADDRESS_HEADER_LEN..len]) {
+                Ok(data) => data,
+                Err(_) => continue,
+            };
+            return Some(ReceivedFrame {
+                src_address,
+                rssi: None,
+                data,
+            });
+        }
+    }
+
+    fn is_busy(error: &Self::Error) -> bool {
+        // The socket is non-blocking, so a full send buffer is reported rather than waited on
+        return error.kind() == io::ErrorKind::WouldBlock;
+    }
+}
@@ -0,0 +1,179 @@
+//! Known-answer tests for AES-256-CCM.
+//!
+//! The first vector is from NIST CAVP's `DVPT256.rsp`. The rest cover the
+//! nonce, tag and data lengths the cluster uses, and were generated with
+//! OpenSSL's AES-256-CCM.
+//!
+//! Run on the host with the software AES backend:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::{
+    hal::aes::Aes,
+    hw_aes::{self, DecryptionError, CCM_NONCE_SIZE},
+};
+
+fn hex(hex: &str) -> Vec<u8> {
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+}
+
+/// Checks encryption against `expected` (ciphertext followed by tag), then
+/// that it decrypts, and that any flipped bit is caught
+fn check(key: &str, nonce: &str, aad: &str, plaintext: &str, expected: &str, tag_len: usize) {
+    let mut aes = Aes::new();
+    let key: [u8; 32] = hex(key).try_into().unwrap();
+    let (nonce, aad, plaintext, expected) = (hex(nonce), hex(aad), hex(plaintext), hex(expected));
+
+    let mut data = plaintext.clone();
+    let mut tag = vec![0u8; tag_len];
+    hw_aes::ccm_encrypt(&mut aes, &key, &nonce, &aad, &mut data, &mut tag);
+    assert_eq!([data.as_slice(), &tag].concat(), expected);
+
+    hw_aes::ccm_decrypt(&mut aes, &key, &nonce, &aad, &mut data, &tag).unwrap();
+    assert_eq!(data, plaintext);
+
+    for bit in 0..expected.len() * 8 {
+        let mut sealed = expected.clone();
+        sealed[bit / 8] ^= 1 << (bit % 8);
+        let (data, tag) = sealed.split_at_mut(plaintext.len());
+        assert!(matches!(
+            hw_aes::ccm_decrypt(&mut aes, &key, &nonce, &aad, data, tag),
+            Err(DecryptionError::AuthenticationFailed)
+        ));
+        assert!(data.iter().all(|byte| *byte == 0));
+    }
+}
+
+#[test]
+fn nist_dvpt256_count_0() {
+    check(
+        "eda32f751456e33195f1f499cf2dc7c97ea127b6d488f211ccc5126fbb24afa6",
+        "a544218dadd3c1",
+        "",
+        "",
+        "469c90bb",
+        4,
+    );
+}
+
+#[test]
+fn frame_sized() {
+    check(
+        "e45d0fd5dd4bee222b3a69c5389130a4a8da53662e1afbc695df9675f6ce6b01",
+        "9661cbe0bc6b612edcda16884d",
+        "fb78e0f87d95fdf34905b6aa6042d10f",
+        "01139001a47676e0e628eccdf5bd97e6d2171ec56050e07ebe5d9458b2d2ad49e0b8e19597b3c2c9",
+        "04c0924ef206c2d2338167a092089ad5a4e0144720758a8c7d234d6f63b3c3e7d295d3e6269e9e14\
+         ec422cdd96128c827750727ac81f9cb8",
+        16,
+    );
+}
+
+#[test]
+fn short_tag_without_aad() {
+    check(
+        "bb3007c0134052610acd991655340a5979b2271ccf7dc6c4ee23a802aaf4cf88",
+        "0640faa8f8970cf95b8106717a",
+        "",
+        "35",
+        "0e210be8d81109e424",
+        8,
+    );
+}
+
+#[test]
+fn multi_block_aad_and_payload() {
+    check(
+        "8cb37a1d047560d3b3ec8f43f6a0225c5ce2ee5f02ef260a38025cff8da01a6f",
+        "76d29f76e30d3f8d5a6584b8d7",
+        "eba07e15423b6ffdf16d58f8b25eeed39be15bb4012f1cc7",
+        "ca879561c33dba33e46dbeced12f5a2ad26891b6a98d7b5e21bdc9372b73dce7f2d8d65008f8067f\
+         c8c65ec638cc5b0753766a744de51101acc381b79b97e8edc16a359796dcb6fa8f357399e88a4077\
+         490e67a8d254a246354d15a26ba3e4825e58bdfc0d4cda633906a7ac118f903fccd217affcf9a0f0\
+         849caabd001f8f229645d57e9275ffb854ab326c073a914c5e22b6fc5ed6ef14559a5233bced0182\
+         a84804b4ad7fc7efcbe3a58ebcc5e0731fe2f48acf5bb4b1bc1bac0be410e773b7e979547738ed56",
+        "60bacb1b3e59f0acc0f2b20857c10d0be7a80d1e9e14a0ffb7de2ca7f5d6b667aed5cd5a44aee2fc\
+         63c0d3f0fa67ab48101a80db1156400ce858bef554cdf422b99621b737818b264f7b9487a28ad4a5\
+         fb578b8493528a8027b9dd8e60b27e6d7787966e117369c057e5b2385908e3a41b0cc45ac461b574\
+         e3b706a11ea66b105a307dc9b86e272ccf1c5c290034ee0ef9b96bafe60fb7a4266933c709187dc1\
+         356d09ecb0750beb8b1b6868c977eb345a71a618745fb7eeca302bbf8c8e1863dd9740c4d8991aa7\
+         9a73c9f85ac991cdc36c1b52",
+        12,
+    );
+}
+
+#[test]
+fn empty_payload() {
+    check(
+        "88e449b42cd1b3a6e156df721941a3c8beee77008f8fa68c6d12f2b78e0c8b67",
+        "88f3213b10e61a2c731ab79eb2",
+        "6ab9db9c15fcc1622ee6a03de48993a0",
+        "",
+        "d0cf80c1263cafc3e77da594215d3aa0",
+        16,
+    );
+}
+
+#[test]
+fn shortest_nonce() {
+    check(
+        "1513ec17cd5fd125237ba08cea755afd3472ac51749a339d386d40b88244de41",
+        "58fb68f657ac1a",
+        "8b05555807",
+        "da6f09b3ce03cb6d0994052fa7690e116028d4282e1f238b3d899f8a514a2fd8e9",
+        "d0a03a0b935cfc63cc79274a6cfc0d3282ec238f7a1b57192baf2ac6c69d399a513bc1fed9",
+        4,
+    );
+}
+
+#[test]
+fn sealed_packet_round_trip() {
+    let mut aes = Aes::new();
+    let key = [0x42u8; 32];
+    let nonce = [7u8; CCM_NONCE_SIZE];
+    let mut packet = b"hello cluster".to_vec();
+    hw_aes::seal_packet(&mut aes, &key, &nonce, b"header", 8, &mut packet);
+    assert_eq!(packet.len(), 13 + 8);
+
+    let mut opened = packet.clone();
+    let data = hw_aes::open_packet(&mut aes, &key, &nonce, b"header", 8, &mut opened).unwrap();
+    assert_eq!(data, b"hello cluster");
+
+    // A different nonce, header or tag length doesn't open
+    let mut other_nonce = nonce;
+    other_nonce[12] ^= 1;
+    for (nonce, aad, tag_len) in [
+        (&other_nonce, &b"header"[..], 8),
+        (&nonce, b"HEADER", 8),
+        (&nonce, b"header", 10),
+    ] {
+        let mut opened = packet.clone();
+        assert!(hw_aes::open_packet(&mut aes, &key, nonce, aad, tag_len, &mut opened).is_err());
+    }
+}
+
+#[test]
+fn invalid_parameters_are_rejected() {
+    let mut aes = Aes::new();
+    let key = [0u8; 32];
+    let mut data = [0u8; 16];
+    // Nonces must be 7 to 13 bytes and tags an even length from 4 to 16
+    for (nonce_len, tag_len) in [(6, 8), (14, 8), (13, 2), (13, 9), (13, 18)] {
+        let nonce = vec![0u8; nonce_len];
+        let tag = vec![0u8; tag_len];
+        assert!(matches!(
+            hw_aes::ccm_decrypt(&mut aes, &key, &nonce, &[], &mut data, &tag),
+            Err(DecryptionError::InvalidLength)
+        ));
+    }
+    // A 13-byte nonce leaves a 2-byte length field
+    let mut data = vec![0u8; 1 << 16];
+    assert!(matches!(
+        hw_aes::ccm_decrypt(&mut aes, &key, &[0u8; 13], &[], &mut data, &[0u8; 8]),
+        Err(DecryptionError::InvalidLength)
+    ));
+}
@@ -0,0 +1,236 @@
+//! Signed commands: signatures, and rejection of replayed or stale ones.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use std::{fs, path::PathBuf};
+use tactile_tesla::{
+    binary_packets::{PacketReader, PacketWriter},
+    counter_store::FileCounterStore,
+    hal::{aes::Aes, rng::Rng, sha::Sha},
+    key_store::{ClusterKey, DeviceIdentity, FileKeyStore, KeyId, KeyStore},
+    packet_manager::{PacketManager, Role, TrafficClass},
+    packet_types::{CommPacket, Command, RotateKey, SignedCommand, Transmittable},
+    transport::{LoopbackHub, LoopbackTransport, MacAddress},
+};
+
+const NODE: MacAddress = [0x02, 0, 0, 0, 0, 0x1];
+const RESTARTED_NODE: MacAddress = [0x02, 0, 0, 0, 0, 0x3];
+const RELAY: MacAddress = [0x02, 0, 0, 0, 0, 0x2];
+
+fn identity(seed: u8) -> DeviceIdentity {
+    return DeviceIdentity {
+        signing_seed: [seed; 32],
+        commander_key: None,
+    };
+}
+
+fn cluster_key(id: KeyId) -> ClusterKey {
+    return ClusterKey {
+        id,
+        secret: [id; 64],
+    };
+}
+
+fn rotate_to(id: KeyId) -> Command {
+    return Command::RotateKey(RotateKey {
+        new_key: cluster_key(id),
+        grace_secs: 300,
+    });
+}
+
+fn encode(signed: &SignedCommand) -> Vec<u8> {
+    let mut writer = PacketWriter::new();
+    signed.encode(&mut writer).unwrap();
+    return writer.finish();
+}
+
+/// A file path unique to this test, removed before use
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tt-commands-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("identity"));
+    return path;
+}
+
+#[test]
+fn signatures_verify_only_under_the_signing_key() {
+    let commander = identity(1);
+    let signed = SignedCommand::sign(&commander, 42, &rotate_to(8)).unwrap();
+    assert!(matches!(
+        signed.verify(&commander.public_key()),
+        Some(Command::RotateKey(RotateKey { new_key, grace_secs: 300 })) if new_key == cluster_key(8)
+    ));
+    assert!(signed.verify(&identity(2).public_key()).is_none());
+
+    // Survives the trip through a packet
+    let bytes = encode(&signed);
+    let decoded = SignedCommand::decode(&mut PacketReader::new(&bytes)).unwrap();
+    assert_eq!(decoded.sequence, 42);
+    assert!(decoded.verify(&commander.public_key()).is_some());
+}
+
+#[test]
+fn tampering_breaks_the_signature() {
+    let commander = identity(1);
+    let signed = SignedCommand::sign(&commander, 42, &rotate_to(8)).unwrap();
+
+    // The sequence number is covered, so it can't be bumped past a receiver's
+    let mut bumped = signed.clone();
+    bumped.sequence += 1;
+    assert!(bumped.verify(&commander.public_key()).is_none());
+
+    // As is every byte of the command and signature
+    let bytes = encode(&signed);
+    for i in 8..bytes.len() {
+        let mut tampered = bytes.clone();
+        tampered[i] ^= 0x01;
+        if let Some(decoded) = SignedCommand::decode(&mut PacketReader::new(&tampered)) {
+            assert!(decoded.verify(&commander.public_key()).is_none(), "byte {i}");
+        }
+    }
+}
+
+/// A node that trusts `identity(1)`, and a cluster member that relays
+/// commands to it. The relay holds the cluster key but not the Commander's
+/// signing key, like a compromised node would.
+struct Cluster {
+    hub: LoopbackHub,
+    aes: Aes<'static>,
+    sha: Sha<'static>,
+    rng: Rng,
+    node: PacketManager<LoopbackTransport, FileKeyStore, FileCounterStore>,
+    relay: PacketManager<LoopbackTransport, FileKeyStore, FileCounterStore>,
+    paths: Vec<PathBuf>,
+}
+impl Cluster {
+    fn new(name: &str) -> Self {
+        let hub = LoopbackHub::new();
+        let (aes, mut sha, mut rng) = (Aes::new(), Sha::new(), Rng::new());
+        let paths: Vec<PathBuf> = ["node-keys", "node-counters", "relay-keys", "relay-counters"]
+            .iter()
+            .map(|file| temp_path(&format!("{name}-{file}")))
+            .collect();
+
+        let mut node_keys = FileKeyStore::new(&paths[0]);
+        node_keys.store_cluster_key(&cluster_key(7)).unwrap();
+        node_keys
+            .store_identity(&DeviceIdentity {
+                signing_seed: [3; 32],
+                commander_key: Some(identity(1).public_key()),
+            })
+            .unwrap();
+        let mut relay_keys = FileKeyStore::new(&paths[2]);
+        relay_keys.store_cluster_key(&cluster_key(7)).unwrap();
+
+        let node = PacketManager::new(
+            hub.connect(NODE),
+            node_keys,
+            FileCounterStore::new(&paths[1]),
+            &mut sha,
+            &mut rng,
+        )
+        .unwrap();
+        let relay = PacketManager::new(
+            hub.connect(RELAY),
+            relay_keys,
+            FileCounterStore::new(&paths[3]),
+            &mut sha,
+            &mut rng,
+        )
+        .unwrap();
+        return Self {
+            hub,
+            aes,
+            sha,
+            rng,
+            node,
+            relay,
+            paths,
+        };
+    }
+
+    /// Reboots the node, keeping its key and counter stores. It comes back
+    /// under a new address, since the old transport only detaches once the
+    /// new manager has replaced it.
+    fn restart_node(&mut self) {
+        self.node = PacketManager::new(
+            self.hub.connect(RESTARTED_NODE),
+            FileKeyStore::new(&self.paths[0]),
+            FileCounterStore::new(&self.paths[1]),
+            &mut self.sha,
+            &mut self.rng,
+        )
+        .unwrap();
+    }
+
+    /// Moves the relay onto the key the node rotated to
+    fn relay_to_key(&mut self, id: KeyId) {
+        self.relay
+            .set_cluster_key(&mut self.sha, &cluster_key(id))
+            .unwrap();
+    }
+
+    /// Relays `signed` to the node under the relay's current key
+    fn relay(&mut self, signed: &SignedCommand) {
+        self.relay
+            .broadcast(TrafficClass::Control, &CommPacket::Command(signed.clone()))
+            .unwrap();
+        for _ in 0..3 {
+            self.relay
+                .tick(&mut self.aes, &mut self.sha, &mut self.rng, Role::Node);
+            self.node
+                .tick(&mut self.aes, &mut self.sha, &mut self.rng, Role::Node);
+        }
+    }
+}
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(path.with_extension("identity"));
+        }
+    }
+}
+
+#[test]
+fn replayed_and_stale_commands_are_ignored() {
+    let mut cluster = Cluster::new("replay");
+    let commander = identity(1);
+    let first = SignedCommand::sign(&commander, 100, &rotate_to(8)).unwrap();
+    let second = SignedCommand::sign(&commander, 200, &rotate_to(9)).unwrap();
+    let stale = SignedCommand::sign(&commander, 150, &rotate_to(10)).unwrap();
+
+    cluster.relay(&first);
+    assert_eq!(cluster.node.current_key_id(), 8);
+    cluster.relay_to_key(8);
+    cluster.relay(&second);
+    assert_eq!(cluster.node.current_key_id(), 9);
+
+    // Replaying the first command in a fresh frame would switch back to key 8
+    cluster.relay_to_key(9);
+    cluster.relay(&first);
+    assert_eq!(cluster.node.current_key_id(), 9);
+    // A validly signed command older than the last one is ignored too
+    cluster.relay(&stale);
+    assert_eq!(cluster.node.current_key_id(), 9);
+
+    // Still after a reboot, since the last sequence number is persisted
+    cluster.restart_node();
+    assert_eq!(cluster.node.current_key_id(), 9);
+    cluster.relay(&first);
+    cluster.relay(&stale);
+    assert_eq!(cluster.node.current_key_id(), 9);
+    assert_eq!(cluster.node.stats().unauthorized_commands, 0);
+}
+
+#[test]
+fn commands_from_other_signers_are_ignored() {
+    let mut cluster = Cluster::new("unauthorized");
+    let forged = SignedCommand::sign(&identity(2), 100, &rotate_to(8)).unwrap();
+    cluster.relay(&forged);
+    assert_eq!(cluster.node.current_key_id(), 7);
+    assert!(cluster.node.stats().unauthorized_commands > 0);
+}
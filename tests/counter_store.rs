@@ -0,0 +1,229 @@
+//! Replay counters on flash and on disk.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use std::{fs, path::PathBuf};
+use tactile_tesla::{
+    counter_store::{
+        CounterStore, FileCounterStore, FlashCounterStore, MemoryCounterStore,
+        MAX_PERSISTED_PEERS,
+    },
+    key_store::FLASH_SECTOR_SIZE,
+    transport::MacAddress,
+};
+
+const SECTOR: usize = FLASH_SECTOR_SIZE as usize;
+const OFFSET: u32 = 2 * FLASH_SECTOR_SIZE;
+const SIZE: u32 = 4 * FLASH_SECTOR_SIZE;
+
+/// NOR flash that only takes writes to erased bytes and counts its erases.
+/// Can be made to lose power halfway through a write.
+struct MockFlash {
+    bytes: Vec<u8>,
+    erases: usize,
+    power_loss: bool,
+}
+impl MockFlash {
+    fn new() -> Self {
+        return Self {
+            bytes: vec![0xFF; 8 * SECTOR],
+            erases: 0,
+            power_loss: false,
+        };
+    }
+}
+
+#[derive(Debug)]
+struct PowerLoss;
+impl NorFlashError for PowerLoss {
+    fn kind(&self) -> NorFlashErrorKind {
+        return NorFlashErrorKind::Other;
+    }
+}
+
+impl ErrorType for &mut MockFlash {
+    type Error = PowerLoss;
+}
+impl ReadNorFlash for &mut MockFlash {
+    const READ_SIZE: usize = 4;
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerLoss> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+        return Ok(());
+    }
+    fn capacity(&self) -> usize {
+        return self.bytes.len();
+    }
+}
+impl NorFlash for &mut MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR;
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerLoss> {
+        assert!(from as usize % SECTOR == 0 && to as usize % SECTOR == 0);
+        self.bytes[from as usize..to as usize].fill(0xFF);
+        self.erases += 1;
+        return Ok(());
+    }
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerLoss> {
+        let offset = offset as usize;
+        let target = &mut self.bytes[offset..offset + bytes.len()];
+        assert!(
+            target.iter().all(|byte| *byte == 0xFF),
+            "Wrote to flash that wasn't erased"
+        );
+        if self.power_loss {
+            let half = bytes.len() / 2;
+            target[0..half].copy_from_slice(&bytes[0..half]);
+            return Err(PowerLoss);
+        }
+        target.copy_from_slice(bytes);
+        return Ok(());
+    }
+}
+
+fn peer(i: usize) -> MacAddress {
+    return [0x02, 0, 0, 0, (i >> 8) as u8, i as u8];
+}
+
+fn fill(store: &mut impl CounterStore) {
+    store.store_tx_counter(1025);
+    store.store_command_sequence(77);
+    store.store_peer_counter(&peer(1), 300);
+    store.store_peer_counter(&peer(2), 5);
+}
+
+fn check_filled(store: &mut impl CounterStore) {
+    assert_eq!(store.load_tx_counter(), Some(1025));
+    assert_eq!(store.load_command_sequence(), Some(77));
+    assert_eq!(store.load_peer_counter(&peer(1)), Some(300));
+    assert_eq!(store.load_peer_counter(&peer(2)), Some(5));
+    assert_eq!(store.load_peer_counter(&peer(3)), None);
+}
+
+/// A file path unique to this test, removed before use
+fn temp_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("tt-counter-store-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    return path;
+}
+
+#[test]
+fn memory_forgets_least_recently_updated_peer() {
+    let mut store = MemoryCounterStore::new();
+    for i in 0..MAX_PERSISTED_PEERS {
+        store.store_peer_counter(&peer(i), i as u64 + 1);
+    }
+    // Updating a peer makes it the most recent
+    store.store_peer_counter(&peer(0), 1000);
+    store.store_peer_counter(&peer(MAX_PERSISTED_PEERS), 1);
+    assert_eq!(store.load_peer_counter(&peer(0)), Some(1000));
+    assert_eq!(store.load_peer_counter(&peer(1)), None);
+    assert_eq!(store.load_peer_counter(&peer(MAX_PERSISTED_PEERS)), Some(1));
+}
+
+#[test]
+fn flash_round_trip() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    assert_eq!(store.load_tx_counter(), None);
+    assert_eq!(store.load_command_sequence(), None);
+    fill(&mut store);
+    drop(store);
+
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    check_filled(&mut store);
+    // Enough updates to wrap around the sectors several times
+    for counter in 0..20 {
+        store.store_peer_counter(&peer(1), 300 + counter);
+    }
+    drop(store);
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    assert_eq!(store.load_peer_counter(&peer(1)), Some(319));
+    assert_eq!(store.load_tx_counter(), Some(1025));
+
+    // Nothing is written outside the region
+    assert!(flash.bytes[..OFFSET as usize].iter().all(|byte| *byte == 0xFF));
+    assert!(flash.bytes[(OFFSET + SIZE) as usize..]
+        .iter()
+        .all(|byte| *byte == 0xFF));
+}
+
+#[test]
+fn flash_erases_only_when_moving_to_the_next_sector() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    let updates = 64;
+    for counter in 0..updates {
+        store.store_tx_counter(1 + counter);
+    }
+    drop(store);
+    // Several records share each sector
+    assert!(flash.erases * 3 <= updates as usize);
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    assert_eq!(store.load_tx_counter(), Some(updates));
+}
+
+#[test]
+fn power_loss_keeps_previous_counters() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    fill(&mut store);
+    drop(store);
+
+    flash.power_loss = true;
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    store.store_tx_counter(2049);
+    drop(store);
+
+    flash.power_loss = false;
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    check_filled(&mut store);
+
+    // The torn slot is skipped rather than written over
+    store.store_command_sequence(78);
+    drop(store);
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    assert_eq!(store.load_command_sequence(), Some(78));
+    assert_eq!(store.load_tx_counter(), Some(1025));
+}
+
+#[test]
+fn flash_skips_corrupted_records() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    fill(&mut store);
+    store.store_tx_counter(2049);
+    drop(store);
+
+    // The newest record was appended last, so it ends with the last byte written
+    let last = flash.bytes.iter().rposition(|byte| *byte != 0xFF).unwrap();
+    flash.bytes[last] ^= 1;
+    let mut store = FlashCounterStore::new(&mut flash, OFFSET, SIZE);
+    check_filled(&mut store);
+}
+
+#[test]
+fn file_round_trip() {
+    let path = temp_path("round-trip");
+    let mut store = FileCounterStore::new(&path);
+    assert_eq!(store.load_tx_counter(), None);
+    fill(&mut store);
+
+    let mut store = FileCounterStore::new(&path);
+    check_filled(&mut store);
+
+    // A corrupted file starts over rather than trusting the counters
+    let mut record = fs::read(&path).unwrap();
+    record[30] ^= 1;
+    fs::write(&path, &record).unwrap();
+    let mut store = FileCounterStore::new(&path);
+    assert_eq!(store.load_tx_counter(), None);
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,169 @@
+//! Reliable, FEC and transfer frames carried sealed between packet managers.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use std::{fs, path::PathBuf};
+use tactile_tesla::{
+    counter_store::MemoryCounterStore,
+    hal::{
+        aes::Aes,
+        rng::Rng,
+        sha::Sha,
+        time::{Duration, Instant},
+    },
+    key_store::{ClusterKey, FileKeyStore, KeyStore},
+    packet_manager::{PacketManager, PacketManagerError, Role, TrafficClass},
+    packetizer::{
+        DeliveryResult, ReliablePacketAssembler, ReliablePacketDisassembler, FEEDBACK_LEN,
+        RELIABLE_ACK,
+    },
+    transport::{frame_kind, LoopbackHub, LoopbackTransport, MacAddress, Transport},
+};
+
+const SENDER: MacAddress = [0x02, 0, 0, 0, 0, 0x1];
+const RECEIVER: MacAddress = [0x02, 0, 0, 0, 0, 0x2];
+const ATTACKER: MacAddress = [0x02, 0, 0, 0, 0, 0x3];
+
+type Manager = PacketManager<LoopbackTransport, FileKeyStore, MemoryCounterStore>;
+
+/// A key store file unique to this test, removed before use
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tt-datagrams-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("identity"));
+    return path;
+}
+
+fn manager(hub: &LoopbackHub, address: MacAddress, path: &PathBuf) -> Manager {
+    let mut key_store = FileKeyStore::new(path);
+    key_store
+        .store_cluster_key(&ClusterKey {
+            id: 1,
+            secret: [0x5a; 64],
+        })
+        .unwrap();
+    return PacketManager::new(
+        hub.connect(address),
+        key_store,
+        MemoryCounterStore::new(),
+        &mut Sha::new(),
+        &mut Rng::new(),
+    )
+    .unwrap();
+}
+
+struct Pair {
+    hub: LoopbackHub,
+    sender: Manager,
+    receiver: Manager,
+    paths: [PathBuf; 2],
+}
+impl Pair {
+    fn new(name: &str) -> Self {
+        let hub = LoopbackHub::new();
+        let paths = [
+            temp_path(&format!("{name}-sender")),
+            temp_path(&format!("{name}-receiver")),
+        ];
+        return Self {
+            sender: manager(&hub, SENDER, &paths[0]),
+            receiver: manager(&hub, RECEIVER, &paths[1]),
+            hub,
+            paths,
+        };
+    }
+
+    fn tick(&mut self) {
+        let (mut aes, mut sha, mut rng) = (Aes::new(), Sha::new(), Rng::new());
+        self.sender.tick(&mut aes, &mut sha, &mut rng, Role::Node);
+        self.receiver.tick(&mut aes, &mut sha, &mut rng, Role::Node);
+    }
+}
+impl Drop for Pair {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(path.with_extension("identity"));
+        }
+    }
+}
+
+#[test]
+fn reliable_message_travels_sealed() {
+    let mut pair = Pair::new("reliable");
+    let mut disassembler = ReliablePacketDisassembler::<200>::new(7);
+    let mut assembler = ReliablePacketAssembler::new();
+    let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    disassembler.send(&message, Instant::from_ticks(0)).unwrap();
+
+    let mut results = Vec::new();
+    let mut now = Instant::from_ticks(0);
+    let mut chunk = [0u8; 200];
+    while !disassembler.is_idle() {
+        let mut on_delivery = |msg_seq, result| results.push((msg_seq, result));
+        while let Some(len) = disassembler.poll_chunk(now, &mut chunk, &mut on_delivery) {
+            pair.sender
+                .send_datagram(TrafficClass::Interactive, &chunk[0..len])
+                .unwrap();
+        }
+        pair.tick();
+        while let Some((src, datagram)) = pair.receiver.next_datagram() {
+            assert_eq!(src, SENDER);
+            assembler.push_data(&datagram, now);
+        }
+        while let Some(feedback) = assembler.next_feedback() {
+            pair.receiver
+                .send_datagram(TrafficClass::Interactive, &feedback)
+                .unwrap();
+        }
+        pair.tick();
+        while let Some((_, datagram)) = pair.sender.next_datagram() {
+            disassembler.handle_feedback(&datagram, &mut on_delivery);
+        }
+        now += Duration::millis(50);
+    }
+    assert_eq!(results, [(1, DeliveryResult::Delivered)]);
+    assert_eq!(assembler.next(), Some(message));
+}
+
+#[test]
+fn unsealed_datagrams_are_ignored() {
+    let mut pair = Pair::new("forged");
+    let mut attacker = pair.hub.connect(ATTACKER);
+
+    // A forged ACK sent in the clear, under either a cluster or made-up frame kind
+    let mut ack = [0u8; FEEDBACK_LEN];
+    ack[0] = RELIABLE_ACK;
+    ack[9..].copy_from_slice(&u64::MAX.to_be_bytes());
+    attacker.broadcast(&ack).unwrap();
+    let mut framed = vec![frame_kind::CLUSTER, 1];
+    framed.extend_from_slice(&[0u8; 8]);
+    framed.extend_from_slice(&ack);
+    framed.extend_from_slice(&[0u8; 16]);
+    attacker.broadcast(&framed).unwrap();
+    pair.tick();
+    assert_eq!(pair.sender.next_datagram(), None);
+    assert_eq!(pair.sender.stats().bad_mac, 1);
+}
+
+#[test]
+fn only_datagram_kinds_are_sent() {
+    let mut pair = Pair::new("kinds");
+    assert!(matches!(
+        pair.sender
+            .send_datagram(TrafficClass::Bulk, &[frame_kind::STREAM_CHUNK, 1, 2]),
+        Err(PacketManagerError::UnknownDatagramKind)
+    ));
+    assert!(matches!(
+        pair.sender.send_datagram(TrafficClass::Bulk, &[]),
+        Err(PacketManagerError::UnknownDatagramKind)
+    ));
+    let too_long = vec![frame_kind::FEC_DATA; pair.sender.max_datagram_len() + 1];
+    assert!(matches!(
+        pair.sender.send_datagram(TrafficClass::Bulk, &too_long),
+        Err(PacketManagerError::MessageTooLarge(_))
+    ));
+}
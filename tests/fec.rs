@@ -0,0 +1,147 @@
+//! Rebuilding FEC-protected messages from incomplete sets of chunks.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::packetizer::{FecPacketAssembler, FecPacketDisassembler};
+
+const CHUNK_SIZE: usize = 64;
+const EPOCH: u32 = 0x1234_5678;
+
+fn message(len: usize) -> Vec<u8> {
+    return (0..len).map(|i| (i * 31 + 7) as u8).collect();
+}
+
+/// Splits `packet` and returns every chunk that was produced
+fn chunks(
+    disassembler: &mut FecPacketDisassembler<CHUNK_SIZE>,
+    packet: &[u8],
+    parity_count: u8,
+) -> Vec<Vec<u8>> {
+    let mut iter = disassembler.split_packet(packet, parity_count).unwrap();
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut chunks = Vec::new();
+    while let Some(len) = iter.get_chunk(&mut chunk) {
+        chunks.push(chunk[0..len].to_vec());
+    }
+    return chunks;
+}
+
+/// Feeds every chunk except the ones at `lost` and returns what was rebuilt
+fn deliver(chunks: &[Vec<u8>], lost: &[usize]) -> Option<Vec<u8>> {
+    let mut assembler = FecPacketAssembler::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        if !lost.contains(&i) {
+            assembler.push_data(chunk);
+        }
+    }
+    let packet = assembler.next();
+    assert_eq!(assembler.next(), None);
+    return packet;
+}
+
+#[test]
+fn without_parity() {
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let chunks = chunks(&mut disassembler, &message(200), 0);
+    assert_eq!(chunks.len(), 4);
+    assert_eq!(deliver(&chunks, &[]), Some(message(200)));
+    assert_eq!(deliver(&chunks, &[2]), None);
+}
+
+#[test]
+fn rebuilds_one_loss_per_group() {
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    // 10 data chunks in 3 interleaved groups
+    let chunks = chunks(&mut disassembler, &message(500), 3);
+    assert_eq!(chunks.len(), 13);
+
+    for lost in 0..chunks.len() {
+        assert_eq!(deliver(&chunks, &[lost]), Some(message(500)));
+    }
+    // A burst of data chunks as long as the parity count hits distinct groups
+    for start in 0..10 - 2 {
+        assert_eq!(
+            deliver(&chunks, &[start, start + 1, start + 2]),
+            Some(message(500))
+        );
+    }
+    // Two losses in the same group can't be rebuilt
+    assert_eq!(deliver(&chunks, &[0, 3]), None);
+    assert_eq!(deliver(&chunks, &[9, 10]), None);
+}
+
+#[test]
+fn short_messages_and_duplicates() {
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let chunks = chunks(&mut disassembler, &message(5), 1);
+    assert_eq!(chunks.len(), 2);
+    assert_eq!(deliver(&chunks, &[0]), Some(message(5)));
+
+    // Leftover and repeated chunks of a completed message are ignored
+    let mut assembler = FecPacketAssembler::new();
+    for chunk in chunks.iter().chain(chunks.iter()) {
+        assembler.push_data(chunk);
+    }
+    assert_eq!(assembler.next(), Some(message(5)));
+    assert_eq!(assembler.next(), None);
+}
+
+#[test]
+fn empty_message() {
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let chunks = chunks(&mut disassembler, &[], 1);
+    assert_eq!(deliver(&chunks, &[0]), Some(Vec::new()));
+}
+
+#[test]
+fn restarted_sender_is_not_mistaken_for_a_repeat() {
+    let mut assembler = FecPacketAssembler::new();
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    for chunk in chunks(&mut disassembler, &message(100), 1) {
+        assert!(!assembler.push_data(&chunk));
+    }
+    assert_eq!(assembler.next(), Some(message(100)));
+
+    // The same sequence number again, but from a new session
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH + 1);
+    let restarted = chunks(&mut disassembler, &message(30), 1);
+    assert!(assembler.push_data(&restarted[0]));
+    assert!(!assembler.push_data(&restarted[1]));
+    assert_eq!(assembler.next(), Some(message(30)));
+
+    // Stragglers from the old session are dropped
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    for chunk in chunks(&mut disassembler, &message(40), 1) {
+        assert!(!assembler.push_data(&chunk));
+    }
+    assert_eq!(assembler.next(), None);
+}
+
+/// Rewrites the message sequence number of a chunk
+fn with_msg_seq(chunk: &[u8], msg_seq: u32) -> Vec<u8> {
+    let mut chunk = chunk.to_vec();
+    chunk[5..9].copy_from_slice(&msg_seq.to_be_bytes());
+    return chunk;
+}
+
+#[test]
+fn oldest_incomplete_message_is_evicted_across_wraparound() {
+    let mut disassembler = FecPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let chunks = chunks(&mut disassembler, &message(100), 0);
+    assert_eq!(chunks.len(), 2);
+
+    // More incomplete messages than are kept, with sequence numbers wrapping
+    let mut assembler = FecPacketAssembler::new();
+    for msg_seq in [u32::MAX - 1, u32::MAX, 0, 1, 2] {
+        assembler.push_data(&with_msg_seq(&chunks[0], msg_seq));
+    }
+
+    // Only the first one sent was given up on
+    assembler.push_data(&with_msg_seq(&chunks[1], 0));
+    assert_eq!(assembler.next(), Some(message(100)));
+    assembler.push_data(&with_msg_seq(&chunks[1], u32::MAX - 1));
+    assert_eq!(assembler.next(), None);
+}
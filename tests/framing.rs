@@ -0,0 +1,123 @@
+//! Byte-stream framing, with and without a lossy link.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use proptest::prelude::*;
+use tactile_tesla::{
+    hal::time::Instant,
+    packetizer::{cobs_frame_len, encode_cobs_frame, CobsPacketAssembler, PacketAssembler},
+};
+
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+fn encode(packets: &[Vec<u8>]) -> Vec<u8> {
+    let mut stream = Vec::new();
+    for packet in packets {
+        encode_cobs_frame(packet, &mut stream);
+    }
+    return stream;
+}
+
+proptest! {
+    /// Messages come through intact however the stream is cut up
+    #[test]
+    fn cobs_round_trip(
+        packets in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..600), 1..5),
+        piece_len in 1usize..300,
+    ) {
+        let stream = encode(&packets);
+        let mut assembler = CobsPacketAssembler::new(MAX_MESSAGE_SIZE);
+        for piece in stream.chunks(piece_len) {
+            assembler.push_data(piece);
+        }
+        prop_assert_eq!(assembler.collect::<Vec<_>>(), packets);
+    }
+
+    /// Damage to one frame never costs more than that frame
+    #[test]
+    fn cobs_resyncs_after_corruption(
+        first in proptest::collection::vec(any::<u8>(), 0..300),
+        second in proptest::collection::vec(any::<u8>(), 0..300),
+        third in proptest::collection::vec(any::<u8>(), 0..300),
+        position in any::<usize>(),
+        value in any::<u8>(),
+    ) {
+        let mut stream = encode(&[first.clone()]);
+        let damaged_start = stream.len();
+        encode_cobs_frame(&second, &mut stream);
+        let damaged_end = stream.len();
+        encode_cobs_frame(&third, &mut stream);
+
+        let position = damaged_start + position % (damaged_end - damaged_start);
+        prop_assume!(stream[position] != value);
+        stream[position] = value;
+
+        let mut assembler = CobsPacketAssembler::new(MAX_MESSAGE_SIZE);
+        assembler.push_data(&stream);
+        prop_assert_eq!(assembler.collect::<Vec<_>>(), vec![first, third]);
+    }
+
+    /// Messages split into length-prefixed pieces come back in order
+    #[test]
+    fn length_prefixed_round_trip(
+        packets in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..300), 1..5),
+        piece_len in 1usize..100,
+    ) {
+        let mut stream = Vec::new();
+        for packet in &packets {
+            stream.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            stream.extend_from_slice(packet);
+        }
+        let mut assembler = PacketAssembler::new();
+        for piece in stream.chunks(piece_len) {
+            assembler.push_data(piece, Instant::from_ticks(0));
+        }
+        prop_assert_eq!(assembler.collect::<Vec<_>>(), packets);
+    }
+}
+
+#[test]
+fn cobs_frames_have_no_inner_zeros() {
+    let packet = vec![0u8; 600];
+    let mut stream = Vec::new();
+    encode_cobs_frame(&packet, &mut stream);
+    assert_eq!(stream.first(), Some(&0));
+    assert_eq!(stream.last(), Some(&0));
+    assert!(stream[1..stream.len() - 1].iter().all(|byte| *byte != 0));
+    assert!(stream.len() - 2 <= cobs_frame_len(packet.len()));
+
+    let packet = vec![0xAAu8; 1000];
+    let mut stream = Vec::new();
+    encode_cobs_frame(&packet, &mut stream);
+    assert_eq!(stream.len() - 2, cobs_frame_len(packet.len()));
+}
+
+#[test]
+fn cobs_drops_oversized_and_bad_crc() {
+    let mut stream = encode(&[vec![1u8; MAX_MESSAGE_SIZE + 1], b"ok".to_vec()]);
+    // Flip a bit in a payload byte of a frame that's otherwise well formed
+    let start = stream.len();
+    encode_cobs_frame(b"flipped", &mut stream);
+    stream[start + 3] ^= 0x10;
+    encode_cobs_frame(b"after", &mut stream);
+
+    let mut assembler = CobsPacketAssembler::new(MAX_MESSAGE_SIZE);
+    assembler.push_data(&stream);
+    let errors = assembler.take_errors();
+    assert_eq!(errors.oversized, 1);
+    assert_eq!(errors.bad_crc, 1);
+    assert_eq!(assembler.collect::<Vec<_>>(), vec![b"ok".to_vec(), b"after".to_vec()]);
+}
+
+#[test]
+fn cobs_ignores_leading_garbage() {
+    let mut stream = vec![0x13, 0x37, 0x42];
+    stream.extend(encode(&[b"hello".to_vec()]));
+    let mut assembler = CobsPacketAssembler::new(MAX_MESSAGE_SIZE);
+    assembler.push_data(&stream);
+    assert_eq!(assembler.take_errors().malformed, 1);
+    assert_eq!(assembler.next(), Some(b"hello".to_vec()));
+}
@@ -0,0 +1,157 @@
+//! RFC 4231 test vectors for HMAC-SHA256.
+//!
+//! Run on the host with the software SHA backend:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::{
+    hal::sha::Sha,
+    hw_hmac::{self, HmacContext},
+};
+
+fn hex(hex: &str) -> Vec<u8> {
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+}
+
+/// Only the first `expected.len()` bytes are compared, for the truncated test
+/// case
+fn check(key: &[u8], data: &[u8], expected: &str) {
+    let mut sha = Sha::new();
+    let expected = hex(expected);
+
+    let tag = hw_hmac::hmac_chunk(&mut sha, key, data).unwrap();
+    assert_eq!(&tag[0..expected.len()], expected.as_slice());
+}
+
+#[test]
+fn rfc4231_case_1() {
+    check(
+        &[0x0b; 20],
+        b"Hi There",
+        "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+    );
+}
+
+/// Key shorter than the output length
+#[test]
+fn rfc4231_case_2() {
+    check(
+        b"Jefe",
+        b"what do ya want for nothing?",
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+    );
+}
+
+#[test]
+fn rfc4231_case_3() {
+    check(
+        &[0xaa; 20],
+        &[0xdd; 50],
+        "773ea91e36800e46854db8ebd09181a72959098b3ef8c122d9635514ced565fe",
+    );
+}
+
+#[test]
+fn rfc4231_case_4() {
+    let key: Vec<u8> = (0x01..=0x19).collect();
+    check(
+        &key,
+        &[0xcd; 50],
+        "82558a389a443c0ea4cc819899f2083a85f0faa3e578f8077a2e3ff46729665b",
+    );
+}
+
+/// Output truncated to 128 bits
+#[test]
+fn rfc4231_case_5() {
+    check(
+        &[0x0c; 20],
+        b"Test With Truncation",
+        "a3b6167473100ee06e0c796c2955552b",
+    );
+}
+
+/// Key larger than the block size, which must be hashed first
+#[test]
+fn rfc4231_case_6() {
+    check(
+        &[0xaa; 131],
+        b"Test Using Larger Than Block-Size Key - Hash Key First",
+        "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+    );
+}
+
+/// Key and data larger than the block size
+#[test]
+fn rfc4231_case_7() {
+    check(
+        &[0xaa; 131],
+        b"This is a test using a larger than block-size key and a larger than block-size data. \
+          The key needs to be hashed before being used by the HMAC algorithm.",
+        "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+    );
+}
+
+/// A key of exactly one block is used as-is, not hashed
+#[test]
+fn block_sized_key() {
+    let mut sha = Sha::new();
+    let key = [0x42; 64];
+    let hashed_key: [u8; 32] = {
+        use sha2::Digest;
+        sha2::Sha256::digest(key).into()
+    };
+    assert_ne!(
+        hw_hmac::hmac_chunk(&mut sha, &key, b"data").unwrap(),
+        hw_hmac::hmac_chunk(&mut sha, &hashed_key, b"data").unwrap(),
+    );
+}
+
+/// Feeding data piece by piece gives the same tag as hashing it in one go
+#[test]
+fn streaming_matches_one_shot() {
+    let mut sha = Sha::new();
+    let key = [0xaa; 131];
+    let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+    let expected = hw_hmac::hmac_chunk(&mut sha, &key, &data).unwrap();
+
+    for piece_len in [1, 7, 63, 64, 65, 999] {
+        let mut context = HmacContext::new(&mut sha, &key).unwrap();
+        for piece in data.chunks(piece_len) {
+            context.update(piece).unwrap();
+        }
+        assert_eq!(context.finalize().unwrap(), expected);
+    }
+}
+
+#[test]
+fn streaming_verify() {
+    let mut sha = Sha::new();
+    let mut tag = hw_hmac::hmac_chunk(&mut sha, b"Jefe", b"what do ya want for nothing?").unwrap();
+
+    let mut context = HmacContext::new(&mut sha, b"Jefe").unwrap();
+    context.update(b"what do ya want ").unwrap();
+    context.update(b"for nothing?").unwrap();
+    assert!(context.verify(&tag).unwrap());
+
+    tag[31] ^= 1;
+    let mut context = HmacContext::new(&mut sha, b"Jefe").unwrap();
+    context.update(b"what do ya want for nothing?").unwrap();
+    assert!(!context.verify(&tag).unwrap());
+
+    let context = HmacContext::new(&mut sha, b"Jefe").unwrap();
+    assert!(!context.verify(&tag[0..16]).unwrap());
+}
+
+#[test]
+fn tags_match() {
+    assert!(hw_hmac::tags_match(&[1, 2, 3, 4], &[1, 2, 3, 4]));
+    assert!(!hw_hmac::tags_match(&[1, 2, 3, 4], &[0, 2, 3, 4]));
+    assert!(!hw_hmac::tags_match(&[1, 2, 3, 4], &[1, 2, 3, 5]));
+    assert!(!hw_hmac::tags_match(&[1, 2, 3, 4], &[1, 2, 3]));
+    assert!(hw_hmac::tags_match(&[], &[]));
+}
@@ -0,0 +1,257 @@
+//! Key and identity records on flash and on disk.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use std::{fs, path::PathBuf};
+use tactile_tesla::key_store::{
+    ClusterKey, DeviceIdentity, FileKeyStore, FlashKeyStore, KeyStore, KeyStoreError,
+    FLASH_SECTOR_SIZE, IDENTITY_RECORD_LEN, KEY_STORE_SECTORS, RECORD_LEN,
+};
+
+const SECTOR: usize = FLASH_SECTOR_SIZE as usize;
+const OFFSET: u32 = 2 * FLASH_SECTOR_SIZE;
+/// Each slot starts with its generation
+const SLOT_RECORD_START: usize = 4;
+
+/// NOR flash that only takes writes to erased bytes. Can be made to lose
+/// power halfway through a write.
+struct MockFlash {
+    bytes: Vec<u8>,
+    power_loss: bool,
+}
+impl MockFlash {
+    fn new() -> Self {
+        return Self {
+            bytes: vec![0xFF; 8 * SECTOR],
+            power_loss: false,
+        };
+    }
+}
+
+#[derive(Debug)]
+struct PowerLoss;
+impl NorFlashError for PowerLoss {
+    fn kind(&self) -> NorFlashErrorKind {
+        return NorFlashErrorKind::Other;
+    }
+}
+
+impl ErrorType for &mut MockFlash {
+    type Error = PowerLoss;
+}
+impl ReadNorFlash for &mut MockFlash {
+    const READ_SIZE: usize = 4;
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerLoss> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.bytes[offset..offset + bytes.len()]);
+        return Ok(());
+    }
+    fn capacity(&self) -> usize {
+        return self.bytes.len();
+    }
+}
+impl NorFlash for &mut MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = SECTOR;
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerLoss> {
+        assert!(from as usize % SECTOR == 0 && to as usize % SECTOR == 0);
+        self.bytes[from as usize..to as usize].fill(0xFF);
+        return Ok(());
+    }
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerLoss> {
+        let offset = offset as usize;
+        let target = &mut self.bytes[offset..offset + bytes.len()];
+        assert!(
+            target.iter().all(|byte| *byte == 0xFF),
+            "Wrote to flash that wasn't erased"
+        );
+        if self.power_loss {
+            let half = bytes.len() / 2;
+            target[0..half].copy_from_slice(&bytes[0..half]);
+            return Err(PowerLoss);
+        }
+        target.copy_from_slice(bytes);
+        return Ok(());
+    }
+}
+
+fn cluster_key() -> ClusterKey {
+    return ClusterKey {
+        id: 3,
+        secret: core::array::from_fn(|i| i as u8),
+    };
+}
+
+fn identity(commander_key: Option<[u8; 32]>) -> DeviceIdentity {
+    return DeviceIdentity {
+        signing_seed: [0x5e; 32],
+        commander_key,
+    };
+}
+
+/// A file path unique to this test, removed before use
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tt-key-store-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("identity"));
+    return path;
+}
+
+#[test]
+fn flash_round_trip() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    assert!(matches!(store.load_cluster_key(), Ok(None)));
+    assert!(matches!(store.load_identity(), Ok(None)));
+
+    store.store_cluster_key(&cluster_key()).unwrap();
+    store.store_identity(&identity(Some([0xc0; 32]))).unwrap();
+    assert_eq!(store.load_cluster_key().unwrap(), Some(cluster_key()));
+    assert_eq!(
+        store.load_identity().unwrap(),
+        Some(identity(Some([0xc0; 32])))
+    );
+
+    store.erase_cluster_key().unwrap();
+    assert!(matches!(store.load_cluster_key(), Ok(None)));
+    assert_eq!(
+        store.load_identity().unwrap(),
+        Some(identity(Some([0xc0; 32])))
+    );
+}
+
+#[test]
+fn flash_keeps_the_newest_of_many_writes() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    store.store_identity(&identity(None)).unwrap();
+    for id in 0..5 {
+        store.store_cluster_key(&ClusterKey { id, ..cluster_key() }).unwrap();
+    }
+    store.store_identity(&identity(Some([0xc0; 32]))).unwrap();
+    drop(store);
+
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    assert_eq!(store.load_cluster_key().unwrap().unwrap().id, 4);
+    assert_eq!(
+        store.load_identity().unwrap(),
+        Some(identity(Some([0xc0; 32])))
+    );
+
+    // Nothing is written outside the key store's sectors
+    let end = OFFSET as usize + KEY_STORE_SECTORS as usize * SECTOR;
+    assert!(flash.bytes[..OFFSET as usize].iter().all(|byte| *byte == 0xFF));
+    assert!(flash.bytes[end..].iter().all(|byte| *byte == 0xFF));
+}
+
+#[test]
+fn power_loss_during_writes_keeps_previous_records() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    store.store_cluster_key(&cluster_key()).unwrap();
+    store.store_identity(&identity(None)).unwrap();
+    drop(store);
+
+    flash.power_loss = true;
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    let replacement = ClusterKey {
+        id: 4,
+        secret: [0x77; 64],
+    };
+    assert!(store.store_cluster_key(&replacement).is_err());
+    assert!(store.store_identity(&identity(Some([0xc0; 32]))).is_err());
+    assert!(store.erase_cluster_key().is_err());
+    assert_eq!(store.load_cluster_key().unwrap(), Some(cluster_key()));
+    assert_eq!(store.load_identity().unwrap(), Some(identity(None)));
+    drop(store);
+
+    // Once power is back, the torn slot is simply written over
+    flash.power_loss = false;
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    store.store_cluster_key(&replacement).unwrap();
+    assert_eq!(store.load_cluster_key().unwrap(), Some(replacement));
+}
+
+#[test]
+fn flash_detects_corruption() {
+    let mut flash = MockFlash::new();
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    store.store_cluster_key(&cluster_key()).unwrap();
+    store.store_identity(&identity(Some([0xc0; 32]))).unwrap();
+    drop(store);
+
+    // Any flipped bit in either record is caught, including in the generation,
+    // magic and CRC. The first write of each goes to its first slot.
+    let identity_offset = OFFSET as usize + 2 * SECTOR;
+    for (start, len) in [
+        (OFFSET as usize, SLOT_RECORD_START + RECORD_LEN),
+        (identity_offset, SLOT_RECORD_START + IDENTITY_RECORD_LEN),
+    ] {
+        for byte in start..start + len {
+            flash.bytes[byte] ^= 0x10;
+            let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+            let result = if start == identity_offset {
+                store.load_identity().map(|_| ())
+            } else {
+                store.load_cluster_key().map(|_| ())
+            };
+            assert!(matches!(result, Err(KeyStoreError::Corrupted)));
+            flash.bytes[byte] ^= 0x10;
+        }
+    }
+
+    // Zeroed slots count as erased
+    flash.bytes[OFFSET as usize..OFFSET as usize + SECTOR].fill(0);
+    let mut store = FlashKeyStore::new(&mut flash, OFFSET);
+    assert!(matches!(store.load_cluster_key(), Ok(None)));
+}
+
+#[test]
+fn file_round_trip() {
+    let path = temp_path("round-trip");
+    let mut store = FileKeyStore::new(&path);
+    assert!(matches!(store.load_cluster_key(), Ok(None)));
+    assert!(matches!(store.load_identity(), Ok(None)));
+
+    store.store_cluster_key(&cluster_key()).unwrap();
+    store.store_identity(&identity(None)).unwrap();
+    let mut store = FileKeyStore::new(&path);
+    assert_eq!(store.load_cluster_key().unwrap(), Some(cluster_key()));
+    assert_eq!(store.load_identity().unwrap(), Some(identity(None)));
+
+    store.erase_cluster_key().unwrap();
+    store.erase_cluster_key().unwrap();
+    assert!(matches!(store.load_cluster_key(), Ok(None)));
+    assert_eq!(store.load_identity().unwrap(), Some(identity(None)));
+    fs::remove_file(path.with_extension("identity")).unwrap();
+}
+
+#[test]
+fn file_detects_corruption() {
+    let path = temp_path("corruption");
+    let mut store = FileKeyStore::new(&path);
+    store.store_cluster_key(&cluster_key()).unwrap();
+
+    let mut record = fs::read(&path).unwrap();
+    record[10] ^= 1;
+    fs::write(&path, &record).unwrap();
+    assert!(matches!(
+        store.load_cluster_key(),
+        Err(KeyStoreError::Corrupted)
+    ));
+
+    // A truncated record is corrupt too
+    record[10] ^= 1;
+    fs::write(&path, &record[0..RECORD_LEN - 1]).unwrap();
+    assert!(matches!(
+        store.load_cluster_key(),
+        Err(KeyStoreError::Corrupted)
+    ));
+    fs::remove_file(&path).unwrap();
+}
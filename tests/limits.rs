@@ -0,0 +1,201 @@
+//! Memory and age limits on incomplete messages.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use std::fs;
+use tactile_tesla::{
+    counter_store::MemoryCounterStore,
+    hal::{
+        rng::Rng,
+        sha::Sha,
+        time::{Duration, Instant},
+    },
+    key_store::{ClusterKey, FileKeyStore, KeyStore},
+    packet_manager::{PacketManager, PacketManagerError, TrafficClass},
+    packet_types::{CommPacket, Heartbeat},
+    packetizer::{
+        AssemblerLimits, PacketAssembler, TolerantPacketAssembler, TolerantPacketDisassembler,
+    },
+    transport::LoopbackHub,
+};
+
+const CHUNK_SIZE: usize = 64;
+const EPOCH: u32 = 0x5eed;
+const LIMITS: AssemblerLimits = AssemblerLimits {
+    max_message_size: 256,
+    buffer_budget: 300,
+    message_timeout: Duration::secs(5),
+};
+
+fn split(
+    disassembler: &mut TolerantPacketDisassembler<CHUNK_SIZE>,
+    packet: &[u8],
+) -> Vec<Vec<u8>> {
+    let mut iter = disassembler.split_packet(packet);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut chunks = Vec::new();
+    while let Some(len) = iter.get_chunk(&mut chunk) {
+        chunks.push(chunk[0..len].to_vec());
+    }
+    return chunks;
+}
+
+#[test]
+fn oversized_tolerant_message_is_discarded() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut assembler = TolerantPacketAssembler::with_limits(LIMITS);
+    let now = Instant::from_ticks(0);
+    for chunk in split(&mut disassembler, &[7u8; 1000]) {
+        assembler.push_data(&chunk, now);
+        assert!(assembler.buffered_bytes() <= LIMITS.max_message_size);
+    }
+    assert_eq!(assembler.take_discarded().oversized, 1);
+    assert_eq!(assembler.next(), None);
+
+    // Later messages still get through
+    for chunk in split(&mut disassembler, b"small") {
+        assembler.push_data(&chunk, now);
+    }
+    assert_eq!(assembler.next(), Some(b"small".to_vec()));
+}
+
+#[test]
+fn oldest_tolerant_message_is_evicted_over_budget() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut assembler = TolerantPacketAssembler::with_limits(LIMITS);
+    let first = split(&mut disassembler, &[1u8; 250]);
+    let second = split(&mut disassembler, &[2u8; 250]);
+
+    // Both are held back a chunk, so neither completes
+    for chunk in &first[1..] {
+        assembler.push_data(chunk, Instant::from_ticks(0));
+    }
+    for chunk in &second[1..] {
+        assembler.push_data(chunk, Instant::from_ticks(1));
+    }
+    assert!(assembler.buffered_bytes() <= LIMITS.buffer_budget);
+    assert_eq!(assembler.take_discarded().over_budget, 1);
+
+    // The newer message survived and can still complete
+    assembler.push_data(&second[0], Instant::from_ticks(2));
+    assembler.push_data(&first[0], Instant::from_ticks(2));
+    assert_eq!(assembler.collect::<Vec<_>>(), vec![vec![2u8; 250]]);
+}
+
+#[test]
+fn overtaken_tolerant_message_is_counted() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut assembler = TolerantPacketAssembler::with_limits(LIMITS);
+    let now = Instant::from_ticks(0);
+    let stalled = split(&mut disassembler, &[4u8; 200]);
+    assembler.push_data(&stalled[0], now);
+
+    // Once a message as far ahead as the reorder window arrives, the stalled
+    // one can't complete any more
+    for i in 1..=8u8 {
+        for chunk in split(&mut disassembler, &[i]) {
+            assembler.push_data(&chunk, now);
+        }
+    }
+    assert_eq!(assembler.buffered_bytes(), 0);
+    let discarded = assembler.take_discarded();
+    assert_eq!(discarded.overtaken, 1);
+    assert_eq!(discarded.over_budget, 0);
+    assert_eq!(assembler.count(), 8);
+}
+
+#[test]
+fn stalled_tolerant_message_expires() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut assembler = TolerantPacketAssembler::with_limits(LIMITS);
+    let chunks = split(&mut disassembler, &[3u8; 200]);
+    let start = Instant::from_ticks(0);
+    assembler.push_data(&chunks[0], start);
+
+    assembler.expire(start + Duration::secs(4));
+    assert!(assembler.oldest_partial().is_some());
+    assembler.expire(start + Duration::secs(5));
+    assert_eq!(assembler.oldest_partial(), None);
+    assert_eq!(assembler.buffered_bytes(), 0);
+    assert_eq!(assembler.take_discarded().expired, 1);
+
+    // The rest of it arriving late doesn't revive it
+    for chunk in &chunks[1..] {
+        assembler.push_data(chunk, start + Duration::secs(6));
+    }
+    assert_eq!(assembler.next(), None);
+}
+
+#[test]
+fn oversized_stream_message_is_skipped() {
+    let mut assembler = PacketAssembler::with_limits(LIMITS);
+    let now = Instant::from_ticks(0);
+    let mut stream = Vec::from(1000u16.to_be_bytes());
+    stream.extend_from_slice(&[9u8; 1000]);
+    stream.extend_from_slice(&5u16.to_be_bytes());
+    stream.extend_from_slice(b"small");
+
+    for chunk in stream.chunks(7) {
+        assembler.push_data(chunk, now);
+        assert!(assembler.buffered_bytes() <= LIMITS.max_message_size);
+    }
+    assert_eq!(assembler.take_discarded().oversized, 1);
+    assert_eq!(assembler.collect::<Vec<_>>(), vec![b"small".to_vec()]);
+}
+
+#[test]
+fn stalled_stream_message_expires() {
+    let mut assembler = PacketAssembler::with_limits(LIMITS);
+    let start = Instant::from_ticks(0);
+    assembler.push_data(&[0, 100, 1, 2, 3], start);
+    assembler.expire(start + Duration::secs(5));
+    assert_eq!(assembler.buffered_bytes(), 0);
+    assert_eq!(assembler.take_discarded().expired, 1);
+
+    // The sender starts over with a fresh message
+    assembler.push_data(&[0, 2, b'o', b'k'], start + Duration::secs(6));
+    assert_eq!(assembler.next(), Some(b"ok".to_vec()));
+}
+
+#[test]
+fn messages_peers_would_discard_are_not_queued() {
+    let path = std::env::temp_dir().join(format!("tt-limits-{}-keys", std::process::id()));
+    let mut key_store = FileKeyStore::new(&path);
+    key_store
+        .store_cluster_key(&ClusterKey {
+            id: 1,
+            secret: [0x5a; 64],
+        })
+        .unwrap();
+    let hub = LoopbackHub::new();
+    let mut manager = PacketManager::new(
+        hub.connect([0x02, 0, 0, 0, 0, 1]),
+        key_store,
+        MemoryCounterStore::new(),
+        &mut Sha::new(),
+        &mut Rng::new(),
+    )
+    .unwrap();
+    manager.set_assembler_limits(LIMITS);
+
+    let heartbeat = |len: usize| {
+        return CommPacket::Heartbeat(Heartbeat {
+            car_name: Some("x".repeat(len)),
+        });
+    };
+    assert!(matches!(
+        manager.broadcast(TrafficClass::Bulk, &heartbeat(LIMITS.max_message_size)),
+        Err(PacketManagerError::MessageTooLarge(_))
+    ));
+    assert_eq!(manager.queued_bytes(TrafficClass::Bulk), 0);
+    manager
+        .broadcast(TrafficClass::Bulk, &heartbeat(100))
+        .unwrap();
+    assert!(manager.queued_bytes(TrafficClass::Bulk) > 0);
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("identity"));
+}
@@ -0,0 +1,211 @@
+//! Over-the-air pairing between a Commander and a new node.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use std::{fs, path::PathBuf, thread, time::Duration};
+use tactile_tesla::{
+    counter_store::MemoryCounterStore,
+    hal::{aes::Aes, rng::Rng, sha::Sha, time},
+    key_store::{ClusterKey, FileKeyStore, KeyStore},
+    packet_manager::{PacketManager, PairingClient, PairingEvent, Role},
+    transport::{LoopbackHub, LoopbackTransport, MacAddress, ReceivedFrame, Transport},
+};
+
+const COMMANDER: MacAddress = [0x02, 0, 0, 0, 0, 0xc];
+const NODE: MacAddress = [0x02, 0, 0, 0, 0, 0x1];
+const ROGUE: MacAddress = [0x02, 0, 0, 0, 0, 0xe];
+
+/// Pairing message types, as sent on the air
+const MSG_OFFER: u8 = 1;
+const MSG_REVEAL: u8 = 3;
+
+/// Key store paths unique to this test, removed before use
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tt-pairing-{}-{name}", std::process::id()));
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(path.with_extension("identity"));
+    return path;
+}
+
+fn cluster_key() -> ClusterKey {
+    return ClusterKey {
+        id: 7,
+        secret: core::array::from_fn(|i| (i * 3) as u8),
+    };
+}
+
+struct Setup {
+    hub: LoopbackHub,
+    aes: Aes<'static>,
+    sha: Sha<'static>,
+    rng: Rng,
+    commander: PacketManager<LoopbackTransport, FileKeyStore, MemoryCounterStore>,
+    node: PairingClient<LoopbackTransport>,
+    node_store: FileKeyStore,
+    paths: [PathBuf; 2],
+}
+impl Setup {
+    fn new(name: &str) -> Self {
+        let hub = LoopbackHub::new();
+        let (aes, mut sha, mut rng) = (Aes::new(), Sha::new(), Rng::new());
+        let paths = [
+            temp_path(&format!("{name}-commander")),
+            temp_path(&format!("{name}-node")),
+        ];
+        let mut commander_store = FileKeyStore::new(&paths[0]);
+        commander_store.store_cluster_key(&cluster_key()).unwrap();
+        let mut commander = PacketManager::new(
+            hub.connect(COMMANDER),
+            commander_store,
+            MemoryCounterStore::new(),
+            &mut sha,
+            &mut rng,
+        )
+        .unwrap();
+        commander.start_pairing(time::Duration::secs(60));
+        let node = PairingClient::new(hub.connect(NODE), &mut rng);
+        return Self {
+            hub,
+            aes,
+            sha,
+            rng,
+            commander,
+            node,
+            node_store: FileKeyStore::new(&paths[1]),
+            paths,
+        };
+    }
+
+    fn tick_node(&mut self) -> Option<PairingEvent> {
+        return self.node.tick(
+            &mut self.aes,
+            &mut self.sha,
+            &mut self.rng,
+            &mut self.node_store,
+        );
+    }
+
+    fn tick_commander(&mut self) {
+        self.commander
+            .tick(&mut self.aes, &mut self.sha, &mut self.rng, Role::Commander);
+    }
+
+    /// Runs both sides until the node shows a code, checking the Commander
+    /// shows the same one
+    fn exchange_codes(&mut self) -> u32 {
+        for _ in 0..10 {
+            self.tick_commander();
+            if let Some(PairingEvent::ConfirmationCode(code)) = self.tick_node() {
+                self.tick_commander();
+                assert_eq!(self.commander.pending_pairing(), Some((NODE, code)));
+                return code;
+            }
+        }
+        panic!("No confirmation code");
+    }
+
+    /// Confirms on the Commander and runs the node until it has the key
+    fn confirm(&mut self) -> ClusterKey {
+        assert!(self.commander.confirm_pairing(&mut self.aes));
+        for _ in 0..10 {
+            if let Some(PairingEvent::Paired(key)) = self.tick_node() {
+                return key;
+            }
+            self.tick_commander();
+        }
+        panic!("Never paired");
+    }
+}
+impl Drop for Setup {
+    fn drop(&mut self) {
+        for path in &self.paths {
+            let _ = fs::remove_file(path);
+            let _ = fs::remove_file(path.with_extension("identity"));
+        }
+    }
+}
+
+fn receive_all(transport: &mut impl Transport) -> Vec<ReceivedFrame> {
+    let mut frames = Vec::new();
+    while let Some(frame) = transport.receive() {
+        frames.push(frame);
+    }
+    return frames;
+}
+
+#[test]
+fn pairs_over_loopback() {
+    let mut setup = Setup::new("loopback");
+    // No code is shown until both nonces are out
+    setup.tick_node();
+    setup.tick_commander();
+    assert_eq!(setup.commander.pending_pairing(), None);
+
+    setup.exchange_codes();
+    assert_eq!(setup.confirm(), cluster_key());
+
+    assert_eq!(setup.node_store.load_cluster_key().unwrap(), Some(cluster_key()));
+    let identity = setup.node_store.load_identity().unwrap().unwrap();
+    assert_eq!(identity.commander_key, Some(setup.commander.public_key()));
+}
+
+#[test]
+fn nothing_is_sent_before_confirming() {
+    let mut setup = Setup::new("unconfirmed");
+    setup.exchange_codes();
+    for _ in 0..5 {
+        assert!(setup.tick_node().is_none());
+        setup.tick_commander();
+    }
+    assert_eq!(setup.node_store.load_cluster_key().unwrap(), None);
+}
+
+#[test]
+fn bad_reveal_restarts_pairing() {
+    let mut setup = Setup::new("bad-reveal");
+    let mut rogue = setup.hub.connect(ROGUE);
+
+    // Answer the node's request before the Commander does
+    setup.tick_node();
+    let request = receive_all(&mut rogue).remove(0);
+    let kind = request.data[0];
+    let mut offer = vec![kind, MSG_OFFER];
+    offer.extend_from_slice(&[0x42; 64]);
+    rogue.send(&NODE, &offer).unwrap();
+    setup.tick_node();
+    let nonce = receive_all(&mut rogue).remove(0);
+    assert_eq!(nonce.src_address, NODE);
+
+    // A nonce that doesn't match the commitment makes the node start over
+    rogue.send(&NODE, &[kind, MSG_REVEAL, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+    assert!(setup.tick_node().is_none());
+    setup.tick_node();
+    assert_eq!(receive_all(&mut rogue)[0].data, request.data);
+
+    // And the real Commander can still pair with it
+    setup.exchange_codes();
+    assert_eq!(setup.confirm(), cluster_key());
+}
+
+#[test]
+fn silent_offer_times_out() {
+    let mut setup = Setup::new("silent-offer");
+    let mut rogue = setup.hub.connect(ROGUE);
+
+    setup.tick_node();
+    let kind = receive_all(&mut rogue)[0].data[0];
+    let mut offer = vec![kind, MSG_OFFER];
+    offer.extend_from_slice(&[0x42; 64]);
+    rogue.send(&NODE, &offer).unwrap();
+    // The node is now waiting on the rogue, so the Commander's offer is ignored
+    setup.tick_node();
+    setup.tick_commander();
+    assert!(setup.tick_node().is_none());
+
+    thread::sleep(Duration::from_millis(3100));
+    setup.exchange_codes();
+    assert_eq!(setup.confirm(), cluster_key());
+}
@@ -0,0 +1,187 @@
+//! Selective-repeat delivery over a simulated lossy link.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::{
+    hal::time::{Duration, Instant},
+    packetizer::{
+        DeliveryResult, ReliablePacketAssembler, ReliablePacketDisassembler, ReliableSendError,
+        MAX_MESSAGE_CHUNKS, RELIABLE_PACKET_OVERHEAD,
+    },
+};
+
+const CHUNK_SIZE: usize = 64;
+const EPOCH: u32 = 0x1234_5678;
+
+/// Runs the sender and receiver against each other, dropping every data
+/// chunk for which `drop_chunk` returns true
+fn exchange(
+    sender: &mut ReliablePacketDisassembler<CHUNK_SIZE>,
+    receiver: &mut ReliablePacketAssembler,
+    mut drop_chunk: impl FnMut(usize) -> bool,
+) -> Vec<(u32, DeliveryResult)> {
+    let mut results = Vec::new();
+    let mut now = Instant::from_ticks(0);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut sent = 0;
+    while !sender.is_idle() {
+        let mut on_delivery = |msg_seq, result| results.push((msg_seq, result));
+        match sender.poll_chunk(now, &mut chunk, &mut on_delivery) {
+            Some(len) => {
+                if !drop_chunk(sent) {
+                    receiver.push_data(&chunk[0..len], now);
+                }
+                sent += 1;
+            }
+            None => now += Duration::millis(50),
+        }
+        while let Some(feedback) = receiver.next_feedback() {
+            sender.handle_feedback(&feedback, &mut on_delivery);
+        }
+    }
+    return results;
+}
+
+fn message(len: usize) -> Vec<u8> {
+    return (0..len).map(|i| (i * 31) as u8).collect();
+}
+
+#[test]
+fn lossless() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let msg_seq = sender.send(&message(500), Instant::from_ticks(0)).unwrap();
+
+    let results = exchange(&mut sender, &mut receiver, |_| false);
+    assert_eq!(results, [(msg_seq, DeliveryResult::Delivered)]);
+    assert_eq!(receiver.next(), Some(message(500)));
+    assert_eq!(receiver.next(), None);
+}
+
+#[test]
+fn message_longer_than_the_send_window() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let len = (CHUNK_SIZE - RELIABLE_PACKET_OVERHEAD) * MAX_MESSAGE_CHUNKS;
+    let msg_seq = sender.send(&message(len), Instant::from_ticks(0)).unwrap();
+
+    let results = exchange(&mut sender, &mut receiver, |_| false);
+    assert_eq!(results, [(msg_seq, DeliveryResult::Delivered)]);
+    assert_eq!(receiver.next(), Some(message(len)));
+
+    // Also when chunks get lost along the way
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH + 1);
+    let msg_seq = sender.send(&message(len), Instant::from_ticks(0)).unwrap();
+    let results = exchange(&mut sender, &mut receiver, |i| i % 9 == 4);
+    assert_eq!(results, [(msg_seq, DeliveryResult::Delivered)]);
+    assert_eq!(receiver.next(), Some(message(len)));
+}
+
+#[test]
+fn lost_chunks_are_resent() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let first = sender.send(&message(500), Instant::from_ticks(0)).unwrap();
+    let second = sender.send(&message(120), Instant::from_ticks(0)).unwrap();
+
+    // Lose two chunks that get NACKed by the ones after them, then the last
+    // chunk of the first message, which only its timer can recover
+    let results = exchange(&mut sender, &mut receiver, |i| i == 0 || i == 3 || i == 11);
+    assert_eq!(
+        results,
+        [
+            (second, DeliveryResult::Delivered),
+            (first, DeliveryResult::Delivered)
+        ]
+    );
+    assert_eq!(receiver.next(), Some(message(120)));
+    assert_eq!(receiver.next(), Some(message(500)));
+    assert_eq!(receiver.next(), None);
+}
+
+#[test]
+fn lost_ack_is_repeated() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let msg_seq = sender.send(&message(10), Instant::from_ticks(0)).unwrap();
+
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let len = sender
+        .poll_chunk(Instant::from_ticks(0), &mut chunk, &mut |_, _| {})
+        .unwrap();
+    receiver.push_data(&chunk[0..len], Instant::from_ticks(0));
+    assert!(receiver.next_feedback().is_some());
+
+    let results = exchange(&mut sender, &mut receiver, |_| false);
+    assert_eq!(results, [(msg_seq, DeliveryResult::Delivered)]);
+    assert_eq!(receiver.next(), Some(message(10)));
+    assert_eq!(receiver.next(), None);
+}
+
+#[test]
+fn restarted_sender_is_not_mistaken_for_a_retransmission() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    sender.send(&message(10), Instant::from_ticks(0)).unwrap();
+    exchange(&mut sender, &mut receiver, |_| false);
+    assert_eq!(receiver.next(), Some(message(10)));
+
+    // The same sequence number again, but from a new session
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH + 1);
+    let msg_seq = sender.send(&message(20), Instant::from_ticks(0)).unwrap();
+    let results = exchange(&mut sender, &mut receiver, |_| false);
+    assert_eq!(results, [(msg_seq, DeliveryResult::Delivered)]);
+    assert_eq!(receiver.next(), Some(message(20)));
+    assert_eq!(receiver.next(), None);
+}
+
+#[test]
+fn feedback_from_another_epoch_is_ignored() {
+    let mut old_sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let mut chunk = [0u8; CHUNK_SIZE];
+    old_sender.send(&message(10), Instant::from_ticks(0)).unwrap();
+    let len = old_sender
+        .poll_chunk(Instant::from_ticks(0), &mut chunk, &mut |_, _| {})
+        .unwrap();
+    receiver.push_data(&chunk[0..len], Instant::from_ticks(0));
+    let ack = receiver.next_feedback().unwrap();
+
+    // An ACK for the old session's message doesn't complete the new one
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH + 1);
+    sender.send(&message(10), Instant::from_ticks(0)).unwrap();
+    let mut results = Vec::new();
+    sender.handle_feedback(&ack, &mut |msg_seq, result| results.push((msg_seq, result)));
+    assert!(results.is_empty());
+    assert!(!sender.is_idle());
+}
+
+#[test]
+fn dead_link_times_out() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut receiver = ReliablePacketAssembler::new();
+    let msg_seq = sender.send(&message(200), Instant::from_ticks(0)).unwrap();
+
+    let results = exchange(&mut sender, &mut receiver, |_| true);
+    assert_eq!(results, [(msg_seq, DeliveryResult::TimedOut)]);
+    assert_eq!(receiver.next(), None);
+}
+
+#[test]
+fn send_limits() {
+    let mut sender = ReliablePacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let too_large = message((CHUNK_SIZE - RELIABLE_PACKET_OVERHEAD) * MAX_MESSAGE_CHUNKS + 1);
+    assert_eq!(
+        sender.send(&too_large, Instant::from_ticks(0)),
+        Err(ReliableSendError::TooLarge)
+    );
+
+    while sender.send(&message(10), Instant::from_ticks(0)).is_ok() {}
+    assert_eq!(
+        sender.send(&message(10), Instant::from_ticks(0)),
+        Err(ReliableSendError::Busy)
+    );
+}
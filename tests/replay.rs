@@ -0,0 +1,108 @@
+//! Replay windows and transmit counters.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::{
+    counter_store::{CounterStore, MemoryCounterStore},
+    packet_manager::{
+        ReplayWindow, TxCounter, REPLAY_WINDOW_SIZE, RX_PERSIST_INTERVAL, TX_COUNTER_RESERVATION,
+    },
+    transport::MacAddress,
+};
+
+const PEER: MacAddress = [0x02, 0, 0, 0, 0, 0xa];
+
+fn accept(window: &mut ReplayWindow, counter: u64, store: &mut MemoryCounterStore) -> bool {
+    if !window.check(counter) {
+        return false;
+    }
+    window.accept(&PEER, counter, store);
+    return true;
+}
+
+#[test]
+fn duplicates_are_rejected() {
+    let mut store = MemoryCounterStore::new();
+    let mut window = ReplayWindow::restore(&PEER, &mut store);
+    assert!(accept(&mut window, 1, &mut store));
+    assert!(!accept(&mut window, 1, &mut store));
+    assert!(accept(&mut window, 2, &mut store));
+    assert!(!accept(&mut window, 1, &mut store));
+    assert!(!accept(&mut window, 2, &mut store));
+}
+
+#[test]
+fn out_of_order_within_the_window() {
+    let mut store = MemoryCounterStore::new();
+    let mut window = ReplayWindow::restore(&PEER, &mut store);
+    assert!(accept(&mut window, 100, &mut store));
+    // Everything still inside the window is accepted once, in any order
+    for counter in (100 - REPLAY_WINDOW_SIZE + 1..100).rev() {
+        assert!(accept(&mut window, counter, &mut store));
+        assert!(!accept(&mut window, counter, &mut store));
+    }
+}
+
+#[test]
+fn too_old_counters_are_rejected() {
+    let mut store = MemoryCounterStore::new();
+    let mut window = ReplayWindow::restore(&PEER, &mut store);
+    assert!(accept(&mut window, 1000, &mut store));
+    assert!(!window.check(1000 - REPLAY_WINDOW_SIZE));
+    assert!(!window.check(0));
+    // A jump past the whole window forgets what was seen before it
+    assert!(accept(&mut window, 1000 + 2 * REPLAY_WINDOW_SIZE, &mut store));
+    assert!(!window.check(1000));
+    assert!(window.check(1000 + 2 * REPLAY_WINDOW_SIZE - 1));
+}
+
+#[test]
+fn restored_window_rejects_everything_seen_before_a_reboot() {
+    let mut store = MemoryCounterStore::new();
+    let mut window = ReplayWindow::restore(&PEER, &mut store);
+    // The first frame is persisted straight away, later ones once per interval
+    assert!(accept(&mut window, 5, &mut store));
+    assert_eq!(store.load_peer_counter(&PEER), Some(5));
+    let last_seen = 5 + RX_PERSIST_INTERVAL - 1;
+    for counter in 6..=last_seen {
+        assert!(accept(&mut window, counter, &mut store));
+    }
+    assert_eq!(store.load_peer_counter(&PEER), Some(5));
+
+    // After a reboot nothing the old window saw is accepted again
+    let mut window = ReplayWindow::restore(&PEER, &mut store);
+    for counter in 0..=5 + RX_PERSIST_INTERVAL {
+        assert!(!window.check(counter));
+    }
+    assert!(accept(&mut window, 6 + RX_PERSIST_INTERVAL, &mut store));
+}
+
+#[test]
+fn unknown_peers_start_empty() {
+    let mut store = MemoryCounterStore::new();
+    let window = ReplayWindow::restore(&PEER, &mut store);
+    assert!(window.check(1));
+    assert_eq!(store.load_peer_counter(&PEER), None);
+}
+
+#[test]
+fn tx_counters_never_repeat_across_reboots() {
+    let mut store = MemoryCounterStore::new();
+    let mut counter = TxCounter::restore(&mut store);
+    let mut used = Vec::new();
+    for _ in 0..TX_COUNTER_RESERVATION + 10 {
+        used.push(counter.next(&mut store));
+    }
+    assert!(used.windows(2).all(|pair| pair[0] < pair[1]));
+
+    // Rebooting skips past everything that may have been handed out
+    let mut counter = TxCounter::restore(&mut store);
+    let next = counter.next(&mut store);
+    assert!(next > *used.last().unwrap());
+    // Even when rebooting again without sending anything
+    let mut counter = TxCounter::restore(&mut store);
+    assert!(counter.next(&mut store) > next);
+}
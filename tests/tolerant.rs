@@ -0,0 +1,235 @@
+//! Property tests for out-of-order reassembly of tolerant chunk streams.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use proptest::prelude::*;
+use tactile_tesla::{
+    hal::time::Instant,
+    packetizer::{
+        seq_newer, TolerantPacketAssembler, TolerantPacketDisassembler, DEFAULT_STREAM,
+        MAX_STREAMS, MAX_TOLERANT_CHUNKS, TOLERANT_PACKET_OVERHEAD,
+    },
+};
+
+const CHUNK_SIZE: usize = 64;
+const EPOCH: u32 = 0x5eed;
+const NOW: Instant = Instant::from_ticks(0);
+
+fn split(
+    disassembler: &mut TolerantPacketDisassembler<CHUNK_SIZE>,
+    packet: &[u8],
+) -> Vec<Vec<u8>> {
+    let mut iter = disassembler.split_packet(packet);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut chunks = Vec::new();
+    while let Some(len) = iter.get_chunk(&mut chunk) {
+        chunks.push(chunk[0..len].to_vec());
+    }
+    return chunks;
+}
+
+fn split_stream(
+    disassembler: &mut TolerantPacketDisassembler<CHUNK_SIZE>,
+    stream: u8,
+    packet: &[u8],
+) -> Vec<Vec<u8>> {
+    let mut iter = disassembler.split_stream_packet(stream, packet);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut chunks = Vec::new();
+    while let Some(len) = iter.get_chunk(&mut chunk) {
+        chunks.push(chunk[0..len].to_vec());
+    }
+    return chunks;
+}
+
+/// Reorders and repeats `chunks` as directed by `picks`, which index into
+/// the chunk list and are taken modulo its length
+fn mangle(chunks: &[Vec<u8>], picks: &[usize]) -> Vec<Vec<u8>> {
+    return picks.iter().map(|pick| chunks[pick % chunks.len()].clone()).collect();
+}
+
+fn reassemble(stream: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut assembler = TolerantPacketAssembler::new();
+    for chunk in stream {
+        assembler.push_data(chunk, NOW);
+    }
+    return assembler.collect();
+}
+
+proptest! {
+    /// Any order and any number of repeats yields the message exactly once
+    #[test]
+    fn shuffled_and_duplicated(
+        packet in proptest::collection::vec(any::<u8>(), 0..2000),
+        chunk_size in TOLERANT_PACKET_OVERHEAD + 4..=CHUNK_SIZE,
+        order in Just(()).prop_perturb(|_, mut rng| rng.next_u64()),
+        extra in proptest::collection::vec(any::<usize>(), 0..20),
+    ) {
+        let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+        disassembler.set_chunk_size(chunk_size);
+        let chunks = split(&mut disassembler, &packet);
+
+        // Every chunk at least once, in a scrambled order, plus extra copies
+        let mut picks: Vec<usize> = (0..chunks.len()).chain(extra).collect();
+        let mut state = order | 1;
+        for i in (1..picks.len()).rev() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            picks.swap(i, state as usize % (i + 1));
+        }
+
+        prop_assert_eq!(reassemble(&mangle(&chunks, &picks)), vec![packet]);
+    }
+
+    /// A message missing any chunk is never emitted
+    #[test]
+    fn dropped(
+        packet in proptest::collection::vec(any::<u8>(), 1..2000),
+        chunk_size in TOLERANT_PACKET_OVERHEAD + 4..=CHUNK_SIZE,
+        dropped in any::<usize>(),
+        picks in proptest::collection::vec(any::<usize>(), 0..100),
+    ) {
+        let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+        disassembler.set_chunk_size(chunk_size);
+        let mut chunks = split(&mut disassembler, &packet);
+        chunks.remove(dropped % chunks.len());
+        prop_assume!(!chunks.is_empty());
+
+        prop_assert!(reassemble(&mangle(&chunks, &picks)).is_empty());
+    }
+
+    /// Chunks of consecutive messages can be interleaved
+    #[test]
+    fn interleaved_messages(
+        first in proptest::collection::vec(any::<u8>(), 0..500),
+        second in proptest::collection::vec(any::<u8>(), 0..500),
+    ) {
+        let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+        let first_chunks = split(&mut disassembler, &first);
+        let second_chunks = split(&mut disassembler, &second);
+
+        let mut stream = Vec::new();
+        for i in 0..first_chunks.len().max(second_chunks.len()) {
+            stream.extend(second_chunks.get(i).cloned());
+            stream.extend(first_chunks.get(i).cloned());
+        }
+        let mut packets = reassemble(&stream);
+        packets.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        prop_assert_eq!(packets, expected);
+    }
+}
+
+#[test]
+fn old_messages_are_dropped() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let stale = split(&mut disassembler, b"stale");
+    for _ in 0..10 {
+        split(&mut disassembler, b"skipped");
+    }
+    let fresh = split(&mut disassembler, b"fresh");
+
+    let mut assembler = TolerantPacketAssembler::new();
+    assembler.push_data(&fresh[0], NOW);
+    assembler.push_data(&stale[0], NOW);
+    assert_eq!(assembler.collect::<Vec<_>>(), vec![b"fresh".to_vec()]);
+}
+
+#[test]
+fn restarted_sender_is_followed() {
+    let mut before = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let mut assembler = TolerantPacketAssembler::new();
+    for _ in 0..100 {
+        assert!(!assembler.push_data(&split(&mut before, b"before")[0], NOW));
+    }
+    let straggler = split(&mut before, b"straggler");
+
+    // After a reboot, sequence numbers start over under a new epoch
+    let mut after = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH + 1);
+    assert!(assembler.push_data(&split(&mut after, b"after")[0], NOW));
+    assert!(!assembler.push_data(&split(&mut after, b"again")[0], NOW));
+    // Late chunks from before the restart don't flip it back
+    assert!(!assembler.push_data(&straggler[0], NOW));
+
+    let packets: Vec<_> = assembler.collect();
+    assert_eq!(packets.len(), 102);
+    assert_eq!(packets[100..], [b"after".to_vec(), b"again".to_vec()]);
+}
+
+#[test]
+fn sequence_wraparound() {
+    assert!(seq_newer(1, 0));
+    assert!(seq_newer(0, u32::MAX));
+    assert!(seq_newer(5, u32::MAX - 5));
+    assert!(!seq_newer(u32::MAX, 0));
+    assert!(!seq_newer(7, 7));
+
+    // Chunks that cross the wrap still reassemble, even out of order
+    let mut assembler = TolerantPacketAssembler::new();
+    let chunk = |msg_seq: u32, data: &[u8]| {
+        let mut chunk = Vec::from(EPOCH.to_be_bytes());
+        chunk.push(DEFAULT_STREAM);
+        chunk.extend_from_slice(&msg_seq.to_be_bytes());
+        chunk.extend_from_slice(&[0, 0, 0, 1]);
+        chunk.extend_from_slice(data);
+        return chunk;
+    };
+    assembler.push_data(&chunk(u32::MAX - 1, b"a"), NOW);
+    assembler.push_data(&chunk(1, b"c"), NOW);
+    assembler.push_data(&chunk(u32::MAX, b"b"), NOW);
+    assembler.push_data(&chunk(u32::MAX - 1, b"a"), NOW);
+    let packets: Vec<_> = assembler.collect();
+    assert_eq!(packets, [b"a".to_vec(), b"c".to_vec(), b"b".to_vec()]);
+}
+
+#[test]
+fn streams_interleave_without_interference() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let bulk: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let bulk_chunks = split_stream(&mut disassembler, 1, &bulk);
+
+    // Far more urgent messages than the reorder window go out mid-transfer
+    let mut assembler = TolerantPacketAssembler::new();
+    let mut urgent = Vec::new();
+    for (i, chunk) in bulk_chunks.iter().enumerate() {
+        assembler.push_data(chunk, NOW);
+        if i % 2 == 0 {
+            let press = format!("press {i}").into_bytes();
+            for chunk in split(&mut disassembler, &press) {
+                assembler.push_data(&chunk, NOW);
+            }
+            urgent.push(press);
+        }
+    }
+
+    let (bulk_packets, urgent_packets): (Vec<_>, Vec<_>) =
+        assembler.partition(|packet| *packet == bulk);
+    assert_eq!(bulk_packets, vec![bulk]);
+    assert_eq!(urgent_packets, urgent);
+}
+
+#[test]
+fn unknown_streams_are_ignored() {
+    let mut chunk = Vec::from(EPOCH.to_be_bytes());
+    chunk.push(MAX_STREAMS);
+    chunk.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1]);
+    chunk.extend_from_slice(b"data");
+    let mut assembler = TolerantPacketAssembler::new();
+    assembler.push_data(&chunk, NOW);
+    assert_eq!(assembler.next(), None);
+}
+
+#[test]
+fn oversized_messages_are_not_sent() {
+    let mut disassembler = TolerantPacketDisassembler::<CHUNK_SIZE>::new(EPOCH);
+    let packet = vec![0u8; (CHUNK_SIZE - TOLERANT_PACKET_OVERHEAD) * MAX_TOLERANT_CHUNKS as usize];
+    assert_eq!(split(&mut disassembler, &packet).len(), MAX_TOLERANT_CHUNKS as usize);
+
+    let packet = vec![0u8; packet.len() + 1];
+    assert!(split(&mut disassembler, &packet).is_empty());
+}
@@ -0,0 +1,255 @@
+//! Resumable bulk transfers over a simulated lossy link.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use tactile_tesla::{
+    hal::time::{Duration, Instant},
+    hw_hmac::HASH_SIZE,
+    packetizer::{
+        BulkReceiver, BulkSender, ObjectSink, ObjectSource, StorageError, TransferError,
+        TransferStatus,
+    },
+};
+
+const CHUNK_SIZE: usize = 240;
+
+struct MemorySource(Vec<u8>);
+impl ObjectSource for MemorySource {
+    fn len(&self) -> u32 {
+        return self.0.len() as u32;
+    }
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+        return Ok(());
+    }
+}
+
+/// Keeps partial objects around by digest, like flash that survives a reboot
+#[derive(Default)]
+struct MemorySink {
+    digest: Option<[u8; HASH_SIZE]>,
+    data: Vec<u8>,
+    finished: Vec<bool>,
+    /// Flips a bit in whatever is written at this offset
+    corrupt_at: Option<u32>,
+}
+impl ObjectSink for MemorySink {
+    fn open(&mut self, digest: &[u8; HASH_SIZE], _len: u32) -> Result<u32, StorageError> {
+        if self.digest != Some(*digest) {
+            self.digest = Some(*digest);
+            self.data.clear();
+        }
+        return Ok(self.data.len() as u32);
+    }
+    fn write(&mut self, offset: u32, data: &[u8]) -> Result<(), StorageError> {
+        assert_eq!(offset as usize, self.data.len());
+        self.data.extend_from_slice(data);
+        if self.corrupt_at == Some(offset) {
+            self.data[offset as usize] ^= 1;
+        }
+        return Ok(());
+    }
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), StorageError> {
+        let offset = offset as usize;
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+        return Ok(());
+    }
+    fn finish(&mut self, _digest: &[u8; HASH_SIZE], verified: bool) -> Result<(), StorageError> {
+        self.finished.push(verified);
+        if !verified {
+            self.digest = None;
+            self.data.clear();
+        }
+        return Ok(());
+    }
+}
+
+fn object(len: usize) -> Vec<u8> {
+    return (0..len).map(|i| (i * 7 + i / 251) as u8).collect();
+}
+
+/// Runs the sender and receiver against each other until the sender is done
+/// or `stop_after` frames have been sent, dropping every frame for which
+/// `drop_frame` returns true. Returns the number of frames sent.
+fn exchange(
+    sender: &mut BulkSender<MemorySource, CHUNK_SIZE>,
+    receiver: &mut BulkReceiver<MemorySink>,
+    stop_after: usize,
+    mut drop_frame: impl FnMut(usize) -> bool,
+) -> usize {
+    let mut now = Instant::from_ticks(0);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut sent = 0;
+    while matches!(sender.status(), TransferStatus::InProgress { .. }) && sent < stop_after {
+        match sender.poll_chunk(now, &mut chunk) {
+            Some(len) => {
+                if !drop_frame(sent) {
+                    receiver.push_data(&chunk[0..len]);
+                }
+                sent += 1;
+            }
+            None => now += Duration::millis(50),
+        }
+        receiver.poll();
+        while let Some(feedback) = receiver.next_feedback() {
+            sender.handle_feedback(&feedback, now);
+        }
+    }
+    return sent;
+}
+
+#[test]
+fn large_object_over_lossy_link() {
+    // Well past what a 16-bit length or chunk count could describe
+    let data = object(300_000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 1);
+    let mut receiver = BulkReceiver::new(MemorySink::default());
+
+    exchange(&mut sender, &mut receiver, usize::MAX, |i| i % 37 == 5);
+    assert_eq!(sender.status(), TransferStatus::Complete);
+    let completed = receiver.next_completed().unwrap();
+    assert!(completed.verified);
+    assert_eq!(completed.len, data.len() as u32);
+    assert_eq!(Some(&completed.digest), sender.digest());
+    assert_eq!(receiver.sink_mut().data, data);
+}
+
+#[test]
+fn hashing_is_spread_over_polls() {
+    let data = object(100_000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data), 1);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut polls = 0;
+    while sender.poll_chunk(Instant::from_ticks(0), &mut chunk).is_none() {
+        assert_eq!(sender.digest(), None);
+        polls += 1;
+    }
+    assert!(polls > 10);
+    assert!(sender.digest().is_some());
+}
+
+#[test]
+fn interrupted_transfer_resumes() {
+    let data = object(50_000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 1);
+    let mut receiver = BulkReceiver::new(MemorySink::default());
+    exchange(&mut sender, &mut receiver, 100, |_| false);
+    let acked = match sender.status() {
+        TransferStatus::InProgress { acked, .. } => acked,
+        status => panic!("Unexpected status {status:?}"),
+    };
+    assert!(acked > 0);
+
+    // Both ends restart, keeping only what's in storage
+    let sink = std::mem::take(receiver.sink_mut());
+    let stored = sink.data.len();
+    let mut receiver = BulkReceiver::new(sink);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 2);
+    let sent = exchange(&mut sender, &mut receiver, usize::MAX, |_| false);
+
+    assert_eq!(sender.status(), TransferStatus::Complete);
+    assert_eq!(receiver.sink_mut().data, data);
+    // Only the rest of the object was sent, plus the offer
+    let chunk_data_len = CHUNK_SIZE - 9;
+    assert_eq!(sent, 1 + (data.len() - stored).div_ceil(chunk_data_len));
+}
+
+#[test]
+fn lost_result_is_repeated() {
+    let data = object(1000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 1);
+    let mut receiver = BulkReceiver::new(MemorySink::default());
+
+    // Deliver everything, but throw away the feedback that carries the result
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut now = Instant::from_ticks(0);
+    while receiver.sink_mut().finished.is_empty() {
+        match sender.poll_chunk(now, &mut chunk) {
+            Some(len) => receiver.push_data(&chunk[0..len]),
+            None => now += Duration::millis(50),
+        }
+        while let Some(feedback) = receiver.next_feedback() {
+            if receiver.sink_mut().finished.is_empty() {
+                sender.handle_feedback(&feedback, now);
+            }
+        }
+    }
+
+    exchange(&mut sender, &mut receiver, usize::MAX, |_| false);
+    assert_eq!(sender.status(), TransferStatus::Complete);
+    assert_eq!(receiver.sink_mut().finished, [true]);
+}
+
+#[test]
+fn lost_result_of_an_already_stored_object_is_repeated() {
+    let data = object(10_000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 1);
+    let mut receiver = BulkReceiver::new(MemorySink::default());
+    exchange(&mut sender, &mut receiver, usize::MAX, |_| false);
+    assert_eq!(sender.status(), TransferStatus::Complete);
+
+    // The receiver restarts with the object stored but not yet finished, so
+    // it answers the offer with an ACK for the whole object and then the
+    // result, which gets lost
+    let mut sink = std::mem::take(receiver.sink_mut());
+    sink.finished.clear();
+    let mut receiver = BulkReceiver::new(sink);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 2);
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut now = Instant::from_ticks(0);
+    while receiver.sink_mut().finished.is_empty() {
+        match sender.poll_chunk(now, &mut chunk) {
+            Some(len) => receiver.push_data(&chunk[0..len]),
+            None => now += Duration::millis(50),
+        }
+        receiver.poll();
+        while let Some(feedback) = receiver.next_feedback() {
+            if receiver.sink_mut().finished.is_empty() {
+                sender.handle_feedback(&feedback, now);
+            }
+        }
+    }
+    assert_eq!(
+        sender.status(),
+        TransferStatus::InProgress {
+            acked: data.len() as u32,
+            len: data.len() as u32
+        }
+    );
+
+    exchange(&mut sender, &mut receiver, usize::MAX, |_| false);
+    assert_eq!(sender.status(), TransferStatus::Complete);
+    assert_eq!(receiver.sink_mut().finished, [true]);
+}
+
+#[test]
+fn corrupted_object_fails_verification() {
+    let data = object(5000);
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(data.clone()), 1);
+    let mut receiver = BulkReceiver::new(MemorySink {
+        corrupt_at: Some(0),
+        ..MemorySink::default()
+    });
+
+    exchange(&mut sender, &mut receiver, usize::MAX, |_| false);
+    assert_eq!(
+        sender.status(),
+        TransferStatus::Failed(TransferError::DigestMismatch)
+    );
+    assert!(!receiver.next_completed().unwrap().verified);
+    assert_eq!(receiver.sink_mut().finished, [false]);
+}
+
+#[test]
+fn dead_link_times_out() {
+    let mut sender = BulkSender::<_, CHUNK_SIZE>::new(MemorySource(object(5000)), 1);
+    let mut receiver = BulkReceiver::new(MemorySink::default());
+
+    exchange(&mut sender, &mut receiver, usize::MAX, |_| true);
+    assert_eq!(sender.status(), TransferStatus::Failed(TransferError::TimedOut));
+    assert_eq!(receiver.next_completed(), None);
+}
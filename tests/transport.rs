@@ -0,0 +1,128 @@
+//! Loopback, UDP and simulated ESP-NOW transports.
+//!
+//! Run on the host with the software backends:
+//! `cargo test --no-default-features --features std --target <host triple>`
+
+#![cfg(feature = "std")]
+
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use tactile_tesla::{
+    hal::esp_now::{EspNow, EspNowError, Medium},
+    transport::{
+        LoopbackHub, MacAddress, Transport, UdpTransport, BROADCAST_ADDRESS, MAX_FRAME_LEN,
+    },
+};
+
+const A: MacAddress = [0x02, 0, 0, 0, 0, 0xa];
+const B: MacAddress = [0x02, 0, 0, 0, 0, 0xb];
+const C: MacAddress = [0x02, 0, 0, 0, 0, 0xc];
+
+fn received_data(transport: &mut impl Transport) -> Vec<(MacAddress, Vec<u8>)> {
+    let mut frames = Vec::new();
+    while let Some(frame) = transport.receive() {
+        frames.push((frame.src_address, frame.data.to_vec()));
+    }
+    return frames;
+}
+
+#[test]
+fn loopback_unicast_and_broadcast() {
+    let hub = LoopbackHub::new();
+    let mut a = hub.connect(A);
+    let mut b = hub.connect(B);
+    let mut c = hub.connect(C);
+
+    a.send(&B, b"to b").unwrap();
+    a.broadcast(b"to all").unwrap();
+
+    assert_eq!(
+        received_data(&mut b),
+        [(A, b"to b".to_vec()), (A, b"to all".to_vec())]
+    );
+    assert_eq!(received_data(&mut c), [(A, b"to all".to_vec())]);
+    // Nobody hears their own frames
+    assert_eq!(received_data(&mut a), []);
+}
+
+#[test]
+fn loopback_rejects_oversized_frames() {
+    let hub = LoopbackHub::new();
+    let mut a = hub.connect(A);
+    let mut b = hub.connect(B);
+    assert!(a.broadcast(&[0u8; MAX_FRAME_LEN + 1]).is_err());
+    a.broadcast(&[0u8; MAX_FRAME_LEN]).unwrap();
+    assert_eq!(received_data(&mut b).len(), 1);
+}
+
+#[test]
+fn loopback_detaches_on_drop() {
+    let hub = LoopbackHub::new();
+    let mut a = hub.connect(A);
+    drop(hub.connect(B));
+    // Reconnecting under the same address starts with an empty inbox
+    a.send(&B, b"lost").unwrap();
+    let mut b = hub.connect(B);
+    assert_eq!(received_data(&mut b), []);
+}
+
+#[test]
+fn esp_now_unicast_needs_a_registered_peer() {
+    let medium = Medium::new();
+    let mut a = EspNow::new(&medium, A).unwrap();
+    let mut b = EspNow::new(&medium, B).unwrap();
+
+    // Like esp-wifi, only the broadcast address is registered to begin with
+    assert!(matches!(
+        EspNow::send(&mut a, &B, b"raw"),
+        Err(EspNowError::NotFound)
+    ));
+    EspNow::send(&mut a, &BROADCAST_ADDRESS, b"raw")
+        .unwrap()
+        .wait()
+        .unwrap();
+
+    // The transport registers unicast peers as it needs them
+    Transport::send(&mut a, &B, b"to b").unwrap();
+    assert!(!a.peer_exists(&B));
+    assert_eq!(
+        received_data(&mut b),
+        [(A, b"raw".to_vec()), (A, b"to b".to_vec())]
+    );
+    assert!(matches!(
+        Transport::send(&mut a, &C, b"nobody"),
+        Err(EspNowError::SendFailed)
+    ));
+}
+
+#[test]
+fn udp_filters_by_destination() {
+    const GROUP: Ipv4Addr = Ipv4Addr::new(239, 0, 23, 42);
+    const PORT: u16 = 47_923;
+    let mut transport = UdpTransport::new(GROUP, PORT, A).unwrap();
+    let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+    socket.set_multicast_loop_v4(true).unwrap();
+    let send_raw = |dst: MacAddress, data: &[u8]| {
+        let mut datagram = Vec::from(B);
+        datagram.extend_from_slice(&dst);
+        datagram.extend_from_slice(data);
+        socket
+            .send_to(&datagram, SocketAddrV4::new(GROUP, PORT))
+            .unwrap();
+    };
+
+    // Our own broadcasts loop back through the group and are dropped
+    transport.broadcast(b"own").unwrap();
+    send_raw(A, b"to a");
+    send_raw(C, b"to c");
+    send_raw(BROADCAST_ADDRESS, b"to all");
+
+    let mut frames = Vec::new();
+    for _ in 0..100 {
+        frames.extend(received_data(&mut transport));
+        if frames.len() >= 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    assert_eq!(frames, [(B, b"to a".to_vec()), (B, b"to all".to_vec())]);
+}